@@ -0,0 +1,827 @@
+use crate::calendar::{civil, from_unix, unix, utc_offset, weekday, DAY};
+use crate::clock::Clock;
+use crate::fade::FadeCurve;
+use crate::player::Player;
+use crate::state::State;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// how often a waiting alarm wakes up to check for cancellation
+const IDLE_STEP: Duration = Duration::from_secs(30);
+// look this far ahead for the next match before giving up on a schedule
+const HORIZON_DAYS: i64 = 5 * 366;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weekdays(u8);
+
+impl Weekdays {
+    pub const MONDAY: Weekdays = Weekdays(1);
+    pub const TUESDAY: Weekdays = Weekdays(1 << 1);
+    pub const WEDNESDAY: Weekdays = Weekdays(1 << 2);
+    pub const THURSDAY: Weekdays = Weekdays(1 << 3);
+    pub const FRIDAY: Weekdays = Weekdays(1 << 4);
+    pub const SATURDAY: Weekdays = Weekdays(1 << 5);
+    pub const SUNDAY: Weekdays = Weekdays(1 << 6);
+    pub const WORKDAYS: Weekdays = Weekdays(0b001_1111);
+    pub const WEEKEND: Weekdays = Weekdays(0b110_0000);
+    pub const ALL: Weekdays = Weekdays(0b111_1111);
+
+    const NAMES: [&'static str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+    pub fn empty() -> Weekdays {
+        Weekdays(0)
+    }
+
+    // Monday is 0
+    pub fn contains(self, day: usize) -> bool {
+        day < 7 && self.0 & (1 << day) != 0
+    }
+
+    pub fn with(self, day: usize) -> Weekdays {
+        Weekdays(self.0 | (1 << (day % 7)))
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for Weekdays {
+    type Output = Weekdays;
+
+    fn bitor(self, other: Weekdays) -> Weekdays {
+        Weekdays(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days: Vec<&str> = (0..7)
+            .filter(|d| self.contains(*d))
+            .map(|d| Weekdays::NAMES[d])
+            .collect();
+        f.write_str(&days.join(","))
+    }
+}
+
+fn weekday_index(name: &str) -> Result<usize> {
+    let name = name.to_ascii_lowercase();
+    Weekdays::NAMES
+        .iter()
+        .position(|n| name.starts_with(n))
+        .ok_or_else(|| invalid(format!("Unknown weekday {:?}", name)))
+}
+
+// "mon-fri", "sat,sun", "weekdays", "weekend", "daily"
+impl FromStr for Weekdays {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Weekdays> {
+        match s.to_ascii_lowercase().as_str() {
+            "daily" | "all" | "*" => return Ok(Weekdays::ALL),
+            "weekdays" | "workdays" => return Ok(Weekdays::WORKDAYS),
+            "weekend" | "weekends" => return Ok(Weekdays::WEEKEND),
+            _ => {}
+        }
+
+        let mut days = Weekdays::empty();
+        for part in s.split(',') {
+            match part.find('-') {
+                Some(idx) => {
+                    let (from, to) = (
+                        weekday_index(&part[..idx])?,
+                        weekday_index(&part[idx + 1..])?,
+                    );
+                    let mut day = from;
+                    loop {
+                        days = days.with(day);
+                        if day == to {
+                            break;
+                        }
+                        day = (day + 1) % 7;
+                    }
+                }
+                None => days = days.with(weekday_index(part)?),
+            }
+        }
+
+        Ok(days)
+    }
+}
+
+// A classic five field crontab line: minute hour day-of-month month day-of-week.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: Weekdays,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn cron_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => (&part[..idx], part[idx + 1..].parse::<u32>().ok()),
+            None => (part, Some(1)),
+        };
+        let step = step
+            .filter(|s| *s > 0)
+            .ok_or_else(|| invalid(format!("Bad step in {:?}", part)))?;
+
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else {
+            let number = |s: &str| {
+                s.parse::<u32>()
+                    .ok()
+                    .filter(|n| (min..=max).contains(n))
+                    .ok_or_else(|| invalid(format!("{:?} is not in {}-{}", s, min, max)))
+            };
+            match range.find('-') {
+                Some(idx) => (number(&range[..idx])?, number(&range[idx + 1..])?),
+                None if part.contains('/') => (number(range)?, max),
+                None => {
+                    let n = number(range)?;
+                    (n, n)
+                }
+            }
+        };
+
+        if from > to {
+            return Err(invalid(format!("Empty range {:?}", part)));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cron> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid(format!(
+                "Expected 5 cron fields, got {}",
+                fields.len()
+            )));
+        }
+
+        // cron counts Sunday as 0 and 7
+        let cron_weekdays = cron_field(fields[4], 0, 7)?;
+        let weekdays = (0..=7)
+            .filter(|d| cron_weekdays & (1 << d) != 0)
+            .fold(Weekdays::empty(), |days, d| days.with((d + 6) % 7));
+
+        Ok(Cron {
+            minutes: cron_field(fields[0], 0, 59)?,
+            hours: cron_field(fields[1], 0, 23)?,
+            days: cron_field(fields[2], 1, 31)?,
+            months: cron_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil(days);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let by_day = self.days & (1 << day) != 0;
+        let by_weekday = self.weekdays.contains(weekday(days));
+
+        // like cron, a restricted day of month and day of week are or-ed
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => by_weekday,
+            (false, true) => by_day,
+            (false, false) => by_day || by_weekday,
+        }
+    }
+
+    fn matches_time(&self, hour: u32, minute: u32) -> bool {
+        self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    At {
+        hour: u32,
+        minute: u32,
+        days: Weekdays,
+    },
+    Cron(Cron),
+}
+
+impl Schedule {
+    pub fn at(hour: u32, minute: u32, days: Weekdays) -> Result<Schedule> {
+        if hour > 23 || minute > 59 {
+            return Err(invalid(format!(
+                "{}:{:02} is not a time of day",
+                hour, minute
+            )));
+        }
+        Ok(Schedule::At { hour, minute, days })
+    }
+
+    // "07:30", optionally followed by weekdays: "07:30 mon-fri"
+    pub fn parse_time(s: &str) -> Result<Schedule> {
+        let mut parts = s.split_whitespace();
+        let time = parts.next().unwrap_or("");
+        let days = match parts.next() {
+            Some(days) => days.parse()?,
+            None => Weekdays::ALL,
+        };
+
+        let mut hm = time.splitn(2, ':').map(|n| n.parse::<u32>().ok());
+        match (hm.next().flatten(), hm.next().flatten()) {
+            (Some(hour), Some(minute)) => Schedule::at(hour, minute, days),
+            _ => Err(invalid(format!("Expected HH:MM, got {:?}", time))),
+        }
+    }
+
+    fn matches_day(&self, days: i64) -> bool {
+        match self {
+            Schedule::At { days: mask, .. } => mask.contains(weekday(days)),
+            Schedule::Cron(cron) => cron.matches_day(days),
+        }
+    }
+
+    fn matches_time(&self, h: u32, m: u32) -> bool {
+        match self {
+            Schedule::At { hour, minute, .. } => *hour == h && *minute == m,
+            Schedule::Cron(cron) => cron.matches_time(h, m),
+        }
+    }
+
+    // First matching minute strictly after `after`, both in local seconds.
+    fn next_local(&self, after: i64) -> Option<i64> {
+        let start = after.div_euclid(60) + 1;
+        let today = start.div_euclid(1440);
+
+        for day in today..today + HORIZON_DAYS {
+            let first = if day == today {
+                start.rem_euclid(1440) as u32
+            } else {
+                0
+            };
+            if self.matches_day(day) {
+                if let Some(m) = (first..1440).find(|m| self.matches_time(m / 60, m % 60)) {
+                    return Some(day * DAY + m as i64 * 60);
+                }
+            }
+        }
+
+        None
+    }
+
+    // `utc_offset` is seconds east of UTC, None for the system time zone.
+    pub fn next_after(&self, after: SystemTime, utc_offset: Option<i64>) -> Option<SystemTime> {
+        let offset = |at: i64| utc_offset.unwrap_or_else(|| self::utc_offset(from_unix(at)));
+
+        let local = self.next_local(unix(after) + offset(unix(after)))?;
+        // the offset may differ on the other side of a DST change
+        let guess = local - offset(local);
+        Some(from_unix(local - offset(guess)))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Schedule> {
+        if s.split_whitespace().count() == 5 {
+            s.parse().map(Schedule::Cron)
+        } else {
+            Schedule::parse_time(s)
+        }
+    }
+}
+
+pub trait Launcher {
+    fn launch(&self) -> Result<()>;
+}
+
+impl<F: Fn() -> Result<()>> Launcher for F {
+    fn launch(&self) -> Result<()> {
+        self()
+    }
+}
+
+// Starts Spotify in the background through Launch Services.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenLauncher;
+
+impl Launcher for OpenLauncher {
+    fn launch(&self) -> Result<()> {
+        let status = Command::new("open")
+            .args(["-g", "-b", "com.spotify.client"])
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!("open exited with {}", status)))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlarmConfig {
+    pub uri: String,
+    pub context: Option<String>,
+    pub volume: i32,
+    pub fade: Duration,
+    pub curve: FadeCurve,
+    pub step: Duration,
+    pub snooze: Duration,
+    // how long to wait for Spotify to start or to start playing
+    pub start_timeout: Duration,
+    pub retries: u32,
+}
+
+impl AlarmConfig {
+    pub fn new(uri: &str) -> AlarmConfig {
+        AlarmConfig {
+            uri: uri.to_string(),
+            context: None,
+            volume: 70,
+            fade: Duration::from_secs(5 * 60),
+            curve: FadeCurve::default(),
+            step: Duration::from_secs(1),
+            snooze: Duration::from_secs(9 * 60),
+            start_timeout: Duration::from_secs(20),
+            retries: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // faded all the way in
+    Woke,
+    Dismissed,
+    // someone paused or stopped playback while it was fading in
+    Stopped,
+    Cancelled,
+}
+
+const IDLE: u8 = 0;
+const SNOOZE: u8 = 1;
+const DISMISS: u8 = 2;
+const CANCEL: u8 = 3;
+
+#[derive(Clone)]
+pub struct AlarmHandle(Arc<AtomicU8>);
+
+impl AlarmHandle {
+    pub fn snooze(&self) {
+        let _ = self
+            .0
+            .compare_exchange(IDLE, SNOOZE, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    // Stops ringing and leaves the music playing.
+    pub fn dismiss(&self) {
+        let _ = self
+            .0
+            .compare_exchange(IDLE, DISMISS, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    // Stops ringing and the schedule for good.
+    pub fn cancel(&self) {
+        self.0.store(CANCEL, Ordering::SeqCst);
+    }
+
+    fn take(&self) -> u8 {
+        match self.0.load(Ordering::SeqCst) {
+            CANCEL => CANCEL,
+            _ => self.0.swap(IDLE, Ordering::SeqCst),
+        }
+    }
+}
+
+pub struct Alarm<P, C, L> {
+    player: P,
+    clock: C,
+    launcher: L,
+    config: AlarmConfig,
+    utc_offset: Option<i64>,
+    handle: AlarmHandle,
+}
+
+impl<P: Player, C: Clock, L: Launcher> Alarm<P, C, L> {
+    pub fn new(player: P, clock: C, launcher: L, config: AlarmConfig) -> Alarm<P, C, L> {
+        Alarm {
+            player,
+            clock,
+            launcher,
+            config,
+            utc_offset: None,
+            handle: AlarmHandle(Arc::new(AtomicU8::new(IDLE))),
+        }
+    }
+
+    // Seconds east of UTC to schedule in, instead of the system time zone.
+    pub fn with_utc_offset(mut self, utc_offset: i64) -> Alarm<P, C, L> {
+        self.utc_offset = Some(utc_offset);
+        self
+    }
+
+    pub fn handle(&self) -> AlarmHandle {
+        self.handle.clone()
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn next_fire(&self, schedule: &Schedule) -> Option<SystemTime> {
+        schedule.next_after(self.clock.system_now(), self.utc_offset)
+    }
+
+    // Rings on every match of `schedule` until cancelled.
+    pub fn run(&self, schedule: &Schedule) -> Result<()> {
+        loop {
+            let at = self
+                .next_fire(schedule)
+                .ok_or_else(|| invalid("Schedule never fires".to_string()))?;
+
+            while let Ok(left) = at.duration_since(self.clock.system_now()) {
+                if left == Duration::from_secs(0) {
+                    break;
+                }
+                if self.handle.0.load(Ordering::SeqCst) == CANCEL {
+                    return Ok(());
+                }
+                self.clock.sleep(left.min(IDLE_STEP));
+            }
+
+            if self.ring()? == Outcome::Cancelled {
+                return Ok(());
+            }
+        }
+    }
+
+    // Starts the music now and fades it in, snoozing as asked.
+    pub fn ring(&self) -> Result<Outcome> {
+        // a snooze or dismiss left over from the last time means nothing now
+        if self.handle.take() == CANCEL {
+            return Ok(Outcome::Cancelled);
+        }
+
+        self.start()?;
+
+        loop {
+            match self.fade_in()? {
+                SNOOZE => {
+                    self.player.pause()?;
+                    match self.wait(self.config.snooze) {
+                        CANCEL => return Ok(Outcome::Cancelled),
+                        // dismissed while snoozing, the music stays paused
+                        DISMISS => return Ok(Outcome::Dismissed),
+                        _ => {}
+                    }
+                    self.player.set_volume(0)?;
+                    self.player.play()?;
+                    self.wait_for_playback(|| self.player.play())?;
+                }
+                DISMISS => return Ok(Outcome::Dismissed),
+                CANCEL => return Ok(Outcome::Cancelled),
+                _ => {
+                    return Ok(match self.player.state()? {
+                        Some(State::PLAYING) => Outcome::Woke,
+                        _ => Outcome::Stopped,
+                    })
+                }
+            }
+        }
+    }
+
+    fn start(&self) -> Result<()> {
+        if self.player.state().is_err() {
+            self.launcher.launch()?;
+            self.wait_until(|| self.player.state().is_ok())?;
+        }
+
+        self.player.set_volume(0)?;
+        self.player
+            .play_track(self.config.uri.clone(), self.config.context.clone())?;
+
+        self.wait_for_playback(|| {
+            // Spotify may have died under us, bring it back before trying again
+            if self.player.state().is_err() {
+                self.launcher.launch()?;
+                self.wait_until(|| self.player.state().is_ok())?;
+                self.player.set_volume(0)?;
+            }
+            self.player
+                .play_track(self.config.uri.clone(), self.config.context.clone())
+        })
+    }
+
+    // The watchdog: retries until Spotify reports it is playing.
+    fn wait_for_playback<F: Fn() -> Result<()>>(&self, retry: F) -> Result<()> {
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                retry()?;
+            }
+
+            let playing =
+                self.wait_until(|| self.player.state().ok().flatten() == Some(State::PLAYING));
+            if playing.is_ok() {
+                return Ok(());
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "Spotify did not start playing after {} attempts",
+                self.config.retries + 1
+            ),
+        ))
+    }
+
+    fn wait_until<F: Fn() -> bool>(&self, ready: F) -> Result<()> {
+        let deadline = self.clock.now() + self.config.start_timeout;
+
+        loop {
+            if ready() {
+                return Ok(());
+            }
+            if self.clock.now() >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for Spotify",
+                ));
+            }
+            self.clock.sleep(self.config.step);
+        }
+    }
+
+    // Sleeps for `duration`, returns the cancel or dismiss request that ended
+    // it early or IDLE when done. Snoozing again while snoozed does nothing.
+    fn wait(&self, duration: Duration) -> u8 {
+        let deadline = self.clock.now() + duration;
+
+        while self.clock.now() < deadline {
+            match self.handle.take() {
+                request @ CANCEL | request @ DISMISS => return request,
+                _ => {}
+            }
+            let left = deadline.saturating_duration_since(self.clock.now());
+            self.clock.sleep(left.min(self.config.step));
+        }
+
+        IDLE
+    }
+
+    // Ramps the volume up, returns the control request that ended it early
+    // or IDLE when done.
+    fn fade_in(&self) -> Result<u8> {
+        let started = self.clock.now();
+        let fade = self.config.fade.as_secs_f64();
+        let mut volume = 0;
+
+        loop {
+            match self.handle.take() {
+                IDLE => {}
+                request => return Ok(request),
+            }
+
+            let progress = if fade > 0.0 {
+                self.clock
+                    .now()
+                    .saturating_duration_since(started)
+                    .as_secs_f64()
+                    / fade
+            } else {
+                1.0
+            };
+            let target = self.config.curve.volume(0, self.config.volume, progress);
+
+            if target != volume {
+                self.player.set_volume(target)?;
+                volume = target;
+            }
+
+            if progress >= 1.0 {
+                return Ok(IDLE);
+            }
+
+            if self.player.state()? != Some(State::PLAYING) {
+                return Ok(IDLE);
+            }
+
+            self.clock.sleep(self.config.step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Cued, FakePlayer};
+    use std::cell::Cell;
+
+    const HOUR: u64 = 3600;
+
+    fn config() -> AlarmConfig {
+        AlarmConfig {
+            fade: Duration::from_secs(10),
+            curve: FadeCurve::Linear,
+            snooze: Duration::from_secs(60),
+            ..AlarmConfig::new("spotify:track:4uLU6hMCjMI75M1A2tKUQC")
+        }
+    }
+
+    fn volumes(fake: &FakePlayer) -> Vec<i32> {
+        fake.calls()
+            .iter()
+            .filter_map(|call| call.strip_prefix("set_volume "))
+            .map(|v| v.parse().unwrap())
+            .collect()
+    }
+
+    fn never() -> Result<()> {
+        Err(Error::other("Spotify is already running"))
+    }
+
+    fn epoch(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn fades_in_and_wakes() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        // a snooze from before it rang is forgotten
+        alarm.handle().snooze();
+
+        assert_eq!(alarm.ring().unwrap(), Outcome::Woke);
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+        assert_eq!(fake.count("play_track"), 1);
+        assert_eq!(fake.count("pause"), 0);
+        let volumes = volumes(&fake);
+        assert_eq!(volumes.first(), Some(&0));
+        assert_eq!(volumes.last(), Some(&70));
+        assert!(volumes.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn launches_spotify_when_it_is_not_running() {
+        let fake = FakePlayer::new();
+        fake.fail("state", &[-600]);
+        let launches = Cell::new(0);
+        let launcher = || {
+            launches.set(launches.get() + 1);
+            Ok(())
+        };
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, launcher, config());
+
+        assert_eq!(alarm.ring().unwrap(), Outcome::Woke);
+        assert_eq!(launches.get(), 1);
+
+        let fake = FakePlayer::new();
+        fake.fail("state", &[-600]);
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        assert!(alarm.ring().is_err());
+        assert_eq!(fake.count("play_track"), 0);
+    }
+
+    #[test]
+    fn snoozes_and_fades_in_again() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        let handle = alarm.handle();
+        clock.at(Duration::from_secs(3), move || handle.snooze());
+
+        assert_eq!(alarm.ring().unwrap(), Outcome::Woke);
+        assert_eq!(clock.elapsed(), Duration::from_secs(3 + 60 + 10));
+        assert_eq!(fake.count("pause"), 1);
+        assert_eq!(fake.count("play"), 1);
+        assert_eq!(volumes(&fake).last(), Some(&70));
+    }
+
+    #[test]
+    fn dismissing_a_snoozed_alarm_leaves_it_paused() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        let handle = alarm.handle();
+        let h = handle.clone();
+        clock.at(Duration::from_secs(3), move || h.snooze());
+        clock.at(Duration::from_secs(20), move || handle.dismiss());
+
+        assert_eq!(alarm.ring().unwrap(), Outcome::Dismissed);
+        assert_eq!(clock.elapsed(), Duration::from_secs(20));
+        assert_eq!(fake.get().state, Some(State::PAUSED));
+        assert_eq!(fake.count("play"), 0);
+        assert_eq!(volumes(&fake).iter().filter(|v| **v == 0).count(), 1);
+    }
+
+    #[test]
+    fn dismisses_cancels_and_notices_a_stop() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        let handle = alarm.handle();
+        clock.at(Duration::from_secs(4), move || handle.dismiss());
+        assert_eq!(alarm.ring().unwrap(), Outcome::Dismissed);
+        assert_eq!(fake.get().state, Some(State::PLAYING));
+
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        let handle = alarm.handle();
+        let h = handle.clone();
+        clock.at(Duration::from_secs(3), move || h.snooze());
+        clock.at(Duration::from_secs(20), move || handle.cancel());
+        assert_eq!(alarm.ring().unwrap(), Outcome::Cancelled);
+        assert_eq!(fake.count("play"), 0);
+
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config());
+        clock.at(Duration::from_secs(4), || fake.pause().unwrap());
+        assert_eq!(alarm.ring().unwrap(), Outcome::Stopped);
+    }
+
+    #[test]
+    fn rings_on_schedule_until_cancelled() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let alarm = Alarm::new(&fake, &clock, never, config()).with_utc_offset(0);
+        let handle = alarm.handle();
+        clock.at(Duration::from_secs(2 * HOUR), move || handle.cancel());
+
+        alarm.run(&"01:00".parse().unwrap()).unwrap();
+        assert_eq!(fake.count("play_track"), 1);
+        assert!(clock.elapsed() < Duration::from_secs(2 * HOUR) + IDLE_STEP);
+    }
+
+    #[test]
+    fn finds_the_next_fire() {
+        // the epoch was a Thursday
+        let next = |schedule: &str, after: u64, offset: i64| {
+            let schedule: Schedule = schedule.parse().unwrap();
+            schedule.next_after(epoch(after), Some(offset)).unwrap()
+        };
+
+        assert_eq!(next("07:30", 0, 0), epoch(7 * HOUR + 1800));
+        assert_eq!(next("07:30", 7 * HOUR + 1800, 0), epoch(31 * HOUR + 1800));
+        assert_eq!(next("07:30", 0, 3600), epoch(6 * HOUR + 1800));
+        assert_eq!(next("07:30 sat", 0, 0), epoch(55 * HOUR + 1800));
+        assert_eq!(next("07:30 weekend", 0, 0), epoch(55 * HOUR + 1800));
+        assert_eq!(next("0 9 * * 1", 0, 0), epoch(4 * 24 * HOUR + 9 * HOUR));
+        assert_eq!(next("*/15 * * * *", 60, 0), epoch(15 * 60));
+        // a day of the month or a weekday
+        assert_eq!(next("0 0 5 * 6", 0, 0), epoch(2 * 24 * HOUR));
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        for bad in &[
+            "25:00",
+            "7",
+            "07:30 funday",
+            "61 * * * *",
+            "* * *",
+            "0 0 * * */0",
+        ] {
+            assert!(bad.parse::<Schedule>().is_err(), "{}", bad);
+        }
+        assert_eq!(
+            "sat-mon".parse::<Weekdays>().unwrap(),
+            Weekdays::SATURDAY | Weekdays::SUNDAY | Weekdays::MONDAY
+        );
+        assert_eq!(Weekdays::WORKDAYS.to_string(), "mon,tue,wed,thu,fri");
+    }
+}
@@ -0,0 +1,55 @@
+// everything here drives Spotify, the rest only builds on macOS
+#![cfg_attr(not(target_os = "macos"), allow(dead_code, unused_imports))]
+
+extern crate macos_spotify;
+
+use macos_spotify::metrics::Exporter;
+#[cfg(target_os = "macos")]
+use macos_spotify::Spotify;
+use std::env;
+use std::process;
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!("Usage: exporter [--bind ADDR] [--interval MILLISECONDS]");
+    process::exit(2);
+}
+
+#[cfg(target_os = "macos")]
+fn main() {
+    let mut bind = "127.0.0.1:9753".to_string();
+    let mut interval = Duration::from_secs(5);
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => bind = args.next().unwrap_or_else(|| usage()),
+            "--interval" => {
+                let millis = args.next().and_then(|ms| ms.parse().ok());
+                interval = Duration::from_millis(millis.unwrap_or_else(|| usage()));
+            }
+            _ => usage(),
+        }
+    }
+
+    let exporter = match Exporter::bind(&bind, Spotify::new(), interval) {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            eprintln!("Cannot listen on {}: {}", bind, err);
+            process::exit(1);
+        }
+    };
+
+    eprintln!("Serving metrics on http://{}/metrics", bind);
+
+    if let Err(err) = exporter.run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    eprintln!("exporter talks to Spotify through Apple Events, which only exist on macOS");
+    process::exit(1);
+}
@@ -0,0 +1,61 @@
+// everything here drives Spotify, the rest only builds on macOS
+#![cfg_attr(not(target_os = "macos"), allow(dead_code, unused_imports))]
+
+extern crate macos_spotify;
+
+use macos_spotify::party::{PartyConfig, PartyServer};
+#[cfg(target_os = "macos")]
+use macos_spotify::Spotify;
+use std::env;
+use std::process;
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!("Usage: party [--bind ADDR] [--max-requests N] [--window SECS] [--max-pending N]");
+    eprintln!("             [--drop-at SCORE]");
+    eprintln!("Listens on 127.0.0.1:8090, --bind 0.0.0.0:8090 takes requests from the network.");
+    process::exit(2);
+}
+
+fn number<T: std::str::FromStr>(arg: Option<String>) -> T {
+    arg.and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())
+}
+
+#[cfg(target_os = "macos")]
+fn main() {
+    let mut bind = "127.0.0.1:8090".to_string();
+    let mut config = PartyConfig::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => bind = args.next().unwrap_or_else(|| usage()),
+            "--max-requests" => config.max_requests = number(args.next()),
+            "--window" => config.window = Duration::from_secs(number(args.next())),
+            "--max-pending" => config.max_pending = number(args.next()),
+            "--drop-at" => config.drop_at = number(args.next()),
+            _ => usage(),
+        }
+    }
+
+    let server = match PartyServer::bind(&bind, Spotify::new(), config) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Cannot listen on {}: {}", bind, err);
+            process::exit(1);
+        }
+    };
+
+    eprintln!("Taking requests on http://{}", bind);
+
+    if let Err(err) = server.run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    eprintln!("party talks to Spotify through Apple Events, which only exist on macOS");
+    process::exit(1);
+}
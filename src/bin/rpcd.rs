@@ -0,0 +1,56 @@
+// everything here drives Spotify, the rest only builds on macOS
+#![cfg_attr(not(target_os = "macos"), allow(dead_code, unused_imports))]
+
+extern crate macos_spotify;
+
+use macos_spotify::rpc::{default_socket_path, RpcServer};
+#[cfg(target_os = "macos")]
+use macos_spotify::Spotify;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!("Usage: rpcd [--socket PATH] [--interval MILLISECONDS]");
+    process::exit(2);
+}
+
+#[cfg(target_os = "macos")]
+fn main() {
+    let mut path = default_socket_path();
+    let mut interval = Duration::from_millis(500);
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => path = PathBuf::from(args.next().unwrap_or_else(|| usage())),
+            "--interval" => {
+                let millis = args.next().and_then(|ms| ms.parse().ok());
+                interval = Duration::from_millis(millis.unwrap_or_else(|| usage()));
+            }
+            _ => usage(),
+        }
+    }
+
+    let server = match RpcServer::bind(&path, Spotify::new(), interval) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Cannot listen on {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+
+    eprintln!("Listening on {}", path.display());
+
+    if let Err(err) = server.run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    eprintln!("rpcd talks to Spotify through Apple Events, which only exist on macOS");
+    process::exit(1);
+}
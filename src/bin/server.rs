@@ -0,0 +1,59 @@
+// everything here drives Spotify, the rest only builds on macOS
+#![cfg_attr(not(target_os = "macos"), allow(dead_code, unused_imports))]
+
+extern crate macos_spotify;
+
+use macos_spotify::server::{Server, ServerConfig};
+#[cfg(target_os = "macos")]
+use macos_spotify::Spotify;
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("Usage: server [--bind ADDR] [--token TOKEN] [--cors ORIGIN]...");
+    eprintln!();
+    eprintln!("The token can also be given through SPOTIFY_SERVER_TOKEN.");
+    process::exit(2);
+}
+
+#[cfg(target_os = "macos")]
+fn main() {
+    let mut bind = "127.0.0.1:8080".to_string();
+    let mut config = ServerConfig {
+        token: env::var("SPOTIFY_SERVER_TOKEN").ok(),
+        ..Default::default()
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => bind = args.next().unwrap_or_else(|| usage()),
+            "--token" => config.token = Some(args.next().unwrap_or_else(|| usage())),
+            "--cors" => config
+                .cors_origins
+                .push(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let server = match Server::bind(&bind, Spotify::new(), config) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Cannot listen on {}: {}", bind, err);
+            process::exit(1);
+        }
+    };
+
+    eprintln!("Listening on http://{}", bind);
+
+    if let Err(err) = server.run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    eprintln!("server talks to Spotify through Apple Events, which only exist on macOS");
+    process::exit(1);
+}
@@ -0,0 +1,364 @@
+// everything here drives Spotify, the rest only builds on macOS
+#![cfg_attr(not(target_os = "macos"), allow(dead_code, unused_imports))]
+
+extern crate macos_spotify;
+
+use macos_spotify::alarm::{Alarm, AlarmConfig, OpenLauncher, Schedule, Weekdays};
+#[cfg(feature = "bookmarks")]
+use macos_spotify::bookmark::Bookmarks;
+use macos_spotify::clock::SystemClock;
+#[cfg(feature = "rules")]
+use macos_spotify::rules::Rules;
+use macos_spotify::sleep::{Outcome, SleepConfig, SleepHandle, SleepTimer, Until};
+#[cfg(target_os = "macos")]
+use macos_spotify::Spotify;
+use std::env;
+use std::io::{self, BufRead};
+#[cfg(feature = "bookmarks")]
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+fn usage() -> ! {
+    eprintln!("Usage: spotifyctl <command> [options]");
+    eprintln!();
+    eprintln!("Commands:");
+    eprintln!(
+        "  sleep <DURATION|end-of-track> [--fade DURATION] [--curve linear|exponential|s-curve]"
+    );
+    eprintln!("        [--finish-track] [--keep-volume]");
+    eprintln!("  alarm <HH:MM> [--days mon-fri|weekend|daily] --uri URI [--context URI]");
+    eprintln!("        [--volume N] [--fade DURATION] [--curve CURVE] [--snooze DURATION]");
+    eprintln!("  alarm --cron \"30 7 * * 1-5\" --uri URI [options]");
+    #[cfg(feature = "rules")]
+    eprintln!("  rules <FILE.toml> [--dry-run] [--interval DURATION]");
+    #[cfg(feature = "bookmarks")]
+    eprintln!("  bookmark <save|restore|rm NAME | list> [--file FILE]");
+    eprintln!();
+    eprintln!("Durations look like 30m, 1h15m, 90s or a bare number of minutes.");
+    process::exit(2);
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    if let Ok(minutes) = s.parse::<u64>() {
+        return Some(Duration::from_secs(minutes * 60));
+    }
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let value: u64 = number.parse().ok()?;
+        number.clear();
+        total += value
+            * match c {
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return None,
+            };
+    }
+
+    if number.is_empty() && total > 0 {
+        Some(Duration::from_secs(total))
+    } else {
+        None
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Ctrl-C cancels the timer, which puts the volume back, instead of leaving
+// it halfway through a fade.
+fn cancel_on_interrupt(handle: SleepHandle) {
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        handle.cancel();
+    });
+}
+
+#[cfg(target_os = "macos")]
+fn sleep(mut args: impl Iterator<Item = String>) {
+    let until = match args.next().as_deref() {
+        Some("end-of-track") => Until::EndOfTrack,
+        Some(duration) => Until::Elapsed(parse_duration(duration).unwrap_or_else(|| usage())),
+        None => usage(),
+    };
+    let mut config = SleepConfig {
+        until,
+        ..Default::default()
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fade" => {
+                config.fade = args
+                    .next()
+                    .and_then(|d| parse_duration(&d))
+                    .unwrap_or_else(|| usage())
+            }
+            "--curve" => {
+                config.curve = args
+                    .next()
+                    .and_then(|c| c.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--finish-track" => config.finish_track = true,
+            "--keep-volume" => config.restore_volume = false,
+            _ => usage(),
+        }
+    }
+
+    let timer = SleepTimer::new(Spotify::new(), SystemClock, config);
+    cancel_on_interrupt(timer.handle());
+
+    match timer.run() {
+        Ok(Outcome::Paused) => eprintln!("Good night"),
+        Ok(Outcome::Interrupted) => eprintln!("Playback stopped, sleep timer cancelled"),
+        Ok(Outcome::Cancelled) => eprintln!("Sleep timer cancelled"),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn alarm(args: impl Iterator<Item = String>) {
+    let mut args = args.peekable();
+    let mut time = match args.peek() {
+        Some(arg) if !arg.starts_with("--") => args.next(),
+        _ => None,
+    };
+    let mut days = Weekdays::ALL;
+    let mut cron = None;
+    let mut uri = None;
+    let mut context = None;
+    let mut volume = None;
+    let mut fade = None;
+    let mut curve = None;
+    let mut snooze = None;
+
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--at" => time = Some(value),
+            "--days" => days = value.parse().unwrap_or_else(|_| usage()),
+            "--cron" => cron = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--uri" => uri = Some(value),
+            "--context" => context = Some(value),
+            "--volume" => volume = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--fade" => fade = Some(parse_duration(&value).unwrap_or_else(|| usage())),
+            "--curve" => curve = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--snooze" => snooze = Some(parse_duration(&value).unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let schedule = match (time, cron) {
+        (Some(time), None) => Schedule::parse_time(&time)
+            .and_then(|s| match s {
+                Schedule::At { hour, minute, .. } => Schedule::at(hour, minute, days),
+                s => Ok(s),
+            })
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                usage()
+            }),
+        (None, Some(cron)) => Schedule::Cron(cron),
+        _ => usage(),
+    };
+
+    let mut config = AlarmConfig::new(&uri.unwrap_or_else(|| usage()));
+    config.context = context;
+    config.volume = volume.unwrap_or(config.volume);
+    config.fade = fade.unwrap_or(config.fade);
+    config.curve = curve.unwrap_or(config.curve);
+    config.snooze = snooze.unwrap_or(config.snooze);
+
+    let clock = Alarm::new(Spotify::new(), SystemClock, OpenLauncher, config);
+    if let Some(next) = clock.next_fire(&schedule) {
+        let wait = next.duration_since(SystemTime::now()).unwrap_or_default();
+        eprintln!("Next alarm in {}m", wait.as_secs() / 60);
+    }
+    eprintln!("Press enter to snooze, d and enter to dismiss, q and enter to quit");
+
+    let handle = clock.handle();
+    thread::spawn(move || {
+        // stdin going away (running detached) leaves the alarm armed
+        for line in io::stdin().lock().lines().map_while(|line| line.ok()) {
+            match line.trim() {
+                "" | "s" => handle.snooze(),
+                "d" => handle.dismiss(),
+                "q" => handle.cancel(),
+                _ => {}
+            }
+        }
+    });
+
+    if let Err(err) = clock.run(&schedule) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(all(target_os = "macos", feature = "rules"))]
+fn rules(mut args: impl Iterator<Item = String>) {
+    let path = args.next().unwrap_or_else(|| usage());
+    let mut dry_run = false;
+    let mut interval = Duration::from_secs(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--interval" => {
+                interval = args
+                    .next()
+                    .and_then(|d| parse_duration(&d))
+                    .unwrap_or_else(|| usage())
+            }
+            _ => usage(),
+        }
+    }
+
+    let mut rules = match Rules::load(Spotify::new(), &path) {
+        Ok(rules) => rules.dry_run(dry_run),
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    eprintln!("Loaded {} rules from {}", rules.len(), path);
+
+    loop {
+        match rules.poll() {
+            Ok(fired) => {
+                for f in fired {
+                    let verb = if f.applied { "" } else { "would " };
+                    eprintln!("[{}] {}{}", f.rule, verb, f.action);
+                }
+            }
+            Err(err) => eprintln!("{}", err),
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(all(target_os = "macos", feature = "bookmarks"))]
+fn bookmark(mut args: impl Iterator<Item = String>) {
+    let command = args.next().unwrap_or_else(|| usage());
+    let mut name = None;
+    let mut file = env::var_os("HOME")
+        .map(|home| {
+            PathBuf::from(home).join("Library/Application Support/spotifyctl/bookmarks.jsonl")
+        })
+        .unwrap_or_else(|| PathBuf::from("bookmarks.jsonl"));
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => file = args.next().map(PathBuf::from).unwrap_or_else(|| usage()),
+            _ if name.is_none() && !arg.starts_with("--") => name = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let fail = |err: io::Error| -> ! {
+        match err.kind() {
+            // a bad name rather than the file
+            io::ErrorKind::InvalidInput => eprintln!("{}", err),
+            _ => eprintln!("{}: {}", file.display(), err),
+        }
+        process::exit(1);
+    };
+    let mut bookmarks = Bookmarks::open(&file).unwrap_or_else(|err| fail(err));
+    let spotify = Spotify::new();
+
+    match (command.as_str(), name) {
+        ("list", None) => {
+            for entry in bookmarks.entries() {
+                let b = &entry.bookmark;
+                let position = b.position as u64;
+                println!(
+                    "{}\t{} - {}\t{}:{:02}",
+                    entry.name,
+                    b.artist.as_deref().unwrap_or("?"),
+                    b.title.as_deref().unwrap_or(&b.track),
+                    position / 60,
+                    position % 60
+                );
+            }
+        }
+        ("save", Some(name)) => {
+            let bookmark = spotify.save_state().unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            });
+            bookmarks
+                .insert(&name, bookmark)
+                .unwrap_or_else(|err| fail(err));
+            eprintln!("Saved {}", name.trim());
+        }
+        ("restore", Some(name)) => {
+            let bookmark = match bookmarks.get(&name) {
+                Some(bookmark) => bookmark,
+                None => {
+                    eprintln!("No bookmark named {}", name);
+                    process::exit(1);
+                }
+            };
+            if let Err(err) = spotify.restore(bookmark) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        ("rm", Some(name)) => {
+            if bookmarks
+                .remove(&name)
+                .unwrap_or_else(|err| fail(err))
+                .is_none()
+            {
+                eprintln!("No bookmark named {}", name);
+                process::exit(1);
+            }
+        }
+        _ => usage(),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn main() {
+    let mut args = env::args().skip(1);
+
+    match args.next().as_deref() {
+        Some("sleep") => sleep(args),
+        Some("alarm") => alarm(args),
+        #[cfg(feature = "rules")]
+        Some("rules") => rules(args),
+        #[cfg(feature = "bookmarks")]
+        Some("bookmark") => bookmark(args),
+        _ => usage(),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    eprintln!("spotifyctl talks to Spotify through Apple Events, which only exist on macOS");
+    process::exit(1);
+}
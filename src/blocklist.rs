@@ -0,0 +1,638 @@
+use crate::calendar::unix;
+use crate::player::Player;
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::spool::Spool;
+use crate::uri::SpotifyUri;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Artist,
+    Album,
+    Track,
+}
+
+impl Field {
+    fn of(self, track: &TrackInfo) -> Option<&str> {
+        match self {
+            Field::Artist => track.artist.as_deref(),
+            Field::Album => track.album.as_deref(),
+            Field::Track => track.name.as_deref(),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Field::Artist => "artist",
+            Field::Album => "album",
+            Field::Track => "track",
+        })
+    }
+}
+
+// Names are matched ignoring case. Spotify only reports the URI of the track
+// itself, so albums and artists are blocked by name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Uri(SpotifyUri),
+    Name { field: Field, name: String },
+    // `*` for any run of characters, `?` for any one
+    Glob { field: Field, pattern: String },
+    Regex { field: Field, pattern: String },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Uri(uri) => write!(f, "{}", uri),
+            Target::Name { field, name } => write!(f, "{} {:?}", field, name),
+            Target::Glob { field, pattern } => write!(f, "{} like {:?}", field, pattern),
+            Target::Regex { field, pattern } => write!(f, "{} ~ /{}/", field, pattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub target: Target,
+    pub added_at: SystemTime,
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl Entry {
+    pub fn is_expired(&self, at: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires| expires <= at)
+    }
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    // classic two pointer match, backtracking to the last star
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn lower_chars(s: &str) -> Vec<char> {
+    s.to_lowercase().chars().collect()
+}
+
+enum Matcher {
+    Uri(String),
+    Name(Field, String),
+    Glob(Field, Vec<char>),
+    Regex(Field, Regex),
+}
+
+impl Matcher {
+    fn compile(target: &Target) -> Result<Matcher> {
+        Ok(match target {
+            Target::Uri(uri) if !uri.is_playable() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Only track and episode URIs can be blocked, block the {} by name",
+                        uri.kind
+                    ),
+                ))
+            }
+            Target::Uri(uri) => Matcher::Uri(uri.to_string()),
+            Target::Name { field, name } => Matcher::Name(*field, name.to_lowercase()),
+            Target::Glob { field, pattern } => Matcher::Glob(*field, lower_chars(pattern)),
+            Target::Regex { field, pattern } => Matcher::Regex(
+                *field,
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?,
+            ),
+        })
+    }
+
+    fn matches(&self, track: &TrackInfo) -> bool {
+        match self {
+            Matcher::Uri(uri) => track.spotify_url.as_ref() == Some(uri),
+            Matcher::Name(field, name) => field
+                .of(track)
+                .is_some_and(|value| value.to_lowercase() == *name),
+            Matcher::Glob(field, pattern) => field
+                .of(track)
+                .is_some_and(|value| glob(pattern, &lower_chars(value))),
+            Matcher::Regex(field, regex) => {
+                field.of(track).is_some_and(|value| regex.is_match(value))
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    at: i64,
+    event: &'a str,
+    target: &'a Target,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track: Option<&'a TrackInfo>,
+}
+
+pub struct Blocklist {
+    entries: Spool<Entry>,
+    // None for an entry whose pattern no longer compiles
+    matchers: Vec<Option<Matcher>>,
+    audit: Option<PathBuf>,
+}
+
+impl Blocklist {
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Blocklist> {
+        let mut blocklist = Blocklist {
+            entries: Spool::open(path)?,
+            matchers: Vec::new(),
+            audit: None,
+        };
+        blocklist.rebuild();
+        Ok(blocklist)
+    }
+
+    // Appends every change and skip as a line of JSON to `path`.
+    pub fn with_audit_log<T: AsRef<Path>>(mut self, path: T) -> Blocklist {
+        self.audit = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        self.entries.items()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Blocks `target`, for `ttl` if given. Blocking something again replaces
+    // the old entry.
+    pub fn add(
+        &mut self,
+        target: Target,
+        ttl: Option<Duration>,
+        reason: Option<String>,
+        at: SystemTime,
+    ) -> Result<()> {
+        // refuse bad patterns before anything is written
+        Matcher::compile(&target)?;
+
+        self.entries.retain(|entry| entry.target != target)?;
+        self.entries.push(Entry {
+            target,
+            added_at: at,
+            expires_at: ttl.map(|ttl| at + ttl),
+            reason,
+        })?;
+        self.rebuild();
+
+        let entry = &self.entries.items()[self.entries.len() - 1];
+        self.audit("added", &entry.target, None, at)
+    }
+
+    pub fn remove(&mut self, target: &Target, at: SystemTime) -> Result<bool> {
+        if self.entries.retain(|entry| entry.target != *target)? == 0 {
+            return Ok(false);
+        }
+        self.rebuild();
+        self.audit("removed", target, None, at)?;
+        Ok(true)
+    }
+
+    // Forgets entries that ran out, returning how many.
+    pub fn prune(&mut self, at: SystemTime) -> Result<usize> {
+        let expired: Vec<Target> = self
+            .entries
+            .items()
+            .iter()
+            .filter(|entry| entry.is_expired(at))
+            .map(|entry| entry.target.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        self.entries.retain(|entry| !entry.is_expired(at))?;
+        self.rebuild();
+        for target in &expired {
+            self.audit("expired", target, None, at)?;
+        }
+        Ok(expired.len())
+    }
+
+    pub fn find(&self, track: &TrackInfo, at: SystemTime) -> Option<&Entry> {
+        self.entries
+            .items()
+            .iter()
+            .zip(&self.matchers)
+            .find(|(entry, matcher)| {
+                !entry.is_expired(at) && matcher.as_ref().is_some_and(|m| m.matches(track))
+            })
+            .map(|(entry, _)| entry)
+    }
+
+    // Entries that never match because their pattern does not compile, say
+    // after the file was edited by hand. They are kept so they can be removed.
+    pub fn broken(&self) -> Vec<&Entry> {
+        self.entries
+            .items()
+            .iter()
+            .zip(&self.matchers)
+            .filter(|(_, matcher)| matcher.is_none())
+            .map(|(entry, _)| entry)
+            .collect()
+    }
+
+    fn rebuild(&mut self) {
+        self.matchers = self
+            .entries
+            .items()
+            .iter()
+            .map(|entry| Matcher::compile(&entry.target).ok())
+            .collect();
+    }
+
+    fn audit(
+        &self,
+        event: &str,
+        target: &Target,
+        track: Option<&TrackInfo>,
+        at: SystemTime,
+    ) -> Result<()> {
+        let path = match &self.audit {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let record = AuditRecord {
+            at: unix(at),
+            event,
+            target,
+            track,
+        };
+        let mut line = serde_json::to_string(&record)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        line.push('\n');
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    Track,
+    Artist,
+    Album,
+}
+
+// Skips blocked tracks as soon as they start.
+pub struct Blocker<P> {
+    player: P,
+    blocklist: Blocklist,
+    current: Option<String>,
+}
+
+impl<P: Player> Blocker<P> {
+    pub fn new(player: P, blocklist: Blocklist) -> Blocker<P> {
+        Blocker {
+            player,
+            blocklist,
+            current: None,
+        }
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
+    }
+
+    pub fn blocklist_mut(&mut self) -> &mut Blocklist {
+        &mut self.blocklist
+    }
+
+    pub fn poll(&mut self) -> Result<Option<Entry>> {
+        let snapshot = self.player.snapshot()?;
+        self.update(&snapshot, SystemTime::now())
+    }
+
+    // Returns the entry a newly started track was skipped for.
+    pub fn update(&mut self, snapshot: &Snapshot, at: SystemTime) -> Result<Option<Entry>> {
+        let track = match &snapshot.track {
+            Some(track) => track,
+            None => {
+                self.current = None;
+                return Ok(None);
+            }
+        };
+
+        let key = track.spotify_url.clone().or_else(|| track.id.clone());
+        if key.is_some() && key == self.current {
+            return Ok(None);
+        }
+        self.current = key;
+
+        self.blocklist.prune(at)?;
+        self.skip_if_blocked(track, at)
+    }
+
+    // Blocks what is playing right now and skips it.
+    pub fn block_playing(
+        &mut self,
+        what: Block,
+        ttl: Option<Duration>,
+        reason: Option<String>,
+        at: SystemTime,
+    ) -> Result<Target> {
+        let track = self
+            .player
+            .track()?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Nothing is playing"))?;
+
+        let name = |field: Field| {
+            field
+                .of(&track)
+                .map(|name| Target::Name {
+                    field,
+                    name: name.to_string(),
+                })
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("The playing track has no {}", field),
+                    )
+                })
+        };
+        let target = match what {
+            Block::Track => Target::Uri(track.uri().ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, "The playing track has no Spotify URI")
+            })?),
+            Block::Artist => name(Field::Artist)?,
+            Block::Album => name(Field::Album)?,
+        };
+
+        self.blocklist.add(target.clone(), ttl, reason, at)?;
+        self.skip_if_blocked(&track, at)?;
+        Ok(target)
+    }
+
+    fn skip_if_blocked(&mut self, track: &TrackInfo, at: SystemTime) -> Result<Option<Entry>> {
+        let entry = match self.blocklist.find(track, at) {
+            Some(entry) => entry.clone(),
+            None => return Ok(None),
+        };
+
+        self.player.next()?;
+        self.blocklist
+            .audit("skipped", &entry.target, Some(track), at)?;
+        Ok(Some(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{track, FakePlayer, TempDir, TRACK};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn song(uri: &str, artist: &str, album: &str, name: &str) -> TrackInfo {
+        TrackInfo {
+            artist: Some(artist.to_string()),
+            album: Some(album.to_string()),
+            name: Some(name.to_string()),
+            ..track(uri, 200_000)
+        }
+    }
+
+    fn block(blocklist: &mut Blocklist, target: Target) {
+        blocklist.add(target, None, None, at(0)).unwrap();
+    }
+
+    #[test]
+    fn matches_uris_names_globs_and_regexes() {
+        let dir = TempDir::new();
+        let mut blocklist = Blocklist::open(dir.join("blocklist.jsonl")).unwrap();
+        block(&mut blocklist, Target::Uri(TRACK.parse().unwrap()));
+        block(
+            &mut blocklist,
+            Target::Name {
+                field: Field::Artist,
+                name: "The Band".to_string(),
+            },
+        );
+        block(
+            &mut blocklist,
+            Target::Glob {
+                field: Field::Album,
+                pattern: "live at *".to_string(),
+            },
+        );
+        block(
+            &mut blocklist,
+            Target::Regex {
+                field: Field::Track,
+                pattern: r"\(remix\)$".to_string(),
+            },
+        );
+
+        let found = |track: &TrackInfo| blocklist.find(track, at(60)).map(|e| e.target.clone());
+        assert_eq!(
+            found(&song(TRACK, "a", "b", "c")),
+            Some(Target::Uri(TRACK.parse().unwrap()))
+        );
+        assert!(found(&song("spotify:track:x", "THE BAND", "b", "c")).is_some());
+        assert!(found(&song("spotify:track:x", "a", "Live at Leeds", "c")).is_some());
+        assert!(found(&song("spotify:track:x", "a", "b", "Song (Remix)")).is_some());
+        assert_eq!(
+            found(&song("spotify:track:x", "The Bandits", "Leeds", "Remix")),
+            None
+        );
+
+        // still there after a reopen
+        let reopened = Blocklist::open(dir.join("blocklist.jsonl")).unwrap();
+        assert_eq!(reopened.entries(), blocklist.entries());
+    }
+
+    #[test]
+    fn refuses_bad_targets() {
+        let dir = TempDir::new();
+        let mut blocklist = Blocklist::open(dir.join("blocklist.jsonl")).unwrap();
+        let album = Target::Uri("spotify:album:1DFixLWuPkv3KT3TnV35m3".parse().unwrap());
+        let regex = Target::Regex {
+            field: Field::Track,
+            pattern: "(".to_string(),
+        };
+
+        for target in [album, regex] {
+            let err = blocklist.add(target, None, None, at(0)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert!(blocklist.is_empty());
+    }
+
+    #[test]
+    fn a_broken_entry_does_not_lose_the_rest() {
+        let dir = TempDir::new();
+        let path = dir.join("blocklist.jsonl");
+        let entry = |target| Entry {
+            target,
+            added_at: at(0),
+            expires_at: None,
+            reason: None,
+        };
+        let lines: Vec<String> = [
+            entry(Target::Regex {
+                field: Field::Artist,
+                pattern: "[unclosed".to_string(),
+            }),
+            entry(Target::Name {
+                field: Field::Artist,
+                name: "Band".to_string(),
+            }),
+        ]
+        .iter()
+        .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+        .collect();
+        fs::write(&path, lines.concat()).unwrap();
+
+        let mut blocklist = Blocklist::open(&path).unwrap();
+        assert_eq!(blocklist.len(), 2);
+        assert_eq!(blocklist.broken().len(), 1);
+        let found = blocklist.find(&song(TRACK, "band", "b", "c"), at(0));
+        assert_eq!(
+            found.map(|e| &e.target),
+            Some(&blocklist.entries()[1].target)
+        );
+
+        let broken = blocklist.broken()[0].target.clone();
+        assert!(blocklist.remove(&broken, at(1)).unwrap());
+        assert!(blocklist.broken().is_empty());
+    }
+
+    #[test]
+    fn expires_entries_and_keeps_an_audit_log() {
+        let dir = TempDir::new();
+        let mut blocklist = Blocklist::open(dir.join("blocklist.jsonl"))
+            .unwrap()
+            .with_audit_log(dir.join("audit.jsonl"));
+        let target = Target::Name {
+            field: Field::Album,
+            name: "LP".to_string(),
+        };
+        blocklist
+            .add(
+                target.clone(),
+                Some(HOUR),
+                Some("too loud".to_string()),
+                at(0),
+            )
+            .unwrap();
+        // blocking it again replaces the entry
+        blocklist
+            .add(target.clone(), Some(HOUR), None, at(60))
+            .unwrap();
+        assert_eq!(blocklist.len(), 1);
+        assert_eq!(blocklist.entries()[0].expires_at, Some(at(60) + HOUR));
+
+        let playing = song(TRACK, "a", "LP", "c");
+        assert!(blocklist.find(&playing, at(3600)).is_some());
+        assert!(blocklist.find(&playing, at(3660)).is_none());
+        assert_eq!(blocklist.prune(at(3660)).unwrap(), 1);
+        assert!(blocklist.is_empty());
+        assert!(!blocklist.remove(&target, at(3700)).unwrap());
+
+        let audit = fs::read_to_string(dir.join("audit.jsonl")).unwrap();
+        let events: Vec<String> = audit
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["event"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(events, vec!["added", "added", "expired"]);
+    }
+
+    #[test]
+    fn skips_blocked_tracks_as_they_start() {
+        let dir = TempDir::new();
+        let fake = FakePlayer::new();
+        let blocklist = Blocklist::open(dir.join("blocklist.jsonl")).unwrap();
+        let mut blocker = Blocker::new(&fake, blocklist);
+
+        let target = blocker
+            .block_playing(Block::Artist, None, None, at(0))
+            .unwrap();
+        assert_eq!(
+            target,
+            Target::Name {
+                field: Field::Artist,
+                name: "Band".to_string(),
+            }
+        );
+        assert_eq!(fake.count("next"), 1);
+
+        // the next track is by the same band, but only skipped once
+        let snapshot = fake.get();
+        assert!(blocker.update(&snapshot, at(1)).unwrap().is_some());
+        assert!(blocker.update(&snapshot, at(2)).unwrap().is_none());
+        assert_eq!(fake.count("next"), 2);
+
+        fake.set(|s| s.track = None);
+        assert!(blocker
+            .block_playing(Block::Track, None, None, at(3))
+            .is_err());
+    }
+}
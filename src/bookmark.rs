@@ -0,0 +1,286 @@
+use crate::changes::{Field, PlayerChanges};
+use crate::clock::Clock;
+#[cfg(target_os = "macos")]
+use crate::clock::SystemClock;
+use crate::player::Player;
+#[cfg(feature = "bookmarks")]
+use crate::spool::Spool;
+#[cfg(target_os = "macos")]
+use crate::spotify::Spotify;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+#[cfg(feature = "bookmarks")]
+use std::path::Path;
+use std::time::SystemTime;
+
+// Where the player was, to come back to later.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PlayerBookmark {
+    pub track: String,
+    // Spotify doesn't say what it's playing from, so only if set by hand
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub context: Option<String>,
+    // seconds
+    pub position: f64,
+    pub volume: Option<i32>,
+    pub shuffling: Option<bool>,
+    pub repeating: Option<bool>,
+    pub playing: bool,
+    pub saved_at: SystemTime,
+    // to tell bookmarks apart, not restored
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub title: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub artist: Option<String>,
+}
+
+impl PlayerBookmark {
+    pub fn capture<P: Player>(player: &P, at: SystemTime) -> Result<PlayerBookmark> {
+        let snapshot = player.snapshot()?;
+        let playing = snapshot.is_playing();
+        let track = snapshot
+            .track
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Nothing is playing to bookmark"))?;
+        let uri = track.spotify_url.ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "The current track has no Spotify URI")
+        })?;
+
+        Ok(PlayerBookmark {
+            track: uri,
+            context: None,
+            position: snapshot.position.unwrap_or(0.0),
+            volume: snapshot.volume,
+            shuffling: snapshot.shuffling,
+            repeating: snapshot.repeating,
+            playing,
+            saved_at: at,
+            title: track.name,
+            artist: track.artist,
+        })
+    }
+
+    // What `restore_to` changes before pausing, if it was paused.
+    pub fn changes(&self) -> PlayerChanges {
+        PlayerChanges {
+            shuffling: self.shuffling,
+            repeating: self.repeating,
+            volume: self.volume,
+            track: Some(self.track.clone()),
+            context: self.context.clone(),
+            position: Some(self.position),
+            ..Default::default()
+        }
+    }
+
+    // Plays the track again from where it was, with the volume and modes it
+    // had, and pauses it if it was paused. Puts everything back if that
+    // fails halfway, see `PlayerChanges::apply_to`.
+    pub fn restore_to<P: Player, C: Clock>(&self, player: &P, clock: &C) -> Result<Vec<Field>> {
+        let changed = self.changes().apply_to(player, clock)?;
+        if !self.playing {
+            player.pause()?;
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Spotify {
+    pub fn save_state(&self) -> Result<PlayerBookmark> {
+        PlayerBookmark::capture(self, SystemTime::now())
+    }
+
+    pub fn restore(&self, bookmark: &PlayerBookmark) -> Result<Vec<Field>> {
+        bookmark.restore_to(self, &SystemClock)
+    }
+}
+
+#[cfg(feature = "bookmarks")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NamedBookmark {
+    pub name: String,
+    pub bookmark: PlayerBookmark,
+}
+
+// Bookmarks kept by name in a JSON lines file.
+#[cfg(feature = "bookmarks")]
+pub struct Bookmarks {
+    entries: Spool<NamedBookmark>,
+}
+
+#[cfg(feature = "bookmarks")]
+impl Bookmarks {
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Bookmarks> {
+        Ok(Bookmarks {
+            entries: Spool::open(path)?,
+        })
+    }
+
+    pub fn entries(&self) -> &[NamedBookmark] {
+        self.entries.items()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Names are looked up, saved and removed without the whitespace around
+    // them.
+    pub fn get(&self, name: &str) -> Option<&PlayerBookmark> {
+        let name = name.trim();
+        self.entries
+            .items()
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| &entry.bookmark)
+    }
+
+    // Saves `bookmark` as `name`, returning the one it replaced.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        bookmark: PlayerBookmark,
+    ) -> Result<Option<PlayerBookmark>> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Bookmark names can't be empty",
+            ));
+        }
+
+        // one write, so a crash can't lose the one being replaced
+        self.entries.edit(|entries| {
+            let old = entries
+                .iter()
+                .position(|entry| entry.name == name)
+                .map(|idx| entries.remove(idx).bookmark);
+            entries.push(NamedBookmark {
+                name: name.to_string(),
+                bookmark,
+            });
+            old
+        })
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Option<PlayerBookmark>> {
+        let name = name.trim();
+        let old = self.get(name).cloned();
+        if old.is_some() {
+            self.entries.retain(|entry| entry.name != name)?;
+        }
+        Ok(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::state::State;
+    #[cfg(feature = "bookmarks")]
+    use crate::testing::TempDir;
+    use crate::testing::{FakePlayer, TRACK};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn bookmark(track: &str) -> PlayerBookmark {
+        PlayerBookmark {
+            track: track.to_string(),
+            context: None,
+            position: 42.0,
+            volume: Some(40),
+            shuffling: Some(true),
+            repeating: None,
+            playing: false,
+            saved_at: UNIX_EPOCH,
+            title: None,
+            artist: None,
+        }
+    }
+
+    #[test]
+    fn captures_what_is_playing() {
+        let player = FakePlayer::new();
+        let at = UNIX_EPOCH + Duration::from_secs(1000);
+
+        let saved = PlayerBookmark::capture(&player, at).unwrap();
+        assert_eq!(saved.track, TRACK);
+        assert_eq!(saved.position, 10.0);
+        assert_eq!(saved.volume, Some(50));
+        assert!(saved.playing);
+        assert_eq!(saved.saved_at, at);
+        assert_eq!(saved.artist.as_deref(), Some("Band"));
+
+        player.set(|s| s.track = None);
+        let err = PlayerBookmark::capture(&player, at).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn restores_and_pauses_what_was_paused() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let other = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+
+        let changed = bookmark(other).restore_to(&player, &clock).unwrap();
+        assert_eq!(
+            changed,
+            [
+                Field::Shuffling,
+                Field::Volume,
+                Field::Track,
+                Field::Position
+            ]
+        );
+        let snapshot = player.get();
+        assert_eq!(
+            snapshot.track.and_then(|t| t.spotify_url).as_deref(),
+            Some(other)
+        );
+        assert_eq!(snapshot.position, Some(42.0));
+        assert_eq!(snapshot.volume, Some(40));
+        assert_eq!(snapshot.state, Some(State::PAUSED));
+    }
+
+    #[cfg(feature = "bookmarks")]
+    #[test]
+    fn keeps_bookmarks_by_trimmed_name() {
+        let dir = TempDir::new();
+        let path = dir.join("bookmarks.jsonl");
+        let mut bookmarks = Bookmarks::open(&path).unwrap();
+
+        assert_eq!(
+            bookmarks.insert(" morning ", bookmark(TRACK)).unwrap(),
+            None
+        );
+        assert!(bookmarks.get("morning").is_some());
+        assert!(bookmarks.get("  morning").is_some());
+        assert_eq!(bookmarks.entries()[0].name, "morning");
+
+        let err = bookmarks.insert("  ", bookmark(TRACK)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let other = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+        let old = bookmarks.insert("morning\n", bookmark(other)).unwrap();
+        assert_eq!(old.map(|b| b.track).as_deref(), Some(TRACK));
+        assert_eq!(bookmarks.len(), 1);
+
+        let reopened = Bookmarks::open(&path).unwrap();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(
+            reopened.get("morning").map(|b| b.track.as_str()),
+            Some(other)
+        );
+
+        let removed = bookmarks.remove(" morning").unwrap();
+        assert_eq!(removed.map(|b| b.track).as_deref(), Some(other));
+        assert_eq!(bookmarks.remove("morning").unwrap(), None);
+        assert!(Bookmarks::open(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+use crate::clock::Clock;
+use crate::player::Player;
+use crate::snapshot::TrackInfo;
+use crate::state::State;
+use std::io::Result;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Property {
+    State,
+    Shuffling,
+    Repeating,
+    Position,
+    Volume,
+    Track,
+}
+
+impl Property {
+    pub const ALL: [Property; 6] = [
+        Property::State,
+        Property::Shuffling,
+        Property::Repeating,
+        Property::Position,
+        Property::Volume,
+        Property::Track,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+struct Entry<T> {
+    at: Instant,
+    value: T,
+}
+
+struct PositionEntry {
+    at: Instant,
+    position: Option<f64>,
+    // playing when read, so it moves on with the clock
+    advancing: bool,
+    // where the track playing then ends, in seconds
+    end: Option<f64>,
+}
+
+#[derive(Default)]
+struct Entries {
+    state: Option<Entry<Option<State>>>,
+    shuffling: Option<Entry<Option<bool>>>,
+    repeating: Option<Entry<Option<bool>>>,
+    position: Option<PositionEntry>,
+    volume: Option<Entry<Option<i32>>>,
+    track: Option<Entry<Option<TrackInfo>>>,
+    stats: [CacheStats; 6],
+    // bumped by every invalidation, so a read that was already on its way
+    // doesn't store what the command changed
+    generations: [u64; 6],
+}
+
+impl Entries {
+    fn generation(&self, property: Property) -> u64 {
+        self.generations[property.index()]
+    }
+
+    fn invalidate(&mut self, property: Property) {
+        self.generations[property.index()] += 1;
+        match property {
+            Property::State => self.state = None,
+            Property::Shuffling => self.shuffling = None,
+            Property::Repeating => self.repeating = None,
+            Property::Position => self.position = None,
+            Property::Volume => self.volume = None,
+            Property::Track => self.track = None,
+        }
+    }
+}
+
+// Serves repeated reads from memory for a short while. Commands sent through
+// the same handle forget whatever they could have changed, and the position
+// is worked out from the clock while playing instead of asked for.
+pub struct Cached<P, C> {
+    inner: P,
+    clock: C,
+    ttls: [Duration; 6],
+    entries: Mutex<Entries>,
+}
+
+impl<P: Player, C: Clock> Cached<P, C> {
+    pub fn new(inner: P, clock: C) -> Cached<P, C> {
+        let mut ttls = [Duration::from_millis(250); 6];
+        ttls[Property::Shuffling.index()] = Duration::from_secs(1);
+        ttls[Property::Repeating.index()] = Duration::from_secs(1);
+        ttls[Property::Volume.index()] = Duration::from_millis(500);
+        ttls[Property::Track.index()] = Duration::from_secs(1);
+        // predicted from the clock in between
+        ttls[Property::Position.index()] = Duration::from_secs(5);
+
+        Cached {
+            inner,
+            clock,
+            ttls,
+            entries: Default::default(),
+        }
+    }
+
+    // A zero TTL always asks Spotify.
+    pub fn with_ttl(mut self, property: Property, ttl: Duration) -> Cached<P, C> {
+        self.ttls[property.index()] = ttl;
+        self
+    }
+
+    pub fn ttl(&self, property: Property) -> Duration {
+        self.ttls[property.index()]
+    }
+
+    pub fn stats(&self, property: Property) -> CacheStats {
+        self.lock().stats[property.index()]
+    }
+
+    pub fn total_stats(&self) -> CacheStats {
+        self.lock()
+            .stats
+            .iter()
+            .fold(CacheStats::default(), |a, b| CacheStats {
+                hits: a.hits + b.hits,
+                misses: a.misses + b.misses,
+            })
+    }
+
+    pub fn invalidate(&self, property: Property) {
+        self.lock().invalidate(property);
+    }
+
+    pub fn invalidate_all(&self) {
+        let mut entries = self.lock();
+        for property in Property::ALL.iter() {
+            entries.invalidate(*property);
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn fresh(&self, property: Property, at: Instant) -> bool {
+        self.clock.now().saturating_duration_since(at) < self.ttl(property)
+    }
+
+    fn cached<T: Clone>(
+        &self,
+        property: Property,
+        slot: fn(&mut Entries) -> &mut Option<Entry<T>>,
+        fetch: impl FnOnce(&P) -> Result<T>,
+    ) -> Result<T> {
+        let generation = {
+            let mut entries = self.lock();
+            let hit = match slot(&mut entries) {
+                Some(entry) if self.fresh(property, entry.at) => Some(entry.value.clone()),
+                _ => None,
+            };
+            let stats = &mut entries.stats[property.index()];
+            match hit {
+                Some(value) => {
+                    stats.hits += 1;
+                    return Ok(value);
+                }
+                None => stats.misses += 1,
+            }
+            entries.generation(property)
+        };
+
+        // not locked while asking, so other reads aren't held up
+        let at = self.clock.now();
+        let value = fetch(&self.inner)?;
+        let mut entries = self.lock();
+        if entries.generation(property) == generation {
+            *slot(&mut entries) = Some(Entry {
+                at,
+                value: value.clone(),
+            });
+        }
+        Ok(value)
+    }
+
+    fn command(&self, changes: &[Property], f: impl FnOnce(&P) -> Result<()>) -> Result<()> {
+        let res = f(&self.inner);
+        let mut entries = self.lock();
+        for property in changes {
+            entries.invalidate(*property);
+        }
+        res
+    }
+
+    fn predicted_position(&self) -> Option<Option<f64>> {
+        let mut entries = self.lock();
+        let now = self.clock.now();
+
+        let entry = entries.position.as_ref()?;
+        if !self.fresh(Property::Position, entry.at) {
+            return None;
+        }
+        let position = match (entry.position, entry.advancing) {
+            (Some(position), true) => {
+                let position = position + now.saturating_duration_since(entry.at).as_secs_f64();
+                // past the end, so another track has most likely started
+                if entry.end.is_some_and(|end| position >= end) {
+                    entries.invalidate(Property::Position);
+                    entries.invalidate(Property::Track);
+                    entries.invalidate(Property::State);
+                    return None;
+                }
+                Some(position)
+            }
+            (position, _) => position,
+        };
+
+        Some(position)
+    }
+}
+
+const SKIP: &[Property] = &[Property::State, Property::Track, Property::Position];
+const PLAYBACK: &[Property] = &[Property::State, Property::Position];
+
+impl<P: Player, C: Clock> Player for Cached<P, C> {
+    fn state(&self) -> Result<Option<State>> {
+        self.cached(Property::State, |e| &mut e.state, |p| p.state())
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        self.cached(
+            Property::Shuffling,
+            |e| &mut e.shuffling,
+            |p| p.is_shuffling(),
+        )
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        self.command(&[Property::Shuffling], |p| p.set_shuffling(is_it))
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        self.cached(
+            Property::Repeating,
+            |e| &mut e.repeating,
+            |p| p.is_repeating(),
+        )
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        self.command(&[Property::Repeating], |p| p.set_repeating(is_it))
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        let stats = Property::Position.index();
+        if let Some(position) = self.predicted_position() {
+            self.lock().stats[stats].hits += 1;
+            return Ok(position);
+        }
+        let generation = {
+            let mut entries = self.lock();
+            entries.stats[stats].misses += 1;
+            entries.generation(Property::Position)
+        };
+
+        let at = self.clock.now();
+        let position = self.inner.position()?;
+        let advancing = self.state()? == Some(State::PLAYING);
+        let end = if advancing {
+            self.track()?
+                .and_then(|track| track.duration)
+                .filter(|ms| *ms > 0)
+                .map(|ms| ms as f64 / 1000.0)
+        } else {
+            None
+        };
+
+        let mut entries = self.lock();
+        if entries.generation(Property::Position) == generation {
+            entries.position = Some(PositionEntry {
+                at,
+                position,
+                advancing,
+                end,
+            });
+        }
+        Ok(position)
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        self.command(&[Property::Position], |p| p.set_position(pos))
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        self.cached(Property::Volume, |e| &mut e.volume, |p| p.volume())
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        self.command(&[Property::Volume], |p| p.set_volume(vol))
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        self.cached(Property::Track, |e| &mut e.track, |p| p.track())
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        self.command(PLAYBACK, |p| p.play_pause())
+    }
+
+    fn play(&self) -> Result<()> {
+        self.command(PLAYBACK, |p| p.play())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.command(PLAYBACK, |p| p.pause())
+    }
+
+    fn next(&self) -> Result<()> {
+        self.command(SKIP, |p| p.next())
+    }
+
+    fn previous(&self) -> Result<()> {
+        self.command(SKIP, |p| p.previous())
+    }
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        self.command(SKIP, |p| p.play_track(track, context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::testing::FakePlayer;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn serves_reads_until_they_expire() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let cached = Cached::new(&player, clock.clone());
+
+        assert_eq!(cached.volume().unwrap(), Some(50));
+        assert_eq!(cached.volume().unwrap(), Some(50));
+        assert_eq!(player.count("volume"), 1);
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(cached.volume().unwrap(), Some(50));
+        assert_eq!(player.count("volume"), 2);
+        assert_eq!(
+            cached.stats(Property::Volume),
+            CacheStats { hits: 1, misses: 2 }
+        );
+
+        let uncached = Cached::new(&player, clock).with_ttl(Property::Volume, Duration::ZERO);
+        uncached.volume().unwrap();
+        uncached.volume().unwrap();
+        assert_eq!(player.count("volume"), 4);
+    }
+
+    #[test]
+    fn commands_forget_what_they_change() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let cached = Cached::new(&player, clock);
+
+        cached.volume().unwrap();
+        cached.is_shuffling().unwrap();
+        cached.set_volume(30).unwrap();
+        assert_eq!(cached.volume().unwrap(), Some(30));
+        assert_eq!(cached.is_shuffling().unwrap(), Some(false));
+        assert_eq!(player.count("volume"), 2);
+        assert_eq!(player.count("is_shuffling"), 1);
+
+        cached.track().unwrap();
+        cached.next().unwrap();
+        let track = cached.track().unwrap().unwrap();
+        assert_eq!(
+            track.id.as_deref(),
+            Some("spotify:track:0000000000000000000next")
+        );
+        assert_eq!(player.count("track"), 2);
+    }
+
+    #[test]
+    fn does_not_keep_a_read_overtaken_by_a_command() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let cached = Cached::new(&player, clock);
+
+        thread::scope(|scope| {
+            // holds the read up inside the player
+            let mut snapshot = player.snapshot.lock().unwrap();
+            let read = scope.spawn(|| cached.volume());
+            while player.count("volume") == 0 {
+                thread::yield_now();
+            }
+
+            snapshot.volume = Some(30);
+            cached.invalidate(Property::Volume);
+            drop(snapshot);
+            read.join().unwrap().unwrap();
+        });
+
+        assert_eq!(cached.volume().unwrap(), Some(30));
+        assert_eq!(player.count("volume"), 2);
+    }
+
+    #[test]
+    fn predicts_the_position_while_playing() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        player.follow(&clock);
+        let cached = Cached::new(&player, clock.clone());
+
+        assert_eq!(cached.position().unwrap(), Some(10.0));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cached.position().unwrap(), Some(12.0));
+        assert_eq!(player.count("position"), 1);
+
+        cached.pause().unwrap();
+        assert_eq!(cached.position().unwrap(), Some(12.0));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cached.position().unwrap(), Some(12.0));
+        assert_eq!(player.count("position"), 2);
+    }
+
+    #[test]
+    fn asks_again_past_the_end_of_the_track() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        player.follow(&clock);
+        let cached = Cached::new(&player, clock.clone())
+            .with_ttl(Property::Position, Duration::from_secs(600));
+
+        assert_eq!(cached.position().unwrap(), Some(10.0));
+        // the track it ends with is long gone from the cache by then
+        cached.invalidate(Property::Track);
+        clock.advance(Duration::from_secs(189));
+        assert_eq!(cached.position().unwrap(), Some(199.0));
+        assert_eq!(player.count("position"), 1);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cached.position().unwrap(), Some(200.0));
+        assert_eq!(player.count("position"), 2);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::clock::Clock;
+#[cfg(target_os = "macos")]
+use crate::clock::SystemClock;
+use crate::player::Player;
+#[cfg(target_os = "macos")]
+use crate::spotify::Spotify;
+use crate::state::State;
+use crate::uri::SpotifyUri;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+// setters don't wait for Spotify, so read back until they show or this runs out
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+const VERIFY_STEP: Duration = Duration::from_millis(50);
+// Spotify rounds volumes
+const VOLUME_SLACK: i32 = 1;
+// the position moves on while we check
+const POSITION_SLACK: f64 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Field {
+    Shuffling,
+    Repeating,
+    Volume,
+    Track,
+    Position,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Field::Shuffling => "shuffling",
+            Field::Repeating => "repeating",
+            Field::Volume => "volume",
+            Field::Track => "track",
+            Field::Position => "position",
+        })
+    }
+}
+
+fn join(fields: &[Field]) -> String {
+    fields
+        .iter()
+        .map(|field| field.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Why `apply` failed, and what it managed to put back.
+#[derive(Debug)]
+pub struct ApplyError {
+    pub field: Field,
+    pub cause: Error,
+    // applied before `field` failed
+    pub applied: Vec<Field>,
+    pub rolled_back: Vec<Field>,
+    // still changed, their old value unknown or refused
+    pub left_changed: Vec<Field>,
+}
+
+impl std::error::Error for ApplyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not set {}: {}", self.field, self.cause)?;
+        if !self.rolled_back.is_empty() {
+            write!(f, ", rolled back {}", join(&self.rolled_back))?;
+        }
+        if !self.left_changed.is_empty() {
+            write!(f, ", could not roll back {}", join(&self.left_changed))?;
+        }
+        Ok(())
+    }
+}
+
+// What to change, in the order it's applied. Fields left None stay as they
+// are.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PlayerChanges {
+    pub shuffling: Option<bool>,
+    pub repeating: Option<bool>,
+    pub volume: Option<i32>,
+    pub track: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub context: Option<String>,
+    // seconds, into `track` if there is one
+    pub position: Option<f64>,
+    // what's playing now was started in, which Spotify won't tell, so a
+    // rolled back track goes on in it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub playing_context: Option<String>,
+}
+
+enum Undo {
+    Shuffling(Option<bool>),
+    Repeating(Option<bool>),
+    Volume(Option<i32>),
+    Track {
+        uri: Option<String>,
+        context: Option<String>,
+        position: Option<f64>,
+        state: Option<State>,
+    },
+    Position(Option<f64>),
+}
+
+impl Undo {
+    fn field(&self) -> Field {
+        match self {
+            Undo::Shuffling(_) => Field::Shuffling,
+            Undo::Repeating(_) => Field::Repeating,
+            Undo::Volume(_) => Field::Volume,
+            Undo::Track { .. } => Field::Track,
+            Undo::Position(_) => Field::Position,
+        }
+    }
+
+    fn run<P: Player>(&self, player: &P) -> Result<bool> {
+        match self {
+            Undo::Shuffling(Some(was)) => player.set_shuffling(*was)?,
+            Undo::Repeating(Some(was)) => player.set_repeating(*was)?,
+            Undo::Volume(Some(was)) => player.set_volume(*was)?,
+            Undo::Position(Some(was)) => player.set_position(*was)?,
+            Undo::Track {
+                uri: Some(uri),
+                context,
+                position,
+                state,
+            } => {
+                player.play_track(uri.clone(), context.clone())?;
+                if let Some(position) = position {
+                    player.set_position(*position)?;
+                }
+                if *state != Some(State::PLAYING) {
+                    player.pause()?;
+                }
+            }
+            // nothing was playing before
+            Undo::Track { uri: None, .. } => player.pause()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn track_undo<P: Player>(player: &P, context: Option<String>) -> Result<Undo> {
+    Ok(Undo::Track {
+        uri: player.track()?.and_then(|t| t.spotify_url),
+        context,
+        position: player.position()?,
+        state: player.state()?,
+    })
+}
+
+fn same_track(playing: Option<&str>, wanted: &str) -> bool {
+    match (
+        playing.and_then(|uri| uri.parse::<SpotifyUri>().ok()),
+        wanted.parse::<SpotifyUri>(),
+    ) {
+        (Some(playing), Ok(wanted)) => playing == wanted,
+        _ => playing == Some(wanted),
+    }
+}
+
+impl PlayerChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == PlayerChanges::default()
+    }
+
+    // Applies each change and reads it back before the next. When one fails,
+    // the ones before it are put back as they were, newest first. Returns
+    // the fields that changed; ones already as asked are left alone.
+    pub fn apply_to<P: Player, C: Clock>(&self, player: &P, clock: &C) -> Result<Vec<Field>> {
+        let mut done: Vec<Undo> = Vec::new();
+
+        match self.apply_each(player, clock, &mut done) {
+            Ok(()) => Ok(done.iter().map(Undo::field).collect()),
+            Err((field, cause)) => {
+                let kind = cause.kind();
+                let applied: Vec<Field> = done
+                    .iter()
+                    .map(Undo::field)
+                    .filter(|applied| *applied != field)
+                    .collect();
+                let mut rolled_back = Vec::new();
+                let mut left_changed = Vec::new();
+
+                for undo in done.iter().rev() {
+                    match undo.run(player) {
+                        Ok(true) => rolled_back.push(undo.field()),
+                        _ => left_changed.push(undo.field()),
+                    }
+                }
+
+                Err(Error::new(
+                    kind,
+                    ApplyError {
+                        field,
+                        cause,
+                        applied,
+                        rolled_back,
+                        left_changed,
+                    },
+                ))
+            }
+        }
+    }
+
+    fn apply_each<P: Player, C: Clock>(
+        &self,
+        player: &P,
+        clock: &C,
+        done: &mut Vec<Undo>,
+    ) -> std::result::Result<(), (Field, Error)> {
+        if let Some(shuffling) = self.shuffling {
+            let field = Field::Shuffling;
+            let was = player.is_shuffling().map_err(|err| (field, err))?;
+            if was != Some(shuffling) {
+                player
+                    .set_shuffling(shuffling)
+                    .map_err(|err| (field, err))?;
+                done.push(Undo::Shuffling(was));
+                verify(clock, field, || {
+                    Ok(player.is_shuffling()? == Some(shuffling))
+                })?;
+            }
+        }
+
+        if let Some(repeating) = self.repeating {
+            let field = Field::Repeating;
+            let was = player.is_repeating().map_err(|err| (field, err))?;
+            if was != Some(repeating) {
+                player
+                    .set_repeating(repeating)
+                    .map_err(|err| (field, err))?;
+                done.push(Undo::Repeating(was));
+                verify(clock, field, || {
+                    Ok(player.is_repeating()? == Some(repeating))
+                })?;
+            }
+        }
+
+        if let Some(volume) = self.volume {
+            let field = Field::Volume;
+            let volume = volume.clamp(0, 100);
+            let was = player.volume().map_err(|err| (field, err))?;
+            if !matches!(was, Some(was) if (was - volume).abs() <= VOLUME_SLACK) {
+                player.set_volume(volume).map_err(|err| (field, err))?;
+                done.push(Undo::Volume(was));
+                verify(clock, field, || {
+                    Ok(player
+                        .volume()?
+                        .is_some_and(|now| (now - volume).abs() <= VOLUME_SLACK))
+                })?;
+            }
+        }
+
+        if let Some(track) = &self.track {
+            let field = Field::Track;
+            let undo =
+                track_undo(player, self.playing_context.clone()).map_err(|err| (field, err))?;
+
+            player
+                .play_track(track.clone(), self.context.clone())
+                .map_err(|err| (field, err))?;
+            done.push(undo);
+
+            // albums and playlists start on a track of their own
+            if track
+                .parse::<SpotifyUri>()
+                .is_ok_and(|uri| uri.is_playable())
+            {
+                verify(clock, field, || {
+                    let playing = player.track()?.and_then(|t| t.spotify_url);
+                    Ok(same_track(playing.as_deref(), track)
+                        && player.state()? == Some(State::PLAYING))
+                })?;
+            }
+        }
+
+        if let Some(position) = self.position {
+            let field = Field::Position;
+            let was = player.position().map_err(|err| (field, err))?;
+            player.set_position(position).map_err(|err| (field, err))?;
+            done.push(Undo::Position(was));
+            verify(clock, field, || {
+                Ok(player
+                    .position()?
+                    .is_some_and(|now| (now - position).abs() <= POSITION_SLACK))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+fn verify<C: Clock>(
+    clock: &C,
+    field: Field,
+    mut check: impl FnMut() -> Result<bool>,
+) -> std::result::Result<(), (Field, Error)> {
+    let deadline = clock.now() + VERIFY_TIMEOUT;
+
+    loop {
+        if check().map_err(|err| (field, err))? {
+            return Ok(());
+        }
+        if clock.now() >= deadline {
+            return Err((
+                field,
+                Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Spotify did not take the new {}", field),
+                ),
+            ));
+        }
+        clock.sleep(VERIFY_STEP);
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Spotify {
+    pub fn apply(&self, changes: PlayerChanges) -> Result<Vec<Field>> {
+        changes.apply_to(self, &SystemClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::testing::{FakePlayer, TRACK};
+    use std::time::UNIX_EPOCH;
+
+    const OTHER: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+    const ALBUM: &str = "spotify:album:1DFixLWuPkv3KT3TnV35m3";
+
+    fn apply_error(err: &Error) -> &ApplyError {
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<ApplyError>())
+            .expect("an ApplyError")
+    }
+
+    #[test]
+    fn applies_in_order_and_skips_what_is_already_set() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let changes = PlayerChanges {
+            shuffling: Some(false),
+            repeating: Some(true),
+            volume: Some(51),
+            track: Some(OTHER.to_string()),
+            context: Some(ALBUM.to_string()),
+            position: Some(30.0),
+            ..Default::default()
+        };
+
+        let changed = changes.apply_to(&player, &clock).unwrap();
+        assert_eq!(changed, [Field::Repeating, Field::Track, Field::Position]);
+        assert_eq!(player.count("set_shuffling"), 0);
+        assert_eq!(player.count("set_volume"), 0);
+        assert!(player
+            .calls()
+            .contains(&format!("play_track {} {}", OTHER, ALBUM)));
+        assert_eq!(player.get().position, Some(30.0));
+    }
+
+    #[test]
+    fn rolls_back_newest_first() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        player.fail("set_volume", &[-1712]);
+        let changes = PlayerChanges {
+            shuffling: Some(true),
+            repeating: Some(true),
+            volume: Some(20),
+            ..Default::default()
+        };
+
+        let err = changes.apply_to(&player, &clock).unwrap_err();
+        let err = apply_error(&err);
+        assert_eq!(err.field, Field::Volume);
+        assert_eq!(err.cause.raw_os_error(), Some(-1712));
+        assert_eq!(err.applied, [Field::Shuffling, Field::Repeating]);
+        assert_eq!(err.rolled_back, [Field::Repeating, Field::Shuffling]);
+        assert!(err.left_changed.is_empty());
+
+        let calls = player.calls();
+        let sets: Vec<_> = calls.iter().filter(|c| c.starts_with("set_")).collect();
+        assert_eq!(
+            sets,
+            [
+                "set_shuffling true",
+                "set_repeating true",
+                "set_volume 20",
+                "set_repeating false",
+                "set_shuffling false",
+            ]
+        );
+        assert_eq!(player.get().volume, Some(50));
+    }
+
+    #[test]
+    fn puts_the_old_track_back_in_its_context() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        player.fail("set_position", &[-609]);
+        let changes = PlayerChanges {
+            track: Some(OTHER.to_string()),
+            position: Some(30.0),
+            playing_context: Some(ALBUM.to_string()),
+            ..Default::default()
+        };
+
+        let err = changes.apply_to(&player, &clock).unwrap_err();
+        let err = apply_error(&err);
+        assert_eq!(err.field, Field::Position);
+        assert_eq!(err.rolled_back, [Field::Track]);
+
+        let calls = player.calls();
+        let restarted = format!("play_track {} {}", TRACK, ALBUM);
+        let at = calls
+            .iter()
+            .position(|c| *c == restarted)
+            .expect(&restarted);
+        // still playing before, so it isn't paused
+        assert_eq!(calls[at + 1..], ["set_position 10"]);
+        let snapshot = player.get();
+        assert_eq!(
+            snapshot.track.and_then(|t| t.spotify_url).as_deref(),
+            Some(TRACK)
+        );
+        assert_eq!(snapshot.position, Some(10.0));
+        assert_eq!(snapshot.state, Some(State::PLAYING));
+    }
+
+    #[test]
+    fn reports_what_it_could_not_put_back() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        // the old modes are unknown, and nothing was playing
+        player.set(|s| {
+            s.shuffling = None;
+            s.volume = None;
+            s.track = None;
+            s.state = Some(State::STOPPED);
+        });
+        player.fail("set_position", &[-609]);
+        let changes = PlayerChanges {
+            shuffling: Some(true),
+            volume: Some(20),
+            track: Some(OTHER.to_string()),
+            position: Some(30.0),
+            ..Default::default()
+        };
+
+        let err = changes.apply_to(&player, &clock).unwrap_err();
+        let err = apply_error(&err);
+        assert_eq!(err.field, Field::Position);
+        assert_eq!(err.applied, [Field::Shuffling, Field::Volume, Field::Track]);
+        assert_eq!(err.rolled_back, [Field::Track]);
+        assert_eq!(err.left_changed, [Field::Volume, Field::Shuffling]);
+        assert_eq!(player.get().state, Some(State::PAUSED));
+        assert!(err
+            .to_string()
+            .contains("could not roll back volume, shuffling"));
+    }
+
+    #[test]
+    fn gives_up_on_a_change_that_never_shows() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let stuck = PlayerChanges {
+            position: Some(500.0),
+            ..Default::default()
+        };
+        player.follow(&clock);
+        // past the end, so it stops at 200s
+        let err = stuck.apply_to(&player, &clock).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(clock.elapsed() >= VERIFY_TIMEOUT);
+        assert_eq!(apply_error(&err).rolled_back, [Field::Position]);
+        assert_eq!(player.get().position, Some(10.0));
+    }
+}
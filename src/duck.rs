@@ -0,0 +1,439 @@
+use crate::clock::Clock;
+use crate::fade::FadeCurve;
+use crate::player::Player;
+use std::io::Result;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+// Spotify rounds volumes it is given, so reading back what we set can be off
+// by one without anyone having touched it.
+const SLACK: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuckState {
+    #[default]
+    Idle,
+    // `volume` is what we last set, `restore` where to go back to
+    Ducked {
+        depth: u32,
+        restore: i32,
+        volume: i32,
+    },
+    // the volume was changed while ducked, so it is left alone from here on
+    Overridden {
+        depth: u32,
+    },
+}
+
+impl DuckState {
+    pub fn depth(self) -> u32 {
+        match self {
+            DuckState::Idle => 0,
+            DuckState::Ducked { depth, .. } | DuckState::Overridden { depth } => depth,
+        }
+    }
+
+    // Notices the volume having been moved by someone else. A volume that
+    // cannot be read counts as moved, so it is left alone.
+    pub fn observe(self, current: Option<i32>) -> DuckState {
+        match (self, current) {
+            (DuckState::Ducked { depth, volume, .. }, current) if !matches!(current, Some(c) if (c - volume).abs() <= SLACK) => {
+                DuckState::Overridden { depth }
+            }
+            (state, _) => state,
+        }
+    }
+
+    // The state after another duck request, and the volume to fade to if
+    // it should change. Ducking never raises the volume.
+    pub fn duck(self, current: Option<i32>, level: i32) -> (DuckState, Option<i32>) {
+        match (self.observe(current), current) {
+            (DuckState::Idle, Some(current)) => {
+                let volume = level.min(current);
+                let state = DuckState::Ducked {
+                    depth: 1,
+                    restore: current,
+                    volume,
+                };
+                (state, Some(volume).filter(|v| *v != current))
+            }
+            (
+                DuckState::Ducked {
+                    depth,
+                    restore,
+                    volume,
+                },
+                _,
+            ) => {
+                let state = DuckState::Ducked {
+                    depth: depth + 1,
+                    restore,
+                    volume: volume.min(level),
+                };
+                (state, Some(level).filter(|l| *l < volume))
+            }
+            // with nothing to go back to there is no ducking either
+            (state, _) => (
+                DuckState::Overridden {
+                    depth: state.depth() + 1,
+                },
+                None,
+            ),
+        }
+    }
+
+    // The state after one duck request is released, and the volume to fade
+    // back to once the last one is.
+    pub fn unduck(self, current: Option<i32>) -> (DuckState, Option<i32>) {
+        match self.observe(current) {
+            DuckState::Ducked {
+                depth: 1, restore, ..
+            } => (
+                DuckState::Idle,
+                Some(restore).filter(|r| Some(*r) != current),
+            ),
+            DuckState::Ducked {
+                depth,
+                restore,
+                volume,
+            } => {
+                let state = DuckState::Ducked {
+                    depth: depth - 1,
+                    restore,
+                    volume,
+                };
+                (state, None)
+            }
+            DuckState::Overridden { depth } if depth > 1 => {
+                (DuckState::Overridden { depth: depth - 1 }, None)
+            }
+            // an unbalanced unduck is ignored
+            DuckState::Overridden { .. } | DuckState::Idle => (DuckState::Idle, None),
+        }
+    }
+}
+
+// A fade in progress: where it goes and the volume it set last.
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    id: u64,
+    to: i32,
+    set: i32,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: DuckState,
+    fade: Option<Fade>,
+    fades: u64,
+}
+
+impl Shared {
+    // The volume to go by: where a fade is headed, unless it was moved off
+    // its course.
+    fn seen(&self, current: Option<i32>) -> Option<i32> {
+        match (self.fade, current) {
+            (Some(fade), Some(current)) if (current - fade.set).abs() <= SLACK => Some(fade.to),
+            _ => current,
+        }
+    }
+
+    // Starts a fade, which takes over from any other still running.
+    fn start(&mut self, from: i32, to: i32) -> u64 {
+        self.fades += 1;
+        self.fade = Some(Fade {
+            id: self.fades,
+            to,
+            set: from,
+        });
+        self.fades
+    }
+
+    fn is_fading(&self, id: u64) -> bool {
+        self.fade.map(|fade| fade.id) == Some(id)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub struct Ducker<P, C> {
+    player: P,
+    clock: C,
+    curve: FadeCurve,
+    step: Duration,
+    shared: Mutex<Shared>,
+}
+
+impl<P: Player, C: Clock> Ducker<P, C> {
+    pub fn new(player: P, clock: C) -> Ducker<P, C> {
+        Ducker {
+            player,
+            clock,
+            curve: FadeCurve::default(),
+            step: Duration::from_millis(50),
+            shared: Default::default(),
+        }
+    }
+
+    pub fn with_curve(mut self, curve: FadeCurve) -> Ducker<P, C> {
+        self.curve = curve;
+        self
+    }
+
+    pub fn with_step(mut self, step: Duration) -> Ducker<P, C> {
+        self.step = step;
+        self
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn state(&self) -> DuckState {
+        lock(&self.shared).state
+    }
+
+    pub fn is_ducked(&self) -> bool {
+        self.state() != DuckState::Idle
+    }
+
+    // Dips the volume to `level` until a matching `unduck`. Nested requests
+    // stay at the lowest level asked for.
+    pub fn duck(&self, level: i32, fade: Duration) -> Result<DuckState> {
+        self.change(fade, |state, current| {
+            state.duck(current, level.clamp(0, 100))
+        })
+    }
+
+    // Releases one duck request, going back to the old volume after the last
+    // one unless the volume was changed in the meantime.
+    pub fn unduck(&self, fade: Duration) -> Result<DuckState> {
+        self.change(fade, |state, current| state.unduck(current))
+    }
+
+    // Moves the state on under the lock, then fades without holding it so
+    // other requests get through and take over the fade.
+    fn change<F>(&self, fade: Duration, next: F) -> Result<DuckState>
+    where
+        F: FnOnce(DuckState, Option<i32>) -> (DuckState, Option<i32>),
+    {
+        let started = {
+            let mut shared = lock(&self.shared);
+            let current = self.player.volume()?;
+
+            let (state, target) = next(shared.state, shared.seen(current));
+            shared.state = state;
+
+            match (current, target) {
+                (Some(from), Some(to)) => Some((shared.start(from, to), from, to)),
+                _ => None,
+            }
+        };
+
+        if let Some((id, from, to)) = started {
+            let faded = self.fade(id, from, to, fade);
+            let mut shared = lock(&self.shared);
+            if shared.is_fading(id) {
+                shared.fade = None;
+                if let (Ok(false), DuckState::Ducked { depth, .. }) = (&faded, shared.state) {
+                    shared.state = DuckState::Overridden { depth };
+                }
+            }
+            drop(shared);
+            faded?;
+        }
+
+        Ok(self.state())
+    }
+
+    // False if the volume was moved by someone else part way through, true
+    // when done or taken over by another fade.
+    fn fade(&self, id: u64, from: i32, to: i32, fade: Duration) -> Result<bool> {
+        let started = self.clock.now();
+        let length = fade.as_secs_f64();
+        let mut volume = from;
+
+        loop {
+            let progress = if length > 0.0 {
+                self.clock
+                    .now()
+                    .saturating_duration_since(started)
+                    .as_secs_f64()
+                    / length
+            } else {
+                1.0
+            };
+            let target = self.curve.volume(from, to, progress);
+
+            {
+                let mut shared = lock(&self.shared);
+                if !shared.is_fading(id) {
+                    return Ok(true);
+                }
+                if target != volume {
+                    self.player.set_volume(target)?;
+                    volume = target;
+                    if let Some(fade) = &mut shared.fade {
+                        fade.set = volume;
+                    }
+                }
+            }
+
+            if progress >= 1.0 {
+                return Ok(true);
+            }
+
+            self.clock.sleep(self.step);
+
+            let shared = lock(&self.shared);
+            if !shared.is_fading(id) {
+                return Ok(true);
+            }
+            match self.player.volume()? {
+                Some(current) if (current - volume).abs() <= SLACK => {}
+                _ => return Ok(false),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Cued, FakePlayer};
+    use std::sync::Arc;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn ducked(depth: u32, restore: i32, volume: i32) -> DuckState {
+        DuckState::Ducked {
+            depth,
+            restore,
+            volume,
+        }
+    }
+
+    fn volumes(fake: &FakePlayer) -> Vec<i32> {
+        fake.calls()
+            .iter()
+            .filter_map(|call| call.strip_prefix("set_volume "))
+            .map(|v| v.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn nests_at_the_lowest_level() {
+        let (state, target) = DuckState::Idle.duck(Some(80), 30);
+        assert_eq!((state, target), (ducked(1, 80, 30), Some(30)));
+        let (state, target) = state.duck(Some(30), 50);
+        assert_eq!((state, target), (ducked(2, 80, 30), None));
+        let (state, target) = state.duck(Some(31), 10);
+        assert_eq!((state, target), (ducked(3, 80, 10), Some(10)));
+
+        let (state, target) = state.unduck(Some(10));
+        assert_eq!((state, target), (ducked(2, 80, 10), None));
+        let (state, _) = state.unduck(Some(10));
+        assert_eq!(state.unduck(Some(10)), (DuckState::Idle, Some(80)));
+
+        // never louder than it was
+        assert_eq!(
+            DuckState::Idle.duck(Some(20), 50),
+            (ducked(1, 20, 20), None)
+        );
+    }
+
+    #[test]
+    fn leaves_a_moved_or_unknown_volume_alone() {
+        let (state, _) = DuckState::Idle.duck(Some(80), 30);
+        let (state, target) = state.duck(Some(60), 10);
+        assert_eq!((state, target), (DuckState::Overridden { depth: 2 }, None));
+        let (state, _) = state.unduck(Some(60));
+        assert_eq!(state.unduck(Some(60)), (DuckState::Idle, None));
+
+        let (state, target) = DuckState::Idle.duck(None, 30);
+        assert_eq!((state, target), (DuckState::Overridden { depth: 1 }, None));
+        let (state, _) = DuckState::Idle.duck(Some(80), 30);
+        assert_eq!(state.unduck(None), (DuckState::Idle, None));
+
+        // an unbalanced unduck
+        assert_eq!(DuckState::Idle.unduck(Some(50)), (DuckState::Idle, None));
+    }
+
+    #[test]
+    fn fades_down_and_back() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let ducker = Ducker::new(&fake, &clock)
+            .with_curve(FadeCurve::Linear)
+            .with_step(Duration::from_millis(100));
+
+        assert_eq!(ducker.duck(20, SECOND).unwrap(), ducked(1, 50, 20));
+        assert_eq!(clock.elapsed(), SECOND);
+        assert_eq!(fake.get().volume, Some(20));
+        assert_eq!(ducker.unduck(SECOND).unwrap(), DuckState::Idle);
+        assert_eq!(fake.get().volume, Some(50));
+
+        let volumes = volumes(&fake);
+        assert_eq!(volumes.len(), 20);
+        assert!(volumes[..10].windows(2).all(|w| w[0] > w[1]));
+        assert!(volumes[10..].windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn stops_fading_when_the_volume_is_moved() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        clock.at(Duration::from_millis(500), || {
+            fake.set(|s| s.volume = Some(90))
+        });
+        let ducker = Ducker::new(&fake, &clock).with_step(Duration::from_millis(100));
+
+        let state = ducker.duck(20, SECOND).unwrap();
+        assert_eq!(state, DuckState::Overridden { depth: 1 });
+        assert!(clock.elapsed() < SECOND);
+        assert_eq!(ducker.unduck(SECOND).unwrap(), DuckState::Idle);
+        assert_eq!(fake.get().volume, Some(90));
+    }
+
+    #[test]
+    fn does_not_guess_an_unknown_volume() {
+        let fake = FakePlayer::new();
+        fake.set(|s| s.volume = None);
+        let clock = Cued::new();
+        let ducker = Ducker::new(&fake, &clock);
+
+        ducker.duck(20, SECOND).unwrap();
+        assert_eq!(ducker.unduck(SECOND).unwrap(), DuckState::Idle);
+        assert!(volumes(&fake).is_empty());
+        assert_eq!(fake.get().volume, None);
+    }
+
+    #[test]
+    fn another_request_takes_over_a_fade() {
+        let fake = Arc::new(FakePlayer::new());
+        let clock = Arc::new(Cued::new());
+        let ducker = Arc::new(
+            Ducker::new(fake.clone(), clock.clone())
+                .with_curve(FadeCurve::Linear)
+                .with_step(Duration::from_millis(100)),
+        );
+        // the lock is not held while fading, or this would never return
+        let weak = Arc::downgrade(&ducker);
+        clock.at(Duration::from_millis(500), move || {
+            let ducker = weak.upgrade().unwrap();
+            assert_eq!(ducker.duck(10, SECOND).unwrap(), ducked(2, 50, 10));
+        });
+
+        assert_eq!(ducker.duck(20, SECOND).unwrap(), ducked(2, 50, 10));
+        assert_eq!(fake.get().volume, Some(10));
+        // the first fade gave up once the second started
+        let volumes = volumes(&fake);
+        assert_eq!(volumes.iter().filter(|v| **v == 20).count(), 0);
+
+        ducker.unduck(SECOND).unwrap();
+        assert_eq!(ducker.unduck(SECOND).unwrap(), DuckState::Idle);
+        assert_eq!(fake.get().volume, Some(50));
+    }
+}
@@ -5,9 +5,12 @@ pub use crate::sys::{AEDesc, ResType};
 use encoding::all::{ASCII, UTF_16LE};
 use encoding::{DecoderTrap, EncoderTrap, Encoding};
 use libc::{c_char, c_uint, c_void, strlen};
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fmt;
 use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "tracing")]
+use std::time::Instant;
 
 pub trait AutoPropertyType: Sized {
     fn read(reader: EventPropertyReader) -> Result<Option<Self>>;
@@ -46,135 +49,138 @@ macro_rules! call {
     ( $obj:ident, $type:ident ) => {{
         let query = b"'----':'null'()\0" as *const u8;
 
-        let mut event: $crate::sys::AppleEvent = std::default::Default::default();
-        let mut err: $crate::sys::AEBuildError = std::default::Default::default();
-
-        let bundle_id = $crate::events::EventedObject::bundle_id($obj);
+        $crate::events::traced(
+            $crate::events::EventedObject::signature($obj),
+            $type,
+            None,
+            query,
+            || {
+                let mut event: $crate::sys::AppleEvent = std::default::Default::default();
+                let mut err: $crate::sys::AEBuildError = std::default::Default::default();
 
-        let res = unsafe {
-            $crate::sys::AEBuildAppleEvent(
-                $crate::events::EventedObject::signature($obj),
-                $type,
-                $crate::sys::typeApplicationBundleID,
-                bundle_id as *const $crate::sys::c_void,
-                $crate::sys::strlen(bundle_id),
-                $crate::sys::kAutoGenerateReturnID,
-                $crate::sys::kAnyTransactionID,
-                &mut event,
-                &mut err,
-                query
-            )
-        };
+                let bundle_id = $crate::events::EventedObject::bundle_id($obj);
 
-        if res == 0 {
-            let res = unsafe {
-                $crate::sys::AESendMessage(
-                    &event,
-                    std::ptr::null_mut(),
-                    $crate::sys::kAENoReply | $crate::sys::kAENeverInteract,
-                    $crate::sys::kAEDefaultTimeout,
-                )
-            };
+                let res = unsafe {
+                    $crate::sys::AEBuildAppleEvent(
+                        $crate::events::EventedObject::signature($obj),
+                        $type,
+                        $crate::sys::typeApplicationBundleID,
+                        bundle_id as *const $crate::sys::c_void,
+                        $crate::sys::strlen(bundle_id),
+                        $crate::sys::kAutoGenerateReturnID,
+                        $crate::sys::kAnyTransactionID,
+                        &mut event,
+                        &mut err,
+                        query
+                    )
+                };
 
-            if res == 0 {
-                Ok(())
-            } else {
-                Err(std::io::Error::from_raw_os_error(res))
-            }
-        } else {
-            Err($crate::events::EventBuildError::new(err))
-        }
+                if res == 0 {
+                    $crate::events::send(
+                        $crate::events::EventedObject::signature($obj),
+                        $type,
+                        &event,
+                        None,
+                        $crate::sys::kAENoReply | $crate::sys::kAENeverInteract,
+                    )
+                } else {
+                    Err($crate::events::EventBuildError::new(err))
+                }
+            },
+        )
     }};
 
     ( $obj:ident, $type:ident, $param:expr ) => {{
         let query = b"'----':@\0" as *const u8;
 
-        let mut event: $crate::sys::AppleEvent = std::default::Default::default();
-        let mut err: $crate::sys::AEBuildError = std::default::Default::default();
+        $crate::events::traced(
+            $crate::events::EventedObject::signature($obj),
+            $type,
+            None,
+            query,
+            || {
+                let mut event: $crate::sys::AppleEvent = std::default::Default::default();
+                let mut err: $crate::sys::AEBuildError = std::default::Default::default();
 
-        let bundle_id = $crate::events::EventedObject::bundle_id($obj);
+                let bundle_id = $crate::events::EventedObject::bundle_id($obj);
 
-        let res = unsafe {
-            $crate::sys::AEBuildAppleEvent(
-                $crate::events::EventedObject::signature($obj),
-                $type,
-                $crate::sys::typeApplicationBundleID,
-                bundle_id as *const $crate::sys::c_void,
-                $crate::sys::strlen(bundle_id),
-                $crate::sys::kAutoGenerateReturnID,
-                $crate::sys::kAnyTransactionID,
-                &mut event,
-                &mut err,
-                query,
-                &$crate::events::EventPropertyType::to_desc($param)?
-            )
-        };
-
-        if res == 0 {
-            let res = unsafe {
-                $crate::sys::AESendMessage(
-                    &event,
-                    std::ptr::null_mut(),
-                    $crate::sys::kAENoReply | $crate::sys::kAENeverInteract,
-                    $crate::sys::kAEDefaultTimeout,
-                )
-            };
+                let res = unsafe {
+                    $crate::sys::AEBuildAppleEvent(
+                        $crate::events::EventedObject::signature($obj),
+                        $type,
+                        $crate::sys::typeApplicationBundleID,
+                        bundle_id as *const $crate::sys::c_void,
+                        $crate::sys::strlen(bundle_id),
+                        $crate::sys::kAutoGenerateReturnID,
+                        $crate::sys::kAnyTransactionID,
+                        &mut event,
+                        &mut err,
+                        query,
+                        &$crate::events::EventPropertyType::to_desc($param)?
+                    )
+                };
 
-            if res == 0 {
-                Ok(())
-            } else {
-                Err(std::io::Error::from_raw_os_error(res))
-            }
-        } else {
-            Err($crate::events::EventBuildError::new(err))
-        }
+                if res == 0 {
+                    $crate::events::send(
+                        $crate::events::EventedObject::signature($obj),
+                        $type,
+                        &event,
+                        None,
+                        $crate::sys::kAENoReply | $crate::sys::kAENeverInteract,
+                    )
+                } else {
+                    Err($crate::events::EventBuildError::new(err))
+                }
+            },
+        )
     }};
 
     ( $obj:ident, $type:ident, $param:expr, $( $ts:ident : $pars:expr ),+ ) => {{
         let query = std::ffi::CString::new(vec!["'----':@".to_string(), $( format!("{}:@", $ts) ),+].join(", "))?;
 
-        let mut event: $crate::sys::AppleEvent = std::default::Default::default();
-        let mut err: $crate::sys::AEBuildError = std::default::Default::default();
+        $crate::events::traced(
+            $crate::events::EventedObject::signature($obj),
+            $type,
+            None,
+            query.as_ptr() as *const u8,
+            || {
+                let mut event: $crate::sys::AppleEvent = std::default::Default::default();
+                let mut err: $crate::sys::AEBuildError = std::default::Default::default();
 
-        let bundle_id = $crate::events::EventedObject::bundle_id($obj);
+                let bundle_id = $crate::events::EventedObject::bundle_id($obj);
 
-        let res = unsafe {
-            $crate::sys::AEBuildAppleEvent(
-                $crate::events::EventedObject::signature($obj),
-                $type,
-                $crate::sys::typeApplicationBundleID,
-                bundle_id as *const $crate::sys::c_void,
-                $crate::sys::strlen(bundle_id),
-                $crate::sys::kAutoGenerateReturnID,
-                $crate::sys::kAnyTransactionID,
-                &mut event,
-                &mut err,
-                query.as_ptr() as *const u8,
-                &$crate::events::EventPropertyType::to_desc($param)?,
-                $(
-                    &$crate::events::EventPropertyType::to_desc($pars)?
-                ),+
-            )
-        };
-
-        if res == 0 {
-            let res = unsafe {
-                $crate::sys::AESendMessage(
-                    &event,
-                    std::ptr::null_mut(),
-                    $crate::sys::kAENoReply | $crate::sys::kAENeverInteract,
-                    $crate::sys::kAEDefaultTimeout,
-                )
-            };
+                let res = unsafe {
+                    $crate::sys::AEBuildAppleEvent(
+                        $crate::events::EventedObject::signature($obj),
+                        $type,
+                        $crate::sys::typeApplicationBundleID,
+                        bundle_id as *const $crate::sys::c_void,
+                        $crate::sys::strlen(bundle_id),
+                        $crate::sys::kAutoGenerateReturnID,
+                        $crate::sys::kAnyTransactionID,
+                        &mut event,
+                        &mut err,
+                        query.as_ptr() as *const u8,
+                        &$crate::events::EventPropertyType::to_desc($param)?,
+                        $(
+                            &$crate::events::EventPropertyType::to_desc($pars)?
+                        ),+
+                    )
+                };
 
-            if res == 0 {
-                Ok(())
-            } else {
-                Err(std::io::Error::from_raw_os_error(res))
-            }
-        } else {
-            Err($crate::events::EventBuildError::new(err))
-        }
+                if res == 0 {
+                    $crate::events::send(
+                        $crate::events::EventedObject::signature($obj),
+                        $type,
+                        &event,
+                        None,
+                        $crate::sys::kAENoReply | $crate::sys::kAENeverInteract,
+                    )
+                } else {
+                    Err($crate::events::EventBuildError::new(err))
+                }
+            },
+        )
     }}
 }
 
@@ -266,6 +272,186 @@ impl fmt::Display for EventBuildError {
     }
 }
 
+// Where outgoing events go. Spotify itself by default, but a transport can
+// log them or answer them from a recording instead.
+pub trait Transport: Send + Sync {
+    // `reply` is None for events that don't wait for one.
+    fn send(
+        &self,
+        class: AEEventClass,
+        id: AEEventID,
+        event: &AppleEvent,
+        reply: Option<&mut AppleEvent>,
+        mode: i32,
+    ) -> Result<()>;
+}
+
+pub struct AppleEvents;
+
+impl Transport for AppleEvents {
+    fn send(
+        &self,
+        _class: AEEventClass,
+        _id: AEEventID,
+        event: &AppleEvent,
+        reply: Option<&mut AppleEvent>,
+        mode: i32,
+    ) -> Result<()> {
+        let reply = match reply {
+            Some(reply) => reply as *mut AppleEvent,
+            None => std::ptr::null_mut(),
+        };
+        let res = unsafe { AESendMessage(event, reply, mode, kAEDefaultTimeout) };
+
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(Error::from_raw_os_error(res))
+        }
+    }
+}
+
+static TRANSPORT: RwLock<Option<Arc<dyn Transport>>> = RwLock::new(None);
+
+// Sends every event from now on, in every thread, through `transport`, or
+// straight to Spotify again for None. Returns the transport it replaces.
+pub fn set_transport(transport: Option<Arc<dyn Transport>>) -> Option<Arc<dyn Transport>> {
+    std::mem::replace(&mut *TRANSPORT.write().unwrap(), transport)
+}
+
+// reborrowed for tracing to look at the reply afterwards
+#[cfg_attr(not(feature = "tracing"), allow(clippy::needless_option_as_deref))]
+pub fn send(
+    class: AEEventClass,
+    id: AEEventID,
+    event: &AppleEvent,
+    mut reply: Option<&mut AppleEvent>,
+    mode: i32,
+) -> Result<()> {
+    let transport = TRANSPORT.read().unwrap().clone();
+
+    #[cfg(feature = "tracing")]
+    crate::redaction::sending(|| print_params(event));
+
+    let res = match transport {
+        Some(transport) => transport.send(class, id, event, reply.as_deref_mut(), mode),
+        None => AppleEvents.send(class, id, event, reply.as_deref_mut(), mode),
+    };
+
+    #[cfg(feature = "tracing")]
+    if let (Ok(()), Some(reply)) = (&res, reply) {
+        trace_reply(reply);
+    }
+
+    res
+}
+
+#[cfg(feature = "tracing")]
+fn trace_reply(reply: &AppleEvent) {
+    let mut reply_type: DescType = Default::default();
+    let mut reply_size: usize = Default::default();
+    let res = unsafe { AESizeOfParam(reply, keyDirectObject, &mut reply_type, &mut reply_size) };
+
+    if res != 0 {
+        tracing::debug!(status = res, "reply has no direct object");
+    } else {
+        crate::redaction::reply(reply_type, reply_size, || print_params(reply));
+    }
+}
+
+// Runs `f`, which builds and sends an event, in a span describing it.
+#[doc(hidden)]
+#[cfg(feature = "tracing")]
+pub fn traced<T, F: FnOnce() -> Result<T>>(
+    class: AEEventClass,
+    id: AEEventID,
+    property: Option<DescType>,
+    query: *const u8,
+    f: F,
+) -> Result<T> {
+    let query = unsafe { CStr::from_ptr(query as *const c_char) }.to_string_lossy();
+    let span = match property {
+        Some(property) => tracing::debug_span!(
+            "apple_event",
+            class = %class,
+            id = %id,
+            property = %property,
+            query = %query
+        ),
+        None => tracing::debug_span!("apple_event", class = %class, id = %id, query = %query),
+    };
+    let _entered = span.enter();
+
+    let start = Instant::now();
+    let res = f();
+    let elapsed_us = start.elapsed().as_micros() as u64;
+
+    match &res {
+        Ok(_) => tracing::debug!(elapsed_us, "done"),
+        Err(err) => match err.raw_os_error() {
+            Some(status) => tracing::warn!(elapsed_us, status, "failed: {}", err),
+            None => tracing::warn!(elapsed_us, "failed: {}", err),
+        },
+    }
+
+    res
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub fn traced<T, F: FnOnce() -> Result<T>>(
+    _class: AEEventClass,
+    _id: AEEventID,
+    _property: Option<DescType>,
+    _query: *const u8,
+    f: F,
+) -> Result<T> {
+    f()
+}
+
+// The descriptor as AEGizmos text, e.g. `{ '----':'kPSP' }`.
+pub fn print_desc(desc: &AEDesc) -> Result<String> {
+    let mut handle: Handle = std::ptr::null_mut();
+    let res = unsafe { AEPrintDescToHandle(desc, &mut handle) };
+    if res != 0 {
+        return Err(Error::from_raw_os_error(res));
+    }
+
+    let text = unsafe { CStr::from_ptr(*handle) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { DisposeHandle(handle) };
+    Ok(text)
+}
+
+// The parameters of an event or reply without its attributes, which carry
+// return IDs that change from run to run.
+pub fn print_params(event: &AppleEvent) -> Result<String> {
+    let mut record: AERecord = Default::default();
+    let res = unsafe { AECoerceDesc(event, typeAERecord, &mut record) };
+
+    if res == 0 {
+        print_desc(&record)
+    } else {
+        Err(Error::from_raw_os_error(res.into()))
+    }
+}
+
+// Builds a descriptor back from the text `print_desc` gives.
+pub fn parse_desc(text: &str) -> Result<AEDesc> {
+    let text = CString::new(text).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let mut desc: AEDesc = Default::default();
+    let mut err: AEBuildError = Default::default();
+
+    let res = unsafe { AEBuildDesc(&mut desc, &mut err, text.as_ptr() as *const u8) };
+    if res == 0 {
+        Ok(desc)
+    } else {
+        Err(EventBuildError::new(err))
+    }
+}
+
 fn property_getter_format(target_object: &AEDesc, desc_type: DescType) -> Result<CString> {
     match CString::new(format!(
         "'----':obj {{ form:prop, want:type(prop), seld:type({}), from:{} }}",
@@ -370,63 +556,66 @@ pub struct EventPropertyReader<'a> {
     signature: ResType,
     bundle_id: *const c_char,
     target_object: &'a AEDesc,
+    property: DescType,
     query: *const u8,
 }
 
 fn compose_and_read_getter_event<'a>(reader: &'a EventPropertyReader) -> Result<AppleEvent> {
-    let mut event: AppleEvent = Default::default();
-    let mut err: AEBuildError = Default::default();
+    traced(
+        kAECoreSuite,
+        kAEGetData,
+        Some(reader.property),
+        reader.query,
+        || {
+            let mut event: AppleEvent = Default::default();
+            let mut err: AEBuildError = Default::default();
 
-    let res = unsafe {
-        if reader.target_object.is_null() {
-            AEBuildAppleEvent(
-                kAECoreSuite,
-                kAEGetData,
-                typeApplicationBundleID,
-                reader.bundle_id as *const c_void,
-                strlen(reader.bundle_id),
-                kAutoGenerateReturnID,
-                kAnyTransactionID,
-                &mut event,
-                &mut err,
-                reader.query,
-            )
-        } else {
-            AEBuildAppleEvent(
-                kAECoreSuite,
-                kAEGetData,
-                typeApplicationBundleID,
-                reader.bundle_id as *const c_void,
-                strlen(reader.bundle_id),
-                kAutoGenerateReturnID,
-                kAnyTransactionID,
-                &mut event,
-                &mut err,
-                reader.query,
-                reader.target_object,
-            )
-        }
-    };
-
-    if res == 0 {
-        let mut reply: AppleEvent = Default::default();
-        let res = unsafe {
-            AESendMessage(
-                &event,
-                &mut reply,
-                kAEWaitReply | kAENeverInteract,
-                kAEDefaultTimeout,
-            )
-        };
+            let res = unsafe {
+                if reader.target_object.is_null() {
+                    AEBuildAppleEvent(
+                        kAECoreSuite,
+                        kAEGetData,
+                        typeApplicationBundleID,
+                        reader.bundle_id as *const c_void,
+                        strlen(reader.bundle_id),
+                        kAutoGenerateReturnID,
+                        kAnyTransactionID,
+                        &mut event,
+                        &mut err,
+                        reader.query,
+                    )
+                } else {
+                    AEBuildAppleEvent(
+                        kAECoreSuite,
+                        kAEGetData,
+                        typeApplicationBundleID,
+                        reader.bundle_id as *const c_void,
+                        strlen(reader.bundle_id),
+                        kAutoGenerateReturnID,
+                        kAnyTransactionID,
+                        &mut event,
+                        &mut err,
+                        reader.query,
+                        reader.target_object,
+                    )
+                }
+            };
 
-        if res == 0 {
-            Ok(reply)
-        } else {
-            Err(Error::from_raw_os_error(res.into()))
-        }
-    } else {
-        Err(EventBuildError::new(err))
-    }
+            if res == 0 {
+                let mut reply: AppleEvent = Default::default();
+                send(
+                    kAECoreSuite,
+                    kAEGetData,
+                    &event,
+                    Some(&mut reply),
+                    kAEWaitReply | kAENeverInteract,
+                )?;
+                Ok(reply)
+            } else {
+                Err(EventBuildError::new(err))
+            }
+        },
+    )
 }
 
 impl<'a> EventPropertyReader<'a> {
@@ -440,6 +629,7 @@ impl<'a> EventPropertyReader<'a> {
             signature,
             bundle_id,
             target_object,
+            property,
             query: property_getter_format(target_object, property)?.into_raw() as *const u8,
         })
     }
@@ -475,6 +665,7 @@ pub struct EventPropertyWriter<'a> {
     signature: ResType,
     bundle_id: *const c_char,
     target_object: &'a AEDesc,
+    property: DescType,
     query: *const u8,
 }
 
@@ -489,60 +680,61 @@ impl<'a> EventPropertyWriter<'a> {
             signature,
             bundle_id,
             target_object,
+            property,
             query: property_setter_format(target_object, property)?.into_raw() as *const u8,
         })
     }
 
     fn write(&self, property: AEDesc) -> Result<()> {
-        let mut event: AppleEvent = Default::default();
-        let mut err: AEBuildError = Default::default();
+        traced(
+            kAECoreSuite,
+            kAESetData,
+            Some(self.property),
+            self.query,
+            || {
+                let mut event: AppleEvent = Default::default();
+                let mut err: AEBuildError = Default::default();
 
-        let res = unsafe {
-            if self.target_object.is_null() {
-                AEBuildAppleEvent(
-                    kAECoreSuite,
-                    kAESetData,
-                    typeApplicationBundleID,
-                    self.bundle_id as *const c_void,
-                    strlen(self.bundle_id),
-                    kAutoGenerateReturnID,
-                    kAnyTransactionID,
-                    &mut event,
-                    &mut err,
-                    self.query,
-                    &property,
-                )
-            } else {
-                AEBuildAppleEvent(
-                    kAECoreSuite,
-                    kAESetData,
-                    typeApplicationBundleID,
-                    self.bundle_id as *const c_void,
-                    strlen(self.bundle_id),
-                    kAutoGenerateReturnID,
-                    kAnyTransactionID,
-                    &mut event,
-                    &mut err,
-                    self.query,
-                    &property,
-                    self.target_object,
-                )
-            }
-        };
-
-        if res == 0 {
-            let res = unsafe {
-                AESendMessage(&event, std::ptr::null_mut(), kAENoReply, kAEDefaultTimeout)
-            };
+                let res = unsafe {
+                    if self.target_object.is_null() {
+                        AEBuildAppleEvent(
+                            kAECoreSuite,
+                            kAESetData,
+                            typeApplicationBundleID,
+                            self.bundle_id as *const c_void,
+                            strlen(self.bundle_id),
+                            kAutoGenerateReturnID,
+                            kAnyTransactionID,
+                            &mut event,
+                            &mut err,
+                            self.query,
+                            &property,
+                        )
+                    } else {
+                        AEBuildAppleEvent(
+                            kAECoreSuite,
+                            kAESetData,
+                            typeApplicationBundleID,
+                            self.bundle_id as *const c_void,
+                            strlen(self.bundle_id),
+                            kAutoGenerateReturnID,
+                            kAnyTransactionID,
+                            &mut event,
+                            &mut err,
+                            self.query,
+                            &property,
+                            self.target_object,
+                        )
+                    }
+                };
 
-            if res == 0 {
-                Ok(())
-            } else {
-                Err(Error::from_raw_os_error(res.into()))
-            }
-        } else {
-            Err(EventBuildError::new(err))
-        }
+                if res == 0 {
+                    send(kAECoreSuite, kAESetData, &event, None, kAENoReply)
+                } else {
+                    Err(EventBuildError::new(err))
+                }
+            },
+        )
     }
 }
 
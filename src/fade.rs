@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FadeCurve {
+    Linear,
+    // spends most of the fade at the quiet end, which sounds even to the ear
+    #[default]
+    Exponential,
+    SCurve,
+}
+
+impl FadeCurve {
+    // How far the volume has moved from the quiet end towards the loud one,
+    // for a fade that is `progress` (0 to 1) of the way through.
+    fn loudness(self, progress: f64) -> f64 {
+        let p = progress.clamp(0.0, 1.0);
+
+        match self {
+            FadeCurve::Linear => p,
+            FadeCurve::Exponential => (2f64.powf(6.0 * p) - 1.0) / 63.0,
+            FadeCurve::SCurve => p * p * (3.0 - 2.0 * p),
+        }
+    }
+
+    pub fn volume(self, from: i32, to: i32, progress: f64) -> i32 {
+        let p = progress.clamp(0.0, 1.0);
+        let (quiet, loud, towards_loud) = if to >= from {
+            (from, to, self.loudness(p))
+        } else {
+            (to, from, self.loudness(1.0 - p))
+        };
+
+        (quiet as f64 + (loud - quiet) as f64 * towards_loud).round() as i32
+    }
+}
+
+impl fmt::Display for FadeCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FadeCurve::Linear => "linear",
+            FadeCurve::Exponential => "exponential",
+            FadeCurve::SCurve => "s-curve",
+        })
+    }
+}
+
+impl FromStr for FadeCurve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FadeCurve, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "linear" => Ok(FadeCurve::Linear),
+            "exponential" | "exp" => Ok(FadeCurve::Exponential),
+            "s-curve" | "scurve" | "s" => Ok(FadeCurve::SCurve),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown fade curve {:?}", s),
+            )),
+        }
+    }
+}
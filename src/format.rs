@@ -0,0 +1,663 @@
+use crate::snapshot::Snapshot;
+use crate::state::State;
+use std::fmt::Write;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use unicode_segmentation::UnicodeSegmentation;
+
+const ELLIPSIS: &str = "…";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    None,
+    Pango,
+    Tmux,
+    Json,
+}
+
+impl Escape {
+    pub fn escape(self, value: &str) -> String {
+        match self {
+            Escape::None => value.to_string(),
+            Escape::Pango => {
+                let mut res = String::with_capacity(value.len());
+                for c in value.chars() {
+                    match c {
+                        '&' => res.push_str("&amp;"),
+                        '<' => res.push_str("&lt;"),
+                        '>' => res.push_str("&gt;"),
+                        '\'' => res.push_str("&apos;"),
+                        '"' => res.push_str("&quot;"),
+                        c => res.push(c),
+                    }
+                }
+                res
+            }
+            Escape::Tmux => value.replace('#', "##"),
+            Escape::Json => {
+                let mut res = String::with_capacity(value.len());
+                for c in value.chars() {
+                    match c {
+                        '"' => res.push_str("\\\""),
+                        '\\' => res.push_str("\\\\"),
+                        '\n' => res.push_str("\\n"),
+                        '\r' => res.push_str("\\r"),
+                        '\t' => res.push_str("\\t"),
+                        c if (c as u32) < 0x20 => {
+                            let _ = write!(res, "\\u{:04x}", c as u32);
+                        }
+                        c => res.push(c),
+                    }
+                }
+                res
+            }
+        }
+    }
+}
+
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", Escape::Json.escape(value))
+}
+
+fn syntax_error(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn format_time(seconds: f64) -> String {
+    let total = seconds.max(0.0) as u64;
+    let (hours, minutes, seconds) = (total / 3600, (total / 60) % 60, total % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn format_flag(value: bool) -> String {
+    if value { "on" } else { "off" }.to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Artist,
+    Title,
+    Album,
+    AlbumArtist,
+    State,
+    Position,
+    Duration,
+    Remaining,
+    Progress,
+    Volume,
+    Shuffle,
+    Repeat,
+    Url,
+    Uri,
+    Id,
+    ArtworkUrl,
+    TrackNumber,
+    DiskNumber,
+    PlayedCount,
+    Popularity,
+    Starred,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "artist" => Field::Artist,
+            "title" | "name" => Field::Title,
+            "album" => Field::Album,
+            "album_artist" => Field::AlbumArtist,
+            "state" => Field::State,
+            "position" => Field::Position,
+            "duration" => Field::Duration,
+            "remaining" => Field::Remaining,
+            "progress" => Field::Progress,
+            "volume" => Field::Volume,
+            "shuffle" => Field::Shuffle,
+            "repeat" => Field::Repeat,
+            "url" => Field::Url,
+            "uri" => Field::Uri,
+            "id" => Field::Id,
+            "artwork_url" => Field::ArtworkUrl,
+            "track_number" => Field::TrackNumber,
+            "disk_number" => Field::DiskNumber,
+            "played_count" => Field::PlayedCount,
+            "popularity" => Field::Popularity,
+            "starred" => Field::Starred,
+            _ => return None,
+        })
+    }
+
+    fn value(self, snapshot: &Snapshot) -> Option<String> {
+        let track = snapshot.track.as_ref();
+
+        match self {
+            Field::Artist => track.and_then(|t| t.artist.clone()),
+            Field::Title => track.and_then(|t| t.name.clone()),
+            Field::Album => track.and_then(|t| t.album.clone()),
+            Field::AlbumArtist => track.and_then(|t| t.album_artist.clone()),
+            Field::State => snapshot.state.map(|s| s.to_string()),
+            Field::Position => snapshot.position.map(format_time),
+            Field::Duration => snapshot.duration().map(format_time),
+            Field::Remaining => snapshot.remaining().map(format_time),
+            Field::Progress => snapshot
+                .progress()
+                .map(|p| format!("{}", (p * 100.0).round() as u32)),
+            Field::Volume => snapshot.volume.map(|v| v.to_string()),
+            Field::Shuffle => snapshot.shuffling.map(format_flag),
+            Field::Repeat => snapshot.repeating.map(format_flag),
+            Field::Url => track.and_then(|t| t.url()),
+            Field::Uri => track.and_then(|t| t.spotify_url.clone()),
+            Field::Id => track.and_then(|t| t.id.clone()),
+            Field::ArtworkUrl => track.and_then(|t| t.artwork_url.clone()),
+            Field::TrackNumber => track.and_then(|t| t.track_number).map(|n| n.to_string()),
+            Field::DiskNumber => track.and_then(|t| t.disk_number).map(|n| n.to_string()),
+            Field::PlayedCount => track.and_then(|t| t.played_count).map(|n| n.to_string()),
+            Field::Popularity => track.and_then(|t| t.popularity).map(|n| n.to_string()),
+            Field::Starred => track.and_then(|t| t.starred).map(format_flag),
+        }
+    }
+
+    fn is_truthy(self, snapshot: &Snapshot) -> bool {
+        let track = snapshot.track.as_ref();
+
+        match self {
+            Field::Shuffle => snapshot.shuffling.unwrap_or(false),
+            Field::Repeat => snapshot.repeating.unwrap_or(false),
+            Field::Starred => track.and_then(|t| t.starred).unwrap_or(false),
+            _ => self.value(snapshot).is_some_and(|v| !v.is_empty()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Field(Field),
+    Equals(Field, String),
+    State(State),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    fn parse(source: &str) -> Result<Condition> {
+        let source = source.trim();
+
+        if let Some(rest) = source.strip_prefix('!') {
+            return Ok(Condition::Not(Box::new(Condition::parse(rest)?)));
+        }
+
+        if let Some(idx) = source.find("!=") {
+            let field = parse_field(&source[..idx])?;
+            let value = source[idx + 2..].trim().to_string();
+            return Ok(Condition::Not(Box::new(Condition::Equals(field, value))));
+        }
+
+        if let Some(idx) = source.find('=') {
+            let field = parse_field(&source[..idx])?;
+            let value = source[idx + 1..].trim().to_string();
+            return Ok(Condition::Equals(field, value));
+        }
+
+        match source {
+            "playing" => Ok(Condition::State(State::PLAYING)),
+            "paused" => Ok(Condition::State(State::PAUSED)),
+            "stopped" => Ok(Condition::State(State::STOPPED)),
+            name => Ok(Condition::Field(parse_field(name)?)),
+        }
+    }
+
+    fn eval(&self, snapshot: &Snapshot) -> bool {
+        match self {
+            Condition::Field(field) => field.is_truthy(snapshot),
+            Condition::Equals(field, value) => field
+                .value(snapshot)
+                .is_some_and(|v| v.eq_ignore_ascii_case(value)),
+            Condition::State(state) => snapshot.state == Some(*state),
+            Condition::Not(cond) => !cond.eval(snapshot),
+        }
+    }
+}
+
+fn parse_field(name: &str) -> Result<Field> {
+    let name = name.trim();
+    Field::from_name(name).ok_or_else(|| syntax_error(format!("Unknown field `{}`", name)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Truncate(usize),
+    Pad(usize),
+    LeftPad(usize),
+    Upper,
+    Lower,
+    Default(String),
+}
+
+impl Filter {
+    fn parse(source: &str) -> Result<Filter> {
+        let source = source.trim();
+        let (name, arg) = match source.find('(') {
+            Some(idx) if source.ends_with(')') => {
+                (&source[..idx], Some(&source[idx + 1..source.len() - 1]))
+            }
+            Some(_) => return Err(syntax_error(format!("Malformed filter `{}`", source))),
+            None => (source, None),
+        };
+
+        let width = || -> Result<usize> {
+            arg.and_then(|arg| arg.trim().parse().ok())
+                .ok_or_else(|| syntax_error(format!("Filter `{}` needs a width", name)))
+        };
+
+        match name.trim() {
+            "truncate" | "trunc" => Ok(Filter::Truncate(width()?)),
+            "pad" => Ok(Filter::Pad(width()?)),
+            "lpad" => Ok(Filter::LeftPad(width()?)),
+            "upper" => Ok(Filter::Upper),
+            "lower" => Ok(Filter::Lower),
+            "default" => Ok(Filter::Default(arg.unwrap_or("").to_string())),
+            name => Err(syntax_error(format!("Unknown filter `{}`", name))),
+        }
+    }
+
+    fn apply(&self, value: Option<String>) -> Option<String> {
+        if let Filter::Default(default) = self {
+            return Some(value.unwrap_or_else(|| default.clone()));
+        }
+
+        let value = value?;
+
+        Some(match self {
+            Filter::Truncate(width) => truncate(&value, *width),
+            Filter::Pad(width) => {
+                let len = display_width(&value);
+                format!("{}{}", value, " ".repeat(width.saturating_sub(len)))
+            }
+            Filter::LeftPad(width) => {
+                let len = display_width(&value);
+                format!("{}{}", " ".repeat(width.saturating_sub(len)), value)
+            }
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Default(_) => value,
+        })
+    }
+}
+
+// East Asian wide and fullwidth characters and emoji, which take two columns
+const WIDE: &[(u32, u32)] = &[
+    (0x1100, 0x115f),
+    (0x231a, 0x231b),
+    (0x2e80, 0x303e),
+    (0x3041, 0x33ff),
+    (0x3400, 0x4dbf),
+    (0x4e00, 0x9fff),
+    (0xa000, 0xa4cf),
+    (0xac00, 0xd7a3),
+    (0xf900, 0xfaff),
+    (0xfe30, 0xfe4f),
+    (0xff00, 0xff60),
+    (0xffe0, 0xffe6),
+    (0x1f300, 0x1f64f),
+    (0x1f680, 0x1f6ff),
+    (0x1f900, 0x1f9ff),
+    (0x20000, 0x3fffd),
+];
+
+fn grapheme_width(grapheme: &str) -> usize {
+    let first = match grapheme.chars().next() {
+        Some(c) => c as u32,
+        None => return 0,
+    };
+
+    if grapheme.contains('\u{fe0f}') || WIDE.iter().any(|&(lo, hi)| lo <= first && first <= hi) {
+        2
+    } else if first < 0x20 {
+        0
+    } else {
+        1
+    }
+}
+
+// Columns `value` takes in a terminal or bar, not its length in graphemes.
+pub fn display_width(value: &str) -> usize {
+    value.graphemes(true).map(grapheme_width).sum()
+}
+
+pub fn truncate(value: &str, width: usize) -> String {
+    if display_width(value) <= width {
+        return value.to_string();
+    }
+
+    if width == 0 {
+        return String::new();
+    }
+
+    let mut res = String::new();
+    let mut used = 0;
+    for grapheme in value.graphemes(true) {
+        used += grapheme_width(grapheme);
+        if used > width - 1 {
+            break;
+        }
+        res.push_str(grapheme);
+    }
+    res.truncate(res.trim_end().len());
+    res.push_str(ELLIPSIS);
+    res
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Field(Field, Vec<Filter>),
+    Cond(Condition, Vec<Node>, Vec<Node>),
+}
+
+struct Frame {
+    condition: Condition,
+    parent: Vec<Node>,
+    then: Option<Vec<Node>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template> {
+        let mut stack: Vec<Frame> = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((idx, c)) = chars.next() {
+            let doubled = chars.peek().map(|&(_, next)| next) == Some(c);
+
+            match c {
+                '{' | '}' if doubled => {
+                    chars.next();
+                    text.push(c);
+                }
+                '{' => {
+                    let end = loop {
+                        match chars.next() {
+                            Some((end, '}')) => break end,
+                            Some(_) => {}
+                            None => {
+                                return Err(syntax_error(format!(
+                                    "Unterminated tag at position {}",
+                                    idx
+                                )))
+                            }
+                        }
+                    };
+
+                    if !text.is_empty() {
+                        nodes.push(Node::Text(std::mem::take(&mut text)));
+                    }
+
+                    let tag = source[idx + 1..end].trim();
+
+                    if let Some(cond) = tag.strip_prefix('?') {
+                        stack.push(Frame {
+                            condition: Condition::parse(cond)?,
+                            parent: std::mem::take(&mut nodes),
+                            then: None,
+                        });
+                    } else if tag == ":" {
+                        match stack.last_mut() {
+                            Some(frame) if frame.then.is_none() => {
+                                frame.then = Some(std::mem::take(&mut nodes));
+                            }
+                            _ => {
+                                return Err(syntax_error(format!(
+                                    "Unexpected `{{:}}` at position {}",
+                                    idx
+                                )))
+                            }
+                        }
+                    } else if tag == "/" {
+                        let frame = stack.pop().ok_or_else(|| {
+                            syntax_error(format!("Unexpected `{{/}}` at position {}", idx))
+                        })?;
+                        let (then, otherwise) = match frame.then {
+                            Some(then) => (then, std::mem::take(&mut nodes)),
+                            None => (std::mem::take(&mut nodes), Vec::new()),
+                        };
+                        nodes = frame.parent;
+                        nodes.push(Node::Cond(frame.condition, then, otherwise));
+                    } else {
+                        let mut parts = tag.split('|');
+                        let field = parse_field(parts.next().unwrap_or(""))?;
+                        let filters = parts.map(Filter::parse).collect::<Result<Vec<_>>>()?;
+                        nodes.push(Node::Field(field, filters));
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+
+        if !stack.is_empty() {
+            return Err(syntax_error("Unclosed conditional".to_string()));
+        }
+
+        if !text.is_empty() {
+            nodes.push(Node::Text(text));
+        }
+
+        Ok(Template { nodes })
+    }
+
+    pub fn render(&self, snapshot: &Snapshot, escape: Escape) -> String {
+        let mut res = String::new();
+        render_nodes(&self.nodes, snapshot, escape, &mut res);
+        res
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Template> {
+        Template::parse(source)
+    }
+}
+
+fn render_nodes(nodes: &[Node], snapshot: &Snapshot, escape: Escape, res: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => res.push_str(text),
+            Node::Field(field, filters) => {
+                let value = filters
+                    .iter()
+                    .fold(field.value(snapshot), |value, filter| filter.apply(value));
+
+                if let Some(value) = value {
+                    res.push_str(&escape.escape(&value));
+                }
+            }
+            Node::Cond(condition, then, otherwise) => {
+                if condition.eval(snapshot) {
+                    render_nodes(then, snapshot, escape, res);
+                } else {
+                    render_nodes(otherwise, snapshot, escape, res);
+                }
+            }
+        }
+    }
+}
+
+fn state_class(snapshot: &Snapshot) -> String {
+    snapshot
+        .state
+        .map_or_else(|| "stopped".to_string(), |s| s.to_string())
+}
+
+pub fn waybar(snapshot: &Snapshot, text: &Template, tooltip: &Template) -> String {
+    let class = state_class(snapshot);
+    let mut res = format!(
+        "{{\"text\":{},\"tooltip\":{},\"alt\":{},\"class\":{}",
+        json_string(&text.render(snapshot, Escape::Pango)),
+        json_string(&tooltip.render(snapshot, Escape::Pango)),
+        json_string(&class),
+        json_string(&class),
+    );
+
+    if let Some(progress) = snapshot.progress() {
+        let _ = write!(res, ",\"percentage\":{}", (progress * 100.0).round() as u32);
+    }
+
+    res.push('}');
+    res
+}
+
+// Printed once before the first `i3bar` line, opens the endless array of
+// status lines the i3bar protocol expects.
+pub const I3BAR_HEADER: &str = "{\"version\":1}\n[\n[]";
+
+// One status line for i3bar, to print after `I3BAR_HEADER`.
+pub fn i3bar(snapshot: &Snapshot, full_text: &Template, short_text: Option<&Template>) -> String {
+    let mut res = format!(
+        ",[{{\"name\":\"spotify\",\"instance\":{},\"full_text\":{}",
+        json_string(&state_class(snapshot)),
+        json_string(&full_text.render(snapshot, Escape::None)),
+    );
+
+    if let Some(short_text) = short_text {
+        let _ = write!(
+            res,
+            ",\"short_text\":{}",
+            json_string(&short_text.render(snapshot, Escape::None))
+        );
+    }
+
+    res.push_str("}]");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{track, TRACK};
+
+    fn snapshot() -> Snapshot {
+        let mut track = track(TRACK, 200_000);
+        track.name = Some("Fish & <Chips>".to_string());
+        Snapshot {
+            state: Some(State::PLAYING),
+            position: Some(65.0),
+            volume: Some(50),
+            shuffling: Some(true),
+            repeating: Some(false),
+            track: Some(track),
+        }
+    }
+
+    fn render(source: &str) -> String {
+        Template::parse(source)
+            .unwrap()
+            .render(&snapshot(), Escape::None)
+    }
+
+    #[test]
+    fn fields_and_braces() {
+        assert_eq!(render("{artist} - {title}"), "Band - Fish & <Chips>");
+        assert_eq!(render("{position}/{duration} {progress}%"), "1:05/3:20 33%");
+        assert_eq!(render("{{{volume}}}"), "{50}");
+        assert_eq!(
+            render("{url}"),
+            "https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6"
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(render("{?playing}>{:}||{/}"), ">");
+        assert_eq!(render("{?paused}||{:}>{/}"), ">");
+        assert_eq!(render("{?shuffle}S{/}{?repeat}R{/}"), "S");
+        assert_eq!(render("{?!repeat}no repeat{/}"), "no repeat");
+        assert_eq!(render("{?artist=band}yes{/}{?artist!=Band}no{/}"), "yes");
+        assert_eq!(render("{?album_artist}{album_artist}{:}none{/}"), "none");
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(render("{artist|upper}{artist|lower}"), "BANDband");
+        assert_eq!(render("[{artist|pad(6)}]"), "[Band  ]");
+        assert_eq!(render("[{artist|lpad(6)}]"), "[  Band]");
+        assert_eq!(render("{title|truncate(8)}"), "Fish &…");
+        assert_eq!(render("{album_artist|default(n/a)|upper}"), "N/A");
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for source in &[
+            "{artist",
+            "{nope}",
+            "{artist|nope}",
+            "{artist|pad}",
+            "{?playing}",
+            "{/}",
+            "{:}",
+            "{?playing}{:}{:}{/}",
+        ] {
+            let err = Template::parse(source).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", source);
+        }
+    }
+
+    #[test]
+    fn width_counts_columns() {
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("👍🏽"), 2);
+
+        let title = Filter::Pad(8).apply(Some("日本語".to_string()));
+        assert_eq!(title.as_deref(), Some("日本語  "));
+        assert_eq!(truncate("日本語です", 6), "日本…");
+        assert_eq!(truncate("日本語", 6), "日本語");
+        assert_eq!(truncate("anything", 0), "");
+    }
+
+    #[test]
+    fn escapes() {
+        let value = "a&b <c> 'd' \"e\" #f \\ \n";
+        assert_eq!(Escape::None.escape(value), value);
+        assert_eq!(
+            Escape::Pango.escape(value),
+            "a&amp;b &lt;c&gt; &apos;d&apos; &quot;e&quot; #f \\ \n"
+        );
+        assert_eq!(Escape::Tmux.escape(value), "a&b <c> 'd' \"e\" ##f \\ \n");
+        assert_eq!(
+            Escape::Json.escape(value),
+            "a&b <c> 'd' \\\"e\\\" #f \\\\ \\n"
+        );
+        assert_eq!(Escape::Json.escape("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn bars() {
+        let text = Template::parse("{title}").unwrap();
+        let short = Template::parse("{artist}").unwrap();
+
+        assert_eq!(
+            waybar(&snapshot(), &text, &short),
+            "{\"text\":\"Fish &amp; &lt;Chips&gt;\",\"tooltip\":\"Band\",\
+             \"alt\":\"playing\",\"class\":\"playing\",\"percentage\":33}"
+        );
+
+        let line = i3bar(&snapshot(), &text, Some(&short));
+        assert_eq!(
+            line,
+            ",[{\"name\":\"spotify\",\"instance\":\"playing\",\
+             \"full_text\":\"Fish & <Chips>\",\"short_text\":\"Band\"}]"
+        );
+        assert!(I3BAR_HEADER.starts_with("{\"version\":1}\n["));
+    }
+}
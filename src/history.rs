@@ -0,0 +1,509 @@
+use crate::calendar::{from_unix, unix};
+use crate::player::Player;
+use crate::plays::{Play, PlayEvent, PlayTracker};
+use crate::snapshot::Snapshot;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Error, Result};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Every entry bumps the schema by one, never edit one that has shipped.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE plays (
+        id INTEGER PRIMARY KEY,
+        track_id TEXT,
+        name TEXT,
+        artist TEXT,
+        album TEXT,
+        started_at INTEGER NOT NULL,
+        listened_ms INTEGER NOT NULL,
+        duration_ms INTEGER,
+        skipped INTEGER NOT NULL
+    );
+    CREATE INDEX plays_started_at ON plays (started_at);",
+    "ALTER TABLE plays ADD COLUMN context TEXT;
+    CREATE INDEX plays_artist ON plays (artist);
+    CREATE INDEX plays_track_id ON plays (track_id);",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Entry {
+    pub id: i64,
+    pub track_id: Option<String>,
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub started_at: SystemTime,
+    pub listened: Duration,
+    pub duration: Option<Duration>,
+    pub skipped: bool,
+    pub context: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TopArtist {
+    pub artist: String,
+    pub plays: u64,
+    pub listened: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TopTrack {
+    pub track_id: String,
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub plays: u64,
+    pub listened: Duration,
+}
+
+fn sql_error(err: rusqlite::Error) -> Error {
+    Error::other(err)
+}
+
+fn from_ms(ms: i64) -> Duration {
+    Duration::from_millis(ms.max(0) as u64)
+}
+
+fn entry(row: &Row) -> rusqlite::Result<Entry> {
+    Ok(Entry {
+        id: row.get(0)?,
+        track_id: row.get(1)?,
+        name: row.get(2)?,
+        artist: row.get(3)?,
+        album: row.get(4)?,
+        started_at: from_unix(row.get(5)?),
+        listened: from_ms(row.get(6)?),
+        duration: row.get::<_, Option<i64>>(7)?.map(from_ms),
+        skipped: row.get(8)?,
+        context: row.get(9)?,
+    })
+}
+
+const ENTRY_COLUMNS: &str =
+    "id, track_id, name, artist, album, started_at, listened_ms, duration_ms, skipped, context";
+
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<History> {
+        History::with_connection(Connection::open(path).map_err(sql_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<History> {
+        History::with_connection(Connection::open_in_memory().map_err(sql_error)?)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<History> {
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(sql_error)?;
+
+        if version > MIGRATIONS.len() {
+            return Err(Error::other(format!(
+                "history database schema {} is newer than supported {}",
+                version,
+                MIGRATIONS.len()
+            )));
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction().map_err(sql_error)?;
+            tx.execute_batch(migration).map_err(sql_error)?;
+            tx.pragma_update(None, "user_version", i + 1)
+                .map_err(sql_error)?;
+            tx.commit().map_err(sql_error)?;
+        }
+
+        Ok(History { conn })
+    }
+
+    pub fn schema_version(&self) -> Result<usize> {
+        self.conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(sql_error)
+    }
+
+    pub fn record(&self, play: &Play, context: Option<&str>) -> Result<i64> {
+        let track = &play.track;
+
+        self.conn
+            .execute(
+                "INSERT INTO plays (track_id, name, artist, album, started_at, listened_ms, duration_ms, skipped, context)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    track.spotify_url.as_ref().or(track.id.as_ref()),
+                    track.name,
+                    track.artist,
+                    track.album,
+                    unix(play.started_at),
+                    play.listened.as_millis() as i64,
+                    play.duration().map(|d| d.as_millis() as i64),
+                    play.skipped,
+                    context,
+                ],
+            )
+            .map_err(sql_error)?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<Entry>> {
+        self.conn
+            .query_row(
+                &format!("SELECT {} FROM plays WHERE id = ?1", ENTRY_COLUMNS),
+                [id],
+                entry,
+            )
+            .optional()
+            .map_err(sql_error)
+    }
+
+    pub fn recent(&self, limit: usize) -> Result<Vec<Entry>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM plays ORDER BY started_at DESC, id DESC LIMIT ?1",
+                ENTRY_COLUMNS
+            ))
+            .map_err(sql_error)?;
+
+        let rows = stmt.query_map([limit as i64], entry).map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(sql_error)
+    }
+
+    // Plays that started in [from, to).
+    pub fn between(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Entry>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM plays WHERE started_at >= ?1 AND started_at < ?2 ORDER BY started_at, id",
+                ENTRY_COLUMNS
+            ))
+            .map_err(sql_error)?;
+
+        let rows = stmt
+            .query_map([unix(from), unix(to)], entry)
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(sql_error)
+    }
+
+    // Skipped plays are left out, they say little about what you like.
+    pub fn top_artists(
+        &self,
+        from: SystemTime,
+        to: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<TopArtist>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT artist, COUNT(*), SUM(listened_ms) FROM plays
+                 WHERE started_at >= ?1 AND started_at < ?2 AND NOT skipped AND artist IS NOT NULL
+                 GROUP BY artist ORDER BY COUNT(*) DESC, SUM(listened_ms) DESC, artist LIMIT ?3",
+            )
+            .map_err(sql_error)?;
+
+        let rows = stmt
+            .query_map(params![unix(from), unix(to), limit as i64], |row| {
+                Ok(TopArtist {
+                    artist: row.get(0)?,
+                    plays: row.get::<_, i64>(1)? as u64,
+                    listened: from_ms(row.get(2)?),
+                })
+            })
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(sql_error)
+    }
+
+    pub fn top_tracks(
+        &self,
+        from: SystemTime,
+        to: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<TopTrack>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT track_id, MAX(name), MAX(artist), COUNT(*), SUM(listened_ms) FROM plays
+                 WHERE started_at >= ?1 AND started_at < ?2 AND NOT skipped AND track_id IS NOT NULL
+                 GROUP BY track_id ORDER BY COUNT(*) DESC, SUM(listened_ms) DESC, track_id LIMIT ?3",
+            )
+            .map_err(sql_error)?;
+
+        let rows = stmt
+            .query_map(params![unix(from), unix(to), limit as i64], |row| {
+                Ok(TopTrack {
+                    track_id: row.get(0)?,
+                    name: row.get(1)?,
+                    artist: row.get(2)?,
+                    plays: row.get::<_, i64>(3)? as u64,
+                    listened: from_ms(row.get(4)?),
+                })
+            })
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(sql_error)
+    }
+
+    // Tracks played at least once before `before`.
+    pub fn known_tracks(&self, before: SystemTime) -> Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT track_id FROM plays WHERE started_at < ?1 AND track_id IS NOT NULL")
+            .map_err(sql_error)?;
+
+        let rows = stmt
+            .query_map([unix(before)], |row| row.get(0))
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(sql_error)
+    }
+
+    // Share of plays in [from, to) that were skipped, None when nothing was played.
+    pub fn skip_rate(&self, from: SystemTime, to: SystemTime) -> Result<Option<f64>> {
+        let (total, skipped): (i64, i64) = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*), COALESCE(SUM(skipped), 0) FROM plays
+                 WHERE started_at >= ?1 AND started_at < ?2",
+                [unix(from), unix(to)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(sql_error)?;
+
+        Ok(if total == 0 {
+            None
+        } else {
+            Some(skipped as f64 / total as f64)
+        })
+    }
+}
+
+pub struct Recorder {
+    history: History,
+    tracker: PlayTracker,
+    context: Option<String>,
+    // context the current play started in
+    playing_context: Option<String>,
+}
+
+impl Recorder {
+    pub fn new(history: History) -> Recorder {
+        Recorder {
+            history,
+            tracker: PlayTracker::new(),
+            context: None,
+            playing_context: None,
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    // Spotify does not tell us what it is playing from, so whoever starts
+    // playback (play_track with a context) should say so here.
+    pub fn set_context(&mut self, context: Option<String>) {
+        self.context = context;
+    }
+
+    pub fn poll<P: Player>(&mut self, player: &P) -> Result<Vec<Entry>> {
+        let snapshot = player.snapshot()?;
+        self.update(&snapshot, SystemTime::now())
+    }
+
+    pub fn update(&mut self, snapshot: &Snapshot, now: SystemTime) -> Result<Vec<Entry>> {
+        let mut recorded = Vec::new();
+
+        for event in self.tracker.update(snapshot, now) {
+            match event {
+                PlayEvent::Started(_) => self.playing_context = self.context.clone(),
+                PlayEvent::Finished(play) => recorded.push(self.record(&play)?),
+            }
+        }
+
+        Ok(recorded)
+    }
+
+    // Records the play in progress, if any, e.g. before shutting down.
+    pub fn finish(&mut self) -> Result<Option<Entry>> {
+        match self.tracker.finish() {
+            Some(play) => self.record(&play).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn record(&mut self, play: &Play) -> Result<Entry> {
+        let context = self.playing_context.take();
+        let id = self.history.record(play, context.as_deref())?;
+
+        self.history
+            .get(id)?
+            .ok_or_else(|| Error::other("recorded play vanished"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::from_unix;
+    use crate::state::State;
+    use crate::testing::{track, TempDir};
+
+    const T0: i64 = 1_700_000_000;
+
+    fn play(id: &str, artist: &str, at: i64, listened: u64, skipped: bool) -> Play {
+        let mut track = track(&format!("spotify:track:{}", id), 200_000);
+        track.artist = Some(artist.to_string());
+        Play {
+            track,
+            started_at: from_unix(at),
+            listened: Duration::from_secs(listened),
+            skipped,
+        }
+    }
+
+    fn filled() -> History {
+        let history = History::open_in_memory().unwrap();
+        for (id, artist, at, listened, skipped) in &[
+            ("a", "One", T0, 200, false),
+            ("b", "Two", T0 + 300, 20, true),
+            ("a", "One", T0 + 600, 150, false),
+            ("c", "Two", T0 + 900, 200, false),
+            ("c", "Two", T0 + 1200, 200, false),
+            ("d", "Three", T0 - 86_400, 200, false),
+        ] {
+            history
+                .record(&play(id, artist, *at, *listened, *skipped), None)
+                .unwrap();
+        }
+        history
+    }
+
+    #[test]
+    fn migrates_old_databases() {
+        let dir = TempDir::new();
+        let path = dir.join("history.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.pragma_update(None, "user_version", 1).unwrap();
+            conn.execute(
+                "INSERT INTO plays (track_id, started_at, listened_ms, skipped) VALUES ('x', 5, 1000, 0)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let history = History::open(&path).unwrap();
+        assert_eq!(history.schema_version().unwrap(), MIGRATIONS.len());
+        let old = history.get(1).unwrap().unwrap();
+        assert_eq!(old.track_id.as_deref(), Some("x"));
+        assert_eq!(old.context, None);
+        drop(history);
+
+        // opening again changes nothing
+        assert_eq!(
+            History::open(&path).unwrap().schema_version().unwrap(),
+            MIGRATIONS.len()
+        );
+
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(History::open(&path).is_err());
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let history = History::open_in_memory().unwrap();
+        let id = history
+            .record(&play("a", "One", T0, 120, true), Some("spotify:album:x"))
+            .unwrap();
+
+        let entry = history.get(id).unwrap().unwrap();
+        assert_eq!(entry.track_id.as_deref(), Some("spotify:track:a"));
+        assert_eq!(entry.started_at, from_unix(T0));
+        assert_eq!(entry.listened, Duration::from_secs(120));
+        assert_eq!(entry.duration, Some(Duration::from_secs(200)));
+        assert!(entry.skipped);
+        assert_eq!(entry.context.as_deref(), Some("spotify:album:x"));
+        assert_eq!(history.get(id + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn queries() {
+        let history = filled();
+        let (from, to) = (from_unix(T0), from_unix(T0 + 1200));
+
+        let recent: Vec<i64> = history.recent(2).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(recent, vec![5, 4]);
+
+        let between: Vec<i64> = history
+            .between(from, to)
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(between, vec![1, 2, 3, 4]);
+
+        let artists = history.top_artists(from, from_unix(T0 + 1201), 10).unwrap();
+        let artists: Vec<(&str, u64)> = artists
+            .iter()
+            .map(|a| (a.artist.as_str(), a.plays))
+            .collect();
+        // ties go to whoever was listened to longer
+        assert_eq!(artists, vec![("Two", 2), ("One", 2)]);
+
+        let tracks = history.top_tracks(from, from_unix(T0 + 1201), 1).unwrap();
+        assert_eq!(tracks[0].track_id, "spotify:track:c");
+        assert_eq!(tracks[0].listened, Duration::from_secs(400));
+
+        let known = history.known_tracks(from_unix(T0 + 1)).unwrap();
+        let mut known: Vec<String> = known.into_iter().collect();
+        known.sort();
+        assert_eq!(known, vec!["spotify:track:a", "spotify:track:d"]);
+
+        assert_eq!(history.skip_rate(from, to).unwrap(), Some(0.25));
+        assert_eq!(history.skip_rate(from_unix(0), from_unix(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn recorder_keeps_the_context_plays_started_in() {
+        let mut recorder = Recorder::new(History::open_in_memory().unwrap());
+        let snapshot = |uri: &str, position: f64| Snapshot {
+            state: Some(State::PLAYING),
+            position: Some(position),
+            track: Some(track(uri, 200_000)),
+            ..Default::default()
+        };
+
+        recorder.set_context(Some("spotify:playlist:x".to_string()));
+        let at = from_unix(T0);
+        assert!(recorder
+            .update(&snapshot("spotify:track:a", 0.0), at)
+            .unwrap()
+            .is_empty());
+        recorder.set_context(None);
+
+        let at = at + Duration::from_secs(60);
+        let recorded = recorder
+            .update(&snapshot("spotify:track:b", 0.0), at)
+            .unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].context.as_deref(), Some("spotify:playlist:x"));
+        assert!(recorded[0].skipped);
+
+        let last = recorder.finish().unwrap().unwrap();
+        assert_eq!(last.track_id.as_deref(), Some("spotify:track:b"));
+        assert_eq!(last.context, None);
+        assert_eq!(recorder.finish().unwrap(), None);
+    }
+}
@@ -1,12 +1,101 @@
 extern crate four_char_code;
 extern crate encoding;
 extern crate libc;
+extern crate unicode_segmentation;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+#[cfg(feature = "tiny_http")]
+extern crate tiny_http;
+#[cfg(feature = "tungstenite")]
+extern crate tungstenite;
+#[cfg(feature = "ureq")]
+extern crate ureq;
+#[cfg(feature = "md5")]
+extern crate md5;
+#[cfg(feature = "rusqlite")]
+extern crate rusqlite;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "mpris")]
+extern crate zbus;
 
+#[cfg(target_os = "macos")]
 #[macro_use]
 mod sys;
+#[cfg(target_os = "macos")]
 #[macro_use]
 mod events;
+#[cfg(all(feature = "tracing", any(target_os = "macos", test)))]
+mod redaction;
+#[cfg(target_os = "macos")]
 mod spotify;
+mod state;
+mod snapshot;
+mod player;
+mod calendar;
+pub mod format;
+pub mod uri;
+pub mod playlist;
+pub mod watch;
+pub mod plays;
+pub mod clock;
+pub mod fade;
+pub mod sleep;
+pub mod alarm;
+pub mod duck;
+pub mod retry;
+pub mod cache;
+pub mod scheduler;
+pub mod changes;
+pub mod bookmark;
+#[cfg(test)]
+mod testing;
+#[cfg(all(feature = "serde", feature = "serde_json"))]
+pub mod spool;
+#[cfg(feature = "mpris")]
+pub mod mpris;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+#[cfg(all(unix, feature = "rpc"))]
+pub mod rpc;
+#[cfg(feature = "scrobbler")]
+pub mod scrobble;
+#[cfg(feature = "listenbrainz")]
+pub mod listenbrainz;
+#[cfg(feature = "history")]
+pub mod history;
+#[cfg(feature = "report")]
+pub mod report;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "rules")]
+pub mod rules;
+#[cfg(feature = "blocklist")]
+pub mod blocklist;
+#[cfg(feature = "queue")]
+pub mod queue;
+#[cfg(feature = "party")]
+pub mod party;
+#[cfg(feature = "recording")]
+pub mod recording;
 
-pub use events::EventBuildError;
-pub use spotify::{Spotify, SpotifyTrack, State};
+#[cfg(target_os = "macos")]
+pub use events::{
+    print_desc, print_params, set_transport, AEDesc, AppleEvents, EventBuildError, ResType, Transport,
+};
+#[cfg(all(target_os = "macos", feature = "tracing"))]
+pub use redaction::{set_redaction, Redaction};
+pub use player::Player;
+pub use snapshot::{Snapshot, TrackInfo};
+#[cfg(target_os = "macos")]
+pub use spotify::{Spotify, SpotifyTrack};
+pub use state::State;
+pub use uri::SpotifyUri;
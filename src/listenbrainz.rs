@@ -0,0 +1,338 @@
+use crate::plays::Play;
+use crate::scrobble::{Scrobble, ScrobbleEndpoint};
+use crate::snapshot::TrackInfo;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, UNIX_EPOCH};
+
+// listens per import request, well below the server side payload limit
+const BATCH: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenType {
+    PlayingNow,
+    Single,
+    Import,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AdditionalInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_artist_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracknumber: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discnumber: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spotify_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub music_service: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submission_client: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submission_client_version: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackMetadata {
+    pub artist_name: String,
+    pub track_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_name: Option<String>,
+    pub additional_info: AdditionalInfo,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Listen {
+    // unix time, absent for playing_now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listened_at: Option<u64>,
+    pub track_metadata: TrackMetadata,
+}
+
+fn additional_info(origin_url: Option<String>) -> AdditionalInfo {
+    AdditionalInfo {
+        spotify_id: origin_url.clone(),
+        origin_url,
+        music_service: Some("spotify.com".to_string()),
+        submission_client: Some(env!("CARGO_PKG_NAME").to_string()),
+        submission_client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        ..Default::default()
+    }
+}
+
+impl Listen {
+    pub fn from_track(track: &TrackInfo, listened_at: Option<u64>) -> Option<Listen> {
+        Some(Listen {
+            listened_at,
+            track_metadata: TrackMetadata {
+                artist_name: track.artist.clone().filter(|a| !a.is_empty())?,
+                track_name: track.name.clone().filter(|n| !n.is_empty())?,
+                release_name: track.album.clone().filter(|a| !a.is_empty()),
+                additional_info: AdditionalInfo {
+                    release_artist_name: track.album_artist.clone().filter(|a| !a.is_empty()),
+                    duration_ms: track.duration.filter(|ms| *ms > 0).map(|ms| ms as u64),
+                    tracknumber: track.track_number.filter(|n| *n > 0),
+                    discnumber: track.disk_number.filter(|n| *n > 0),
+                    ..additional_info(track.url())
+                },
+            },
+        })
+    }
+
+    pub fn from_play(play: &Play) -> Option<Listen> {
+        let listened_at = play
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Listen::from_track(&play.track, Some(listened_at))
+    }
+
+    pub fn from_scrobble(scrobble: &Scrobble) -> Listen {
+        Listen {
+            listened_at: Some(scrobble.timestamp),
+            track_metadata: TrackMetadata {
+                artist_name: scrobble.artist.clone(),
+                track_name: scrobble.track.clone(),
+                release_name: scrobble.album.clone(),
+                additional_info: AdditionalInfo {
+                    release_artist_name: scrobble.album_artist.clone(),
+                    duration_ms: scrobble.duration.map(|secs| secs * 1000),
+                    tracknumber: scrobble.track_number,
+                    ..additional_info(scrobble.origin_url.clone())
+                },
+            },
+        }
+    }
+}
+
+pub fn payload(listen_type: ListenType, listens: &[Listen]) -> Value {
+    let listens: Vec<Value> = listens
+        .iter()
+        .map(|listen| match listen_type {
+            ListenType::PlayingNow => json!({ "track_metadata": listen.track_metadata }),
+            _ => json!(listen),
+        })
+        .collect();
+
+    json!({ "listen_type": listen_type, "payload": listens })
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenBrainzError {
+    code: u16,
+    message: String,
+}
+
+impl ListenBrainzError {
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::error::Error for ListenBrainzError {}
+
+impl fmt::Display for ListenBrainzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ListenBrainz error {}: {}", self.code, self.message)
+    }
+}
+
+pub struct ListenBrainz {
+    token: String,
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl ListenBrainz {
+    pub const API_URL: &'static str = "https://api.listenbrainz.org";
+
+    pub fn new(token: &str) -> ListenBrainz {
+        ListenBrainz {
+            token: token.to_string(),
+            url: ListenBrainz::API_URL.to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(15))
+                .build(),
+        }
+    }
+
+    pub fn with_url(mut self, url: &str) -> ListenBrainz {
+        self.url = url.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn playing_now(&self, listen: &Listen) -> Result<()> {
+        self.submit(ListenType::PlayingNow, std::slice::from_ref(listen))
+    }
+
+    pub fn single(&self, listen: &Listen) -> Result<()> {
+        self.submit(ListenType::Single, std::slice::from_ref(listen))
+    }
+
+    pub fn import(&self, listens: &[Listen]) -> Result<()> {
+        for chunk in listens.chunks(BATCH) {
+            self.submit(ListenType::Import, chunk)?;
+        }
+        Ok(())
+    }
+
+    pub fn submit(&self, listen_type: ListenType, listens: &[Listen]) -> Result<()> {
+        if listens.is_empty() {
+            return Ok(());
+        }
+
+        if listen_type != ListenType::Import && listens.len() != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "playing_now and single take exactly one listen",
+            ));
+        }
+
+        let res = self
+            .agent
+            .post(&format!("{}/1/submit-listens", self.url))
+            .set("Authorization", &format!("Token {}", self.token))
+            .set("Content-Type", "application/json")
+            .send_string(&payload(listen_type, listens).to_string());
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                let message = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str::<Value>(&body).ok())
+                    .and_then(|body| {
+                        body.get("error")
+                            .and_then(Value::as_str)
+                            .map(str::to_string)
+                    })
+                    .unwrap_or_default();
+
+                // retrying only helps when rate limited or the server is down
+                let kind = match code {
+                    401 | 403 => ErrorKind::PermissionDenied,
+                    429 => ErrorKind::Other,
+                    400..=499 => ErrorKind::InvalidInput,
+                    _ => ErrorKind::Other,
+                };
+
+                Err(Error::new(kind, ListenBrainzError { code, message }))
+            }
+            Err(err) => Err(Error::other(err)),
+        }
+    }
+}
+
+impl ScrobbleEndpoint for ListenBrainz {
+    fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        self.playing_now(&Listen::from_scrobble(scrobble))
+    }
+
+    fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()> {
+        let listens: Vec<Listen> = scrobbles.iter().map(Listen::from_scrobble).collect();
+
+        match listens.as_slice() {
+            [listen] => self.single(listen),
+            listens => self.import(listens),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{http_stub, track, TRACK};
+
+    fn listen() -> Listen {
+        Listen::from_track(&track(TRACK, 200_000), Some(1_700_000_000)).unwrap()
+    }
+
+    #[test]
+    fn payloads() {
+        let listen = listen();
+        let info = &listen.track_metadata.additional_info;
+        assert_eq!(info.duration_ms, Some(200_000));
+        assert_eq!(
+            info.origin_url.as_deref(),
+            Some("https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6")
+        );
+
+        let now = payload(ListenType::PlayingNow, std::slice::from_ref(&listen));
+        assert_eq!(now["listen_type"], "playing_now");
+        assert!(now["payload"][0].get("listened_at").is_none());
+
+        let single = payload(ListenType::Single, &[listen]);
+        assert_eq!(single["payload"][0]["listened_at"], 1_700_000_000);
+        assert_eq!(
+            single["payload"][0]["track_metadata"]["artist_name"],
+            "Band"
+        );
+    }
+
+    #[test]
+    fn requests_and_errors() {
+        let (url, requests) = http_stub(vec![
+            (200, r#"{"status":"ok"}"#),
+            (400, r#"{"code":400,"error":"Bad listen"}"#),
+            (401, r#"{"code":401,"error":"Invalid token"}"#),
+            (413, r#"{"code":413,"error":"Too large"}"#),
+            (429, r#"{"code":429,"error":"Slow down"}"#),
+            (500, r#"{"code":500,"error":"Oops"}"#),
+        ]);
+        let client = ListenBrainz::new("token").with_url(&format!("{}/", url));
+
+        client.single(&listen()).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(
+            request.starts_with("POST /1/submit-listens "),
+            "{}",
+            request
+        );
+        assert!(request.contains("Token token"), "{}", request);
+        assert!(
+            request.contains("\"listen_type\":\"single\""),
+            "{}",
+            request
+        );
+
+        let errors: Vec<Error> = (0..5)
+            .map(|_| client.single(&listen()).unwrap_err())
+            .collect();
+        let kinds: Vec<ErrorKind> = errors.iter().map(Error::kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ErrorKind::InvalidInput,
+                ErrorKind::PermissionDenied,
+                ErrorKind::InvalidInput,
+                ErrorKind::Other,
+                ErrorKind::Other
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "ListenBrainz error 400: Bad listen");
+    }
+
+    #[test]
+    fn submit_checks_the_listen_count() {
+        let client = ListenBrainz::new("token").with_url("http://127.0.0.1:1");
+        let err = client
+            .submit(ListenType::Single, &[listen(), listen()])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        client.import(&[]).unwrap();
+    }
+}
@@ -0,0 +1,517 @@
+use crate::player::Player;
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::state::State;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Error, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Response};
+
+// seconds, Apple Events to a running Spotify take a few milliseconds, a
+// cold launch or a busy app can take seconds
+pub const BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // not cumulative, one slot per bucket plus +Inf
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; BUCKETS.len() + 1];
+        }
+
+        let idx = BUCKETS
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.counts[idx] += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    latency: BTreeMap<&'static str, Histogram>,
+    errors: BTreeMap<(&'static str, String), u64>,
+    track_changes: u64,
+    track_key: Option<String>,
+    snapshot: Option<Snapshot>,
+    up: bool,
+}
+
+fn status(err: &Error) -> String {
+    match err.raw_os_error() {
+        Some(code) => code.to_string(),
+        None => format!("{:?}", err.kind()),
+    }
+}
+
+fn track_key(track: &TrackInfo) -> Option<String> {
+    track
+        .spotify_url
+        .clone()
+        .or_else(|| track.id.clone())
+        .or_else(|| track.name.clone())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Default::default()
+    }
+
+    pub fn observe_call<T>(&self, method: &'static str, elapsed: Duration, res: &Result<T>) {
+        let mut inner = lock(&self.inner);
+        inner
+            .latency
+            .entry(method)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+
+        if let Err(err) = res {
+            *inner.errors.entry((method, status(err))).or_insert(0) += 1;
+        }
+    }
+
+    pub fn observe_snapshot(&self, snapshot: &Snapshot) {
+        let mut inner = lock(&self.inner);
+        let key = snapshot.track.as_ref().and_then(track_key);
+
+        if key.is_some() && inner.track_key.is_some() && key != inner.track_key {
+            inner.track_changes += 1;
+        }
+        if key.is_some() {
+            inner.track_key = key;
+        }
+
+        inner.snapshot = Some(snapshot.clone());
+        inner.up = true;
+    }
+
+    pub fn observe_failure(&self) {
+        lock(&self.inner).up = false;
+    }
+
+    pub fn track_changes(&self) -> u64 {
+        lock(&self.inner).track_changes
+    }
+
+    pub fn render(&self) -> String {
+        let inner = lock(&self.inner);
+        let mut out = String::new();
+
+        out.push_str("# HELP spotify_up Whether the last poll of Spotify succeeded.\n");
+        out.push_str("# TYPE spotify_up gauge\n");
+        let _ = writeln!(out, "spotify_up {}", inner.up as u8);
+
+        let snapshot = inner.snapshot.as_ref().filter(|_| inner.up);
+
+        out.push_str("# HELP spotify_player_state Current player state.\n");
+        out.push_str("# TYPE spotify_player_state gauge\n");
+        for state in &[State::STOPPED, State::PLAYING, State::PAUSED] {
+            let current = snapshot.is_some_and(|s| s.state == Some(*state));
+            let _ = writeln!(
+                out,
+                "spotify_player_state{{state=\"{}\"}} {}",
+                state, current as u8
+            );
+        }
+
+        if let Some(snapshot) = snapshot {
+            let gauges: &[(&str, &str, Option<f64>)] = &[
+                (
+                    "spotify_volume_percent",
+                    "Sound volume, 0 to 100.",
+                    snapshot.volume.map(f64::from),
+                ),
+                (
+                    "spotify_position_seconds",
+                    "Position in the current track.",
+                    snapshot.position,
+                ),
+                (
+                    "spotify_track_duration_seconds",
+                    "Length of the current track.",
+                    snapshot.duration(),
+                ),
+                (
+                    "spotify_shuffle_enabled",
+                    "Whether shuffle is on.",
+                    snapshot.shuffling.map(|b| b as u8 as f64),
+                ),
+                (
+                    "spotify_repeat_enabled",
+                    "Whether repeat is on.",
+                    snapshot.repeating.map(|b| b as u8 as f64),
+                ),
+            ];
+
+            for (name, help, value) in gauges {
+                if let Some(value) = value {
+                    let _ = writeln!(out, "# HELP {} {}", name, help);
+                    let _ = writeln!(out, "# TYPE {} gauge", name);
+                    let _ = writeln!(out, "{} {}", name, value);
+                }
+            }
+        }
+
+        out.push_str("# HELP spotify_track_changes_total Number of times the track changed.\n");
+        out.push_str("# TYPE spotify_track_changes_total counter\n");
+        let _ = writeln!(out, "spotify_track_changes_total {}", inner.track_changes);
+
+        out.push_str("# HELP spotify_player_call_errors_total Failed player calls by OSStatus.\n");
+        out.push_str("# TYPE spotify_player_call_errors_total counter\n");
+        for ((method, status), count) in &inner.errors {
+            let _ = writeln!(
+                out,
+                "spotify_player_call_errors_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        // a call is one or more Apple Event round-trips, a snapshot is several
+        out.push_str(
+            "# HELP spotify_player_call_duration_seconds Time taken by each player call.\n",
+        );
+        out.push_str("# TYPE spotify_player_call_duration_seconds histogram\n");
+        for (method, histogram) in &inner.latency {
+            let mut total = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                total += count;
+                let le = BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+                let _ = writeln!(
+                    out,
+                    "spotify_player_call_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    method, le, total
+                );
+            }
+            let _ = writeln!(
+                out,
+                "spotify_player_call_duration_seconds_sum{{method=\"{}\"}} {}",
+                method, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "spotify_player_call_duration_seconds_count{{method=\"{}\"}} {}",
+                method, total
+            );
+        }
+
+        out
+    }
+}
+
+// Times every call into the wrapped player, each of which is one or more
+// Apple Event round-trips to Spotify.
+pub struct Instrumented<P> {
+    inner: P,
+    metrics: Arc<Metrics>,
+}
+
+impl<P: Player> Instrumented<P> {
+    pub fn new(inner: P, metrics: Arc<Metrics>) -> Instrumented<P> {
+        Instrumented { inner, metrics }
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn measure<T, F: FnOnce(&P) -> Result<T>>(&self, method: &'static str, f: F) -> Result<T> {
+        let started = Instant::now();
+        let res = f(&self.inner);
+        self.metrics.observe_call(method, started.elapsed(), &res);
+        res
+    }
+}
+
+impl<P: Player> Player for Instrumented<P> {
+    fn state(&self) -> Result<Option<State>> {
+        self.measure("state", |p| p.state())
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        self.measure("is_shuffling", |p| p.is_shuffling())
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        self.measure("set_shuffling", |p| p.set_shuffling(is_it))
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        self.measure("is_repeating", |p| p.is_repeating())
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        self.measure("set_repeating", |p| p.set_repeating(is_it))
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        self.measure("position", |p| p.position())
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        self.measure("set_position", |p| p.set_position(pos))
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        self.measure("volume", |p| p.volume())
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        self.measure("set_volume", |p| p.set_volume(vol))
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        self.measure("track", |p| p.track())
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        self.measure("play_pause", |p| p.play_pause())
+    }
+
+    fn play(&self) -> Result<()> {
+        self.measure("play", |p| p.play())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.measure("pause", |p| p.pause())
+    }
+
+    fn next(&self) -> Result<()> {
+        self.measure("next", |p| p.next())
+    }
+
+    fn previous(&self) -> Result<()> {
+        self.measure("previous", |p| p.previous())
+    }
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        self.measure("play_track", |p| p.play_track(track, context))
+    }
+}
+
+pub struct Exporter<P> {
+    http: Arc<tiny_http::Server>,
+    player: Arc<Mutex<Instrumented<P>>>,
+    metrics: Arc<Metrics>,
+    poll_interval: Duration,
+    stopped: Arc<AtomicBool>,
+}
+
+impl<P: Player + Send + 'static> Exporter<P> {
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        player: P,
+        poll_interval: Duration,
+    ) -> Result<Exporter<P>> {
+        let http = tiny_http::Server::http(addr).map_err(|err| Error::other(err.to_string()))?;
+
+        let metrics = Arc::new(Metrics::new());
+
+        Ok(Exporter {
+            http: Arc::new(http),
+            player: Arc::new(Mutex::new(Instrumented::new(player, metrics.clone()))),
+            metrics,
+            poll_interval,
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.http.unblock();
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let poller = self.spawn_poller();
+
+        for request in self.http.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => Response::from_string(self.metrics().render())
+                    .with_header(
+                        Header::from_bytes(
+                            &b"Content-Type"[..],
+                            &b"text/plain; version=0.0.4; charset=utf-8"[..],
+                        )
+                        .expect("valid header"),
+                    ),
+                (_, "/metrics") => {
+                    Response::from_string("Method not allowed\n").with_status_code(405)
+                }
+                _ => Response::from_string("Not found\n").with_status_code(404),
+            };
+
+            // a scraper hanging up is not our problem
+            let _ = request.respond(response);
+        }
+
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = poller.join();
+        Ok(())
+    }
+
+    fn spawn_poller(&self) -> thread::JoinHandle<()> {
+        let player = self.player.clone();
+        let metrics = self.metrics.clone();
+        let interval = self.poll_interval;
+        let stopped = self.stopped.clone();
+
+        thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                match player.snapshot() {
+                    Ok(snapshot) => metrics.observe_snapshot(&snapshot),
+                    Err(_) => metrics.observe_failure(),
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakePlayer;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn lines(out: &str, prefix: &str) -> Vec<String> {
+        out.lines()
+            .filter(|line| line.starts_with(prefix))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn calls_are_timed_and_failures_counted() {
+        let metrics = Arc::new(Metrics::new());
+        let fake = FakePlayer::new();
+        fake.fail("volume", &[-1728]);
+        let player = Instrumented::new(&fake, metrics.clone());
+
+        assert!(player.volume().is_err());
+        assert_eq!(player.volume().unwrap(), Some(50));
+        metrics.observe_call::<()>("next", Duration::from_millis(30), &Ok(()));
+
+        let out = metrics.render();
+        assert_eq!(
+            lines(&out, "spotify_player_call_errors_total{"),
+            vec!["spotify_player_call_errors_total{method=\"volume\",status=\"-1728\"} 1"]
+        );
+        assert_eq!(
+            lines(&out, "spotify_player_call_duration_seconds_count"),
+            vec![
+                "spotify_player_call_duration_seconds_count{method=\"next\"} 1",
+                "spotify_player_call_duration_seconds_count{method=\"volume\"} 2",
+            ]
+        );
+        // buckets are cumulative
+        let next = lines(
+            &out,
+            "spotify_player_call_duration_seconds_bucket{method=\"next\"",
+        );
+        assert_eq!(next.len(), BUCKETS.len() + 1);
+        assert!(next[4].ends_with("le=\"0.025\"} 0"), "{}", next[4]);
+        assert!(next[5].ends_with("le=\"0.05\"} 1"), "{}", next[5]);
+        assert!(next[BUCKETS.len()].ends_with("le=\"+Inf\"} 1"));
+    }
+
+    #[test]
+    fn snapshots_become_gauges() {
+        let metrics = Metrics::new();
+        let fake = FakePlayer::new();
+
+        assert!(metrics.render().contains("spotify_up 0\n"));
+
+        metrics.observe_snapshot(&fake.get());
+        fake.next().unwrap();
+        metrics.observe_snapshot(&fake.get());
+        metrics.observe_snapshot(&fake.get());
+        assert_eq!(metrics.track_changes(), 1);
+
+        let out = metrics.render();
+        assert!(out.contains("spotify_up 1\n"));
+        assert!(out.contains("spotify_player_state{state=\"playing\"} 1\n"));
+        assert!(out.contains("spotify_player_state{state=\"paused\"} 0\n"));
+        assert!(out.contains("spotify_volume_percent 50\n"));
+        assert!(out.contains("spotify_track_duration_seconds 180\n"));
+        assert!(out.contains("spotify_track_changes_total 1\n"));
+        assert!(!out.contains("spotify_track_info"));
+
+        metrics.observe_failure();
+        let out = metrics.render();
+        assert!(out.contains("spotify_up 0\n"));
+        assert!(!out.contains("spotify_volume_percent"));
+    }
+
+    fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(stream, "{} HTTP/1.0\r\n\r\n", request).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        res
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let fake = Arc::new(FakePlayer::new());
+        let exporter = Arc::new(
+            Exporter::bind("127.0.0.1:0", fake.clone(), Duration::from_millis(5)).unwrap(),
+        );
+        let running = exporter.clone();
+        let server = thread::spawn(move || running.run());
+        let addr = exporter.local_addr().unwrap();
+
+        while fake.count("state") == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let res = get(addr, "GET /metrics");
+        assert!(res.starts_with("HTTP/1.0 200"), "{}", res);
+        assert!(res.contains("text/plain; version=0.0.4"));
+        assert!(res.contains("spotify_player_call_duration_seconds_count{method=\"state\"}"));
+
+        assert!(get(addr, "POST /metrics").starts_with("HTTP/1.0 405"));
+        assert!(get(addr, "GET /").starts_with("HTTP/1.0 404"));
+
+        exporter.shutdown();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,608 @@
+use crate::player::Player;
+use crate::snapshot::TrackInfo;
+use crate::state::State;
+use crate::watch::{PlayerEvent, Watcher};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Error, Result};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::Connection;
+use zbus::fdo;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+
+pub const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+fn io_error(err: zbus::Error) -> Error {
+    Error::other(err)
+}
+
+fn failed(err: Error) -> fdo::Error {
+    fdo::Error::Failed(err.to_string())
+}
+
+fn micros(seconds: f64) -> i64 {
+    (seconds * 1_000_000.0) as i64
+}
+
+fn seconds(micros: i64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+fn playback_status(state: Option<State>) -> &'static str {
+    match state {
+        Some(State::PLAYING) => "Playing",
+        Some(State::PAUSED) => "Paused",
+        Some(State::STOPPED) | None => "Stopped",
+    }
+}
+
+fn loop_status(repeating: Option<bool>) -> &'static str {
+    if repeating.unwrap_or(false) {
+        "Playlist"
+    } else {
+        "None"
+    }
+}
+
+fn track_id(track: Option<&TrackInfo>) -> String {
+    let id = track
+        .and_then(|t| t.spotify_url.as_deref().or(t.id.as_deref()))
+        .map(|id| {
+            id.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect::<String>()
+        });
+
+    match id {
+        Some(id) if !id.is_empty() => format!("{}/Track/{}", OBJECT_PATH, id),
+        _ => NO_TRACK.to_string(),
+    }
+}
+
+fn owned<'a, T: Into<Value<'a>>>(value: T) -> OwnedValue {
+    // only plain values and arrays end up in metadata, they never carry fds
+    OwnedValue::try_from(value.into()).expect("metadata value without file descriptors")
+}
+
+fn metadata(track: Option<&TrackInfo>) -> HashMap<String, OwnedValue> {
+    let mut res = HashMap::new();
+    let path = track_id(track);
+
+    if let Ok(path) = ObjectPath::try_from(path) {
+        res.insert("mpris:trackid".to_string(), owned(path));
+    }
+
+    let track = match track {
+        Some(track) => track,
+        None => return res,
+    };
+
+    if let Some(duration) = track.duration {
+        res.insert("mpris:length".to_string(), owned(i64::from(duration) * 1000));
+    }
+    if let Some(url) = &track.artwork_url {
+        res.insert("mpris:artUrl".to_string(), owned(url.as_str()));
+    }
+    if let Some(name) = &track.name {
+        res.insert("xesam:title".to_string(), owned(name.as_str()));
+    }
+    if let Some(artist) = &track.artist {
+        res.insert("xesam:artist".to_string(), owned(vec![artist.as_str()]));
+    }
+    if let Some(album) = &track.album {
+        res.insert("xesam:album".to_string(), owned(album.as_str()));
+    }
+    if let Some(album_artist) = &track.album_artist {
+        res.insert(
+            "xesam:albumArtist".to_string(),
+            owned(vec![album_artist.as_str()]),
+        );
+    }
+    if let Some(number) = track.track_number {
+        res.insert("xesam:trackNumber".to_string(), owned(number));
+    }
+    if let Some(number) = track.disk_number {
+        res.insert("xesam:discNumber".to_string(), owned(number));
+    }
+    if let Some(count) = track.played_count {
+        res.insert("xesam:useCount".to_string(), owned(count));
+    }
+    if let Some(popularity) = track.popularity {
+        res.insert(
+            "xesam:autoRating".to_string(),
+            owned(f64::from(popularity) / 100.0),
+        );
+    }
+    if let Some(url) = track.url() {
+        res.insert("xesam:url".to_string(), owned(url));
+    }
+
+    res
+}
+
+struct Root {
+    identity: String,
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        self.identity.clone()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["spotify".to_string()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct MprisPlayer<P> {
+    player: Arc<Mutex<P>>,
+}
+
+impl<P: Player> MprisPlayer<P> {
+    fn player(&self) -> &Mutex<P> {
+        &self.player
+    }
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl<P: Player + Send + 'static> MprisPlayer<P> {
+    fn next(&self) -> fdo::Result<()> {
+        self.player().next().map_err(failed)
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        self.player().previous().map_err(failed)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.player().pause().map_err(failed)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        self.player().play_pause().map_err(failed)
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        self.player().pause().map_err(failed)
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        self.player().play().map_err(failed)
+    }
+
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let player = self.player();
+        let position = player.position().map_err(failed)?.unwrap_or(0.0) + seconds(offset);
+        let length = player
+            .track()
+            .map_err(failed)?
+            .and_then(|t| t.duration)
+            .map(|ms| f64::from(ms) / 1000.0);
+
+        // the spec says seeking past the end skips to the next track
+        if length.is_some_and(|length| position > length) {
+            return player.next().map_err(failed);
+        }
+        player.set_position(position.max(0.0)).map_err(failed)
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let track = self.player().track().map_err(failed)?;
+        let length = track.as_ref().and_then(|t| t.duration).map(i64::from);
+
+        // the spec says to ignore positions outside the track
+        if self::track_id(track.as_ref()) != track_id.as_str()
+            || position < 0
+            || length.is_some_and(|ms| position > ms * 1000)
+        {
+            return Ok(());
+        }
+
+        self.player()
+            .set_position(seconds(position))
+            .map_err(failed)
+    }
+
+    fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        self.player().play_track(uri, None).map_err(failed)
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> fdo::Result<String> {
+        let state = self.player().state().map_err(failed)?;
+        Ok(playback_status(state).to_string())
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> fdo::Result<String> {
+        let repeating = self.player().is_repeating().map_err(failed)?;
+        Ok(loop_status(repeating).to_string())
+    }
+
+    #[zbus(property)]
+    fn set_loop_status(&self, value: String) -> zbus::Result<()> {
+        self.player()
+            .set_repeating(value != "None")
+            .map_err(|err| fdo::Error::Failed(err.to_string()).into())
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&self, _value: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> fdo::Result<bool> {
+        Ok(self
+            .player()
+            .is_shuffling()
+            .map_err(failed)?
+            .unwrap_or(false))
+    }
+
+    #[zbus(property)]
+    fn set_shuffle(&self, value: bool) -> zbus::Result<()> {
+        self.player()
+            .set_shuffling(value)
+            .map_err(|err| fdo::Error::Failed(err.to_string()).into())
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let track = self.player().track().map_err(failed)?;
+        Ok(metadata(track.as_ref()))
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> fdo::Result<f64> {
+        let volume = self.player().volume().map_err(failed)?.unwrap_or(0);
+        Ok(f64::from(volume) / 100.0)
+    }
+
+    #[zbus(property)]
+    fn set_volume(&self, value: f64) -> zbus::Result<()> {
+        let volume = (value.clamp(0.0, 1.0) * 100.0).round() as i32;
+        self.player()
+            .set_volume(volume)
+            .map_err(|err| fdo::Error::Failed(err.to_string()).into())
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> fdo::Result<i64> {
+        let position = self.player().position().map_err(failed)?.unwrap_or(0.0);
+        Ok(micros(position))
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+pub struct MprisServer<P> {
+    connection: Connection,
+    watcher: Watcher<Arc<Mutex<P>>>,
+}
+
+impl<P: Player + Send + 'static> MprisServer<P> {
+    pub fn new(player: P, identity: &str) -> Result<MprisServer<P>> {
+        MprisServer::with_builder(Builder::session().map_err(io_error)?, player, identity)
+    }
+
+    pub fn with_address(player: P, identity: &str, address: &str) -> Result<MprisServer<P>> {
+        MprisServer::with_builder(Builder::address(address).map_err(io_error)?, player, identity)
+    }
+
+    fn with_builder(builder: Builder, player: P, identity: &str) -> Result<MprisServer<P>> {
+        let player = Arc::new(Mutex::new(player));
+        let name = identity
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+
+        let connection = builder
+            .name(format!("{}.{}", ROOT_INTERFACE, name))
+            .map_err(io_error)?
+            .serve_at(
+                OBJECT_PATH,
+                Root {
+                    identity: identity.to_string(),
+                },
+            )
+            .map_err(io_error)?
+            .serve_at(
+                OBJECT_PATH,
+                MprisPlayer {
+                    player: player.clone(),
+                },
+            )
+            .map_err(io_error)?
+            .build()
+            .map_err(io_error)?;
+
+        Ok(MprisServer {
+            connection,
+            watcher: Watcher::new(player),
+        })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn poll(&mut self) -> Result<()> {
+        let events = self.watcher.poll()?;
+        self.notify(&events)
+    }
+
+    pub fn run(&mut self, interval: Duration) -> Result<()> {
+        loop {
+            self.poll()?;
+            thread::sleep(interval);
+        }
+    }
+
+    fn notify(&self, events: &[PlayerEvent]) -> Result<()> {
+        let mut changed: HashMap<&str, Value> = HashMap::new();
+
+        for event in events {
+            match event {
+                PlayerEvent::StateChanged(state) => {
+                    changed.insert("PlaybackStatus", Value::from(playback_status(*state)));
+                }
+                PlayerEvent::TrackChanged(track) => {
+                    changed.insert("Metadata", Value::from(metadata(track.as_ref())));
+                }
+                PlayerEvent::VolumeChanged(volume) => {
+                    let volume = f64::from(volume.unwrap_or(0)) / 100.0;
+                    changed.insert("Volume", Value::from(volume));
+                }
+                PlayerEvent::ShufflingChanged(shuffling) => {
+                    changed.insert("Shuffle", Value::from(shuffling.unwrap_or(false)));
+                }
+                PlayerEvent::RepeatingChanged(repeating) => {
+                    changed.insert("LoopStatus", Value::from(loop_status(*repeating)));
+                }
+                PlayerEvent::Seeked(position) => {
+                    self.connection
+                        .emit_signal(
+                            None::<&str>,
+                            OBJECT_PATH,
+                            PLAYER_INTERFACE,
+                            "Seeked",
+                            &micros(*position),
+                        )
+                        .map_err(io_error)?;
+                }
+                PlayerEvent::PositionChanged(_) => {}
+            }
+        }
+
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        self.connection
+            .emit_signal(
+                None::<&str>,
+                OBJECT_PATH,
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &(PLAYER_INTERFACE, changed, Vec::<&str>::new()),
+            )
+            .map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakePlayer;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use zbus::blocking::Proxy;
+
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    // a bus of our own, these tests need dbus-daemon installed
+    fn private_bus() -> Bus {
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed to run the MPRIS tests");
+        let mut bus = Bus {
+            daemon,
+            address: String::new(),
+        };
+        let stdout = bus.daemon.stdout.take().unwrap();
+        BufReader::new(stdout).read_line(&mut bus.address).unwrap();
+        bus.address = bus.address.trim().to_string();
+        bus
+    }
+
+    struct Setup {
+        _bus: Bus,
+        fake: Arc<FakePlayer>,
+        _server: MprisServer<Arc<FakePlayer>>,
+        client: Connection,
+    }
+
+    fn setup() -> Setup {
+        let bus = private_bus();
+        let fake = Arc::new(FakePlayer::new());
+        let server = MprisServer::with_address(fake.clone(), "Fake Player", &bus.address).unwrap();
+        let client = Builder::address(bus.address.as_str())
+            .unwrap()
+            .build()
+            .unwrap();
+        Setup {
+            _bus: bus,
+            fake,
+            _server: server,
+            client,
+        }
+    }
+
+    fn proxy(client: &Connection) -> Proxy<'_> {
+        Proxy::new(
+            client,
+            "org.mpris.MediaPlayer2.fakeplayer",
+            OBJECT_PATH,
+            PLAYER_INTERFACE,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn seek_moves_and_skips_past_the_end() {
+        let Setup {
+            _bus,
+            fake,
+            _server,
+            client,
+        } = setup();
+        let proxy = proxy(&client);
+
+        proxy.call::<_, _, ()>("Seek", &(5_000_000i64)).unwrap();
+        assert_eq!(fake.get().position, Some(15.0));
+
+        proxy.call::<_, _, ()>("Seek", &(-60_000_000i64)).unwrap();
+        assert_eq!(fake.get().position, Some(0.0));
+
+        proxy.call::<_, _, ()>("Seek", &(500_000_000i64)).unwrap();
+        assert_eq!(fake.count("next"), 1);
+        assert_eq!(fake.count("set_position"), 2);
+    }
+
+    #[test]
+    fn properties_and_set_position() {
+        let Setup {
+            _bus,
+            fake,
+            _server,
+            client,
+        } = setup();
+        let proxy = proxy(&client);
+
+        assert_eq!(
+            proxy.get_property::<String>("PlaybackStatus").unwrap(),
+            "Playing"
+        );
+        assert_eq!(proxy.get_property::<f64>("Volume").unwrap(), 0.5);
+        assert_eq!(proxy.get_property::<i64>("Position").unwrap(), 10_000_000);
+
+        let metadata = proxy
+            .get_property::<HashMap<String, OwnedValue>>("Metadata")
+            .unwrap();
+        let id = ObjectPath::try_from(metadata["mpris:trackid"].clone()).unwrap();
+        assert_eq!(
+            String::try_from(metadata["xesam:url"].clone()).unwrap(),
+            "https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6"
+        );
+
+        // another track's id is ignored
+        let other = ObjectPath::try_from("/org/mpris/MediaPlayer2/Track/other").unwrap();
+        proxy
+            .call::<_, _, ()>("SetPosition", &(other, 42_000_000i64))
+            .unwrap();
+        assert_eq!(fake.get().position, Some(10.0));
+
+        proxy
+            .call::<_, _, ()>("SetPosition", &(id.clone(), 42_000_000i64))
+            .unwrap();
+        assert_eq!(fake.get().position, Some(42.0));
+
+        // and so is a position past the end
+        proxy
+            .call::<_, _, ()>("SetPosition", &(id, 201_000_000i64))
+            .unwrap();
+        assert_eq!(fake.get().position, Some(42.0));
+
+        proxy.set_property("Volume", 0.3).unwrap();
+        assert_eq!(fake.get().volume, Some(30));
+    }
+}
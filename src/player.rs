@@ -0,0 +1,217 @@
+use crate::snapshot::{Snapshot, TrackInfo};
+#[cfg(target_os = "macos")]
+use crate::spotify::Spotify;
+use crate::state::State;
+use std::io::Result;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub trait Player {
+    fn state(&self) -> Result<Option<State>>;
+
+    fn is_shuffling(&self) -> Result<Option<bool>>;
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()>;
+
+    fn is_repeating(&self) -> Result<Option<bool>>;
+
+    fn set_repeating(&self, is_it: bool) -> Result<()>;
+
+    fn position(&self) -> Result<Option<f64>>;
+
+    fn set_position(&self, pos: f64) -> Result<()>;
+
+    fn volume(&self) -> Result<Option<i32>>;
+
+    fn set_volume(&self, vol: i32) -> Result<()>;
+
+    fn track(&self) -> Result<Option<TrackInfo>>;
+
+    fn play_pause(&self) -> Result<()>;
+
+    fn play(&self) -> Result<()>;
+
+    fn pause(&self) -> Result<()>;
+
+    fn next(&self) -> Result<()>;
+
+    fn previous(&self) -> Result<()>;
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()>;
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            state: self.state()?,
+            position: self.position()?,
+            volume: self.volume()?,
+            shuffling: self.is_shuffling()?,
+            repeating: self.is_repeating()?,
+            track: self.track()?,
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Player for Spotify {
+    fn state(&self) -> Result<Option<State>> {
+        Spotify::state(self)
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        Spotify::is_shuffling(self)
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        Spotify::set_shuffling(self, is_it)
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        Spotify::is_repeating(self)
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        Spotify::set_repeating(self, is_it)
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        Spotify::position(self)
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        Spotify::set_position(self, pos)
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        Spotify::volume(self)
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        Spotify::set_volume(self, vol)
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        match Spotify::track(self)? {
+            Some(track) => Ok(Some(track.info()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        Spotify::play_pause(self)
+    }
+
+    fn play(&self) -> Result<()> {
+        Spotify::play(self)
+    }
+
+    fn pause(&self) -> Result<()> {
+        Spotify::pause(self)
+    }
+
+    fn next(&self) -> Result<()> {
+        Spotify::next(self)
+    }
+
+    fn previous(&self) -> Result<()> {
+        Spotify::previous(self)
+    }
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        Spotify::play_track(self, track, context)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Spotify::snapshot(self)
+    }
+}
+
+macro_rules! forward_player {
+    ( $this:ident => $inner:expr ) => {
+        fn state(&$this) -> Result<Option<State>> {
+            $inner.state()
+        }
+
+        fn is_shuffling(&$this) -> Result<Option<bool>> {
+            $inner.is_shuffling()
+        }
+
+        fn set_shuffling(&$this, is_it: bool) -> Result<()> {
+            $inner.set_shuffling(is_it)
+        }
+
+        fn is_repeating(&$this) -> Result<Option<bool>> {
+            $inner.is_repeating()
+        }
+
+        fn set_repeating(&$this, is_it: bool) -> Result<()> {
+            $inner.set_repeating(is_it)
+        }
+
+        fn position(&$this) -> Result<Option<f64>> {
+            $inner.position()
+        }
+
+        fn set_position(&$this, pos: f64) -> Result<()> {
+            $inner.set_position(pos)
+        }
+
+        fn volume(&$this) -> Result<Option<i32>> {
+            $inner.volume()
+        }
+
+        fn set_volume(&$this, vol: i32) -> Result<()> {
+            $inner.set_volume(vol)
+        }
+
+        fn track(&$this) -> Result<Option<TrackInfo>> {
+            $inner.track()
+        }
+
+        fn play_pause(&$this) -> Result<()> {
+            $inner.play_pause()
+        }
+
+        fn play(&$this) -> Result<()> {
+            $inner.play()
+        }
+
+        fn pause(&$this) -> Result<()> {
+            $inner.pause()
+        }
+
+        fn next(&$this) -> Result<()> {
+            $inner.next()
+        }
+
+        fn previous(&$this) -> Result<()> {
+            $inner.previous()
+        }
+
+        fn play_track(&$this, track: String, context: Option<String>) -> Result<()> {
+            $inner.play_track(track, context)
+        }
+
+        fn snapshot(&$this) -> Result<Snapshot> {
+            $inner.snapshot()
+        }
+    };
+}
+
+impl<P: Player + ?Sized> Player for &P {
+    forward_player!(self => (**self));
+}
+
+impl<P: Player + ?Sized> Player for Box<P> {
+    forward_player!(self => (**self));
+}
+
+impl<P: Player + ?Sized> Player for Arc<P> {
+    forward_player!(self => (**self));
+}
+
+fn lock<P: ?Sized>(mutex: &Mutex<P>) -> MutexGuard<'_, P> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl<P: Player + ?Sized> Player for Mutex<P> {
+    forward_player!(self => lock(self));
+}
@@ -0,0 +1,714 @@
+use crate::player::Player;
+use crate::snapshot::TrackInfo;
+use crate::uri::SpotifyUri;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    pub uri: SpotifyUri,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // milliseconds
+    pub duration: Option<u64>,
+}
+
+impl PlaylistEntry {
+    pub fn new(uri: SpotifyUri) -> PlaylistEntry {
+        PlaylistEntry {
+            uri,
+            title: None,
+            artist: None,
+            album: None,
+            duration: None,
+        }
+    }
+
+    // None for tracks without a Spotify URI, like local files.
+    pub fn from_track(track: &TrackInfo) -> Option<PlaylistEntry> {
+        Some(PlaylistEntry {
+            uri: track.uri()?,
+            title: track.name.clone(),
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            duration: track.duration.filter(|ms| *ms > 0).map(|ms| ms as u64),
+        })
+    }
+
+    pub fn play<P: Player>(&self, player: &P) -> Result<()> {
+        player.play_track(self.uri.to_string(), None)
+    }
+}
+
+// Gathers the tracks played over a session, to save as a playlist.
+#[derive(Debug, Clone, Default)]
+pub struct Collector {
+    entries: Vec<PlaylistEntry>,
+    // a track played again is only added again with repeats
+    repeats: bool,
+    last: Option<SpotifyUri>,
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Default::default()
+    }
+
+    pub fn with_repeats(mut self, repeats: bool) -> Collector {
+        self.repeats = repeats;
+        self
+    }
+
+    // Adds `track` unless it is still the one added last, or was added
+    // before and repeats are off. True when added.
+    pub fn observe(&mut self, track: &TrackInfo) -> bool {
+        let entry = match PlaylistEntry::from_track(track) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if self.last.as_ref() == Some(&entry.uri)
+            || (!self.repeats && self.entries.iter().any(|e| e.uri == entry.uri))
+        {
+            self.last = Some(entry.uri);
+            return false;
+        }
+
+        self.last = Some(entry.uri.clone());
+        self.entries.push(entry);
+        true
+    }
+
+    pub fn poll<P: Player>(&mut self, player: &P) -> Result<bool> {
+        Ok(match player.track()? {
+            Some(track) => self.observe(&track),
+            None => false,
+        })
+    }
+
+    pub fn entries(&self) -> &[PlaylistEntry] {
+        &self.entries
+    }
+
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        save(path, &self.entries)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    M3u,
+    Xspf,
+    Csv,
+}
+
+impl Format {
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Format> {
+        let path = path.as_ref();
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("No playlist extension on {}", path.display()),
+                )
+            })?
+            .parse()
+    }
+
+    pub fn parse(self, data: &str) -> Result<Vec<PlaylistEntry>> {
+        match self {
+            Format::M3u => parse_m3u(data),
+            Format::Xspf => parse_xspf(data),
+            Format::Csv => parse_csv(data),
+        }
+    }
+
+    pub fn write(self, entries: &[PlaylistEntry]) -> String {
+        match self {
+            Format::M3u => write_m3u(entries),
+            Format::Xspf => write_xspf(entries),
+            Format::Csv => write_csv(entries),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s.to_ascii_lowercase().as_str() {
+            "m3u" | "m3u8" => Ok(Format::M3u),
+            "xspf" => Ok(Format::Xspf),
+            "csv" => Ok(Format::Csv),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown playlist format {:?}", s),
+            )),
+        }
+    }
+}
+
+// Reads a playlist, telling the format from the extension.
+pub fn load<T: AsRef<Path>>(path: T) -> Result<Vec<PlaylistEntry>> {
+    let format = Format::from_path(&path)?;
+    format.parse(&fs::read_to_string(path)?)
+}
+
+pub fn save<T: AsRef<Path>>(path: T, entries: &[PlaylistEntry]) -> Result<()> {
+    let format = Format::from_path(&path)?;
+    fs::write(path, format.write(entries))
+}
+
+fn bad_line(line: usize, message: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
+
+fn uri(line: usize, s: &str) -> Result<SpotifyUri> {
+    s.parse()
+        .map_err(|err: Error| bad_line(line, err.to_string()))
+}
+
+fn seconds(ms: u64) -> String {
+    if ms % 1000 == 0 {
+        (ms / 1000).to_string()
+    } else {
+        format!("{:.3}", ms as f64 / 1000.0)
+    }
+}
+
+fn millis(s: &str) -> Option<u64> {
+    s.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| *secs >= 0.0)
+        .map(|secs| (secs * 1000.0).round() as u64)
+}
+
+// #EXTINF:215,Artist - Title, with #EXTART and #EXTALB for the artist and album
+// on their own.
+fn parse_m3u(data: &str) -> Result<Vec<PlaylistEntry>> {
+    let mut entries = Vec::new();
+    // what the directives said about the next location
+    let (mut name, mut artist, mut album, mut duration) = (None, None, None, None);
+
+    for (n, line) in data.lines().enumerate() {
+        // only the line break is trimmed here, "Artist - " is meaningful
+        let raw = line.trim_start_matches('\u{feff}').trim_end_matches('\r');
+        let line = raw.trim();
+
+        if let Some(info) = raw.strip_prefix("#EXTINF:") {
+            let (length, title) = match info.find(',') {
+                Some(idx) => (&info[..idx], &info[idx + 1..]),
+                None => (info, ""),
+            };
+            // attributes like tvg-id="…" may follow the length
+            duration = length.split_whitespace().next().and_then(millis);
+            name = Some(title.to_string());
+        } else if let Some(value) = raw.strip_prefix("#EXTART:") {
+            artist = Some(value.to_string());
+        } else if let Some(value) = raw.strip_prefix("#EXTALB:") {
+            album = Some(value.to_string());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let name = name.take().unwrap_or_default();
+            let (artist, title) = match artist.take() {
+                Some(artist) => {
+                    let title = name
+                        .strip_prefix(&format!("{} - ", artist))
+                        .unwrap_or(&name)
+                        .to_string();
+                    (Some(artist), title)
+                }
+                None => match name.find(" - ") {
+                    Some(idx) => (Some(name[..idx].to_string()), name[idx + 3..].to_string()),
+                    None => (None, name),
+                },
+            };
+
+            entries.push(PlaylistEntry {
+                uri: uri(n + 1, line)?,
+                title: Some(title).filter(|t| !t.is_empty()),
+                artist: artist.filter(|a| !a.is_empty()),
+                album: album.take(),
+                duration: duration.take(),
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+fn write_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    // directives end at the line break, so one inside a name becomes a space
+    let line = |s: &str| s.replace(['\r', '\n'], " ");
+
+    for entry in entries {
+        let duration = entry.duration.map_or("-1".to_string(), seconds);
+        let title = line(entry.title.as_deref().unwrap_or(""));
+
+        match &entry.artist {
+            Some(artist) => {
+                let artist = line(artist);
+                let _ = writeln!(out, "#EXTINF:{},{} - {}", duration, artist, title);
+                let _ = writeln!(out, "#EXTART:{}", artist);
+            }
+            None => {
+                let _ = writeln!(out, "#EXTINF:{},{}", duration, title);
+                // or reading it back would take "A - B" for artist A
+                if title.contains(" - ") {
+                    out.push_str("#EXTART:\n");
+                }
+            }
+        }
+        if let Some(album) = &entry.album {
+            let _ = writeln!(out, "#EXTALB:{}", line(album));
+        }
+        let _ = writeln!(out, "{}", entry.uri);
+    }
+
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn xml_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(idx) = rest.find('&') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+// Where the first <tag> in `xml` starts and where its content does.
+fn xml_open(xml: &str, tag: &str) -> Option<(usize, usize)> {
+    let open = format!("<{}", tag);
+
+    let mut from = 0;
+    loop {
+        let idx = from + xml[from..].find(&open)?;
+        let after = &xml[idx + open.len()..];
+        // <track> or <track attr=…>, not <trackList>
+        if after.starts_with('>') || after.starts_with(char::is_whitespace) {
+            return Some((idx, idx + open.len() + after.find('>')? + 1));
+        }
+        from = idx + open.len();
+    }
+}
+
+// Text of the first <tag>…</tag> in `xml`, without the markup.
+fn xml_text(xml: &str, tag: &str) -> Option<String> {
+    let (_, start) = xml_open(xml, tag)?;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+
+    let text = xml[start..end].trim();
+    let text = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|t| t.strip_suffix("]]>"))
+        .map(str::to_string)
+        .unwrap_or_else(|| xml_unescape(text));
+    Some(text).filter(|t| !t.is_empty())
+}
+
+fn parse_xspf(data: &str) -> Result<Vec<PlaylistEntry>> {
+    let mut entries = Vec::new();
+    let mut from = 0;
+    // the line `from` is on
+    let mut line = 1;
+
+    while let Some((tag, start)) = xml_open(&data[from..], "track") {
+        line += data[from..from + tag].matches('\n').count();
+        let at = line;
+        let body = &data[from + start..];
+        let end = body
+            .find("</track>")
+            .ok_or_else(|| bad_line(at, "unterminated <track>".to_string()))?;
+        let track = &body[..end];
+        line += data[from + tag..from + start + end].matches('\n').count();
+        from += start + end;
+
+        let location = xml_text(track, "location")
+            .or_else(|| xml_text(track, "identifier"))
+            .ok_or_else(|| bad_line(at, "track without a location".to_string()))?;
+        entries.push(PlaylistEntry {
+            uri: uri(at, &location)?,
+            title: xml_text(track, "title"),
+            artist: xml_text(track, "creator"),
+            album: xml_text(track, "album"),
+            duration: xml_text(track, "duration").and_then(|ms| ms.parse().ok()),
+        });
+    }
+
+    Ok(entries)
+}
+
+fn write_xspf(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+
+    for entry in entries {
+        out.push_str("    <track>\n");
+        let _ = writeln!(out, "      <location>{}</location>", entry.uri);
+        let fields = [
+            ("title", &entry.title),
+            ("creator", &entry.artist),
+            ("album", &entry.album),
+        ];
+        for (tag, value) in fields.iter() {
+            if let Some(value) = value {
+                let _ = writeln!(out, "      <{0}>{1}</{0}>", tag, xml_escape(value));
+            }
+        }
+        if let Some(ms) = entry.duration {
+            let _ = writeln!(out, "      <duration>{}</duration>", ms);
+        }
+        out.push_str("    </track>\n");
+    }
+
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Rows of fields with the line each row started on. Quoted fields may span
+// lines.
+fn csv_rows(data: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = data.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                if row.iter().any(|f| !f.is_empty()) {
+                    rows.push((row_line, std::mem::take(&mut row)));
+                }
+                line += 1;
+                row_line = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+
+    if quoted {
+        return Err(bad_line(row_line, "unterminated quote".to_string()));
+    }
+    row.push(field);
+    if row.iter().any(|f| !f.is_empty()) {
+        rows.push((row_line, row));
+    }
+
+    Ok(rows)
+}
+
+const CSV_HEADER: [&str; 5] = ["uri", "artist", "title", "album", "duration"];
+
+// A header row picks the columns, without one every row is uri,artist,title,
+// album,duration. Durations are in seconds.
+fn parse_csv(data: &str) -> Result<Vec<PlaylistEntry>> {
+    let mut rows = csv_rows(data)?.into_iter().peekable();
+
+    let columns: Vec<Option<usize>> = match rows.peek() {
+        Some((_, header)) if header[0].parse::<SpotifyUri>().is_err() => {
+            // "Track Name", "track_name" and "trackname" are all the same
+            let header: Vec<String> = header
+                .iter()
+                .map(|h| {
+                    h.chars()
+                        .filter(|c| c.is_alphanumeric())
+                        .collect::<String>()
+                })
+                .map(|h| h.to_lowercase())
+                .collect();
+            let find = |names: &[&str]| header.iter().position(|h| names.contains(&h.as_str()));
+            let columns = vec![
+                find(&["uri", "url", "link", "spotifyuri", "spotifyurl", "trackuri"]),
+                find(&["artist", "artists", "artistname", "artistnames", "creator"]),
+                find(&["title", "track", "name", "trackname"]),
+                find(&["album", "albumname"]),
+                find(&["duration", "length"]),
+            ];
+            if columns[0].is_none() {
+                return Err(bad_line(1, "no uri column".to_string()));
+            }
+            rows.next();
+            columns
+        }
+        _ => (0..CSV_HEADER.len()).map(Some).collect(),
+    };
+
+    let mut entries = Vec::new();
+    for (line, row) in rows {
+        let get = |column: usize| {
+            columns[column]
+                .and_then(|i| row.get(i))
+                .map(|f| f.trim())
+                .filter(|f| !f.is_empty())
+        };
+
+        entries.push(PlaylistEntry {
+            uri: uri(line, get(0).unwrap_or(""))?,
+            artist: get(1).map(str::to_string),
+            title: get(2).map(str::to_string),
+            album: get(3).map(str::to_string),
+            duration: get(4).and_then(millis),
+        });
+    }
+
+    Ok(entries)
+}
+
+fn write_csv(entries: &[PlaylistEntry]) -> String {
+    let mut out = CSV_HEADER.join(",");
+    out.push('\n');
+
+    for entry in entries {
+        let text = |value: &Option<String>| value.as_deref().map(csv_field).unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{}",
+            entry.uri,
+            text(&entry.artist),
+            text(&entry.title),
+            text(&entry.album),
+            entry.duration.map(seconds).unwrap_or_default()
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{track, FakePlayer, TempDir, TRACK};
+
+    fn entry(id: &str, artist: Option<&str>, title: Option<&str>) -> PlaylistEntry {
+        PlaylistEntry {
+            artist: artist.map(str::to_string),
+            title: title.map(str::to_string),
+            ..PlaylistEntry::new(format!("spotify:track:{}", id).parse().unwrap())
+        }
+    }
+
+    fn awkward() -> Vec<PlaylistEntry> {
+        vec![
+            PlaylistEntry {
+                album: Some("Greatest <Hits> & \"More\"".to_string()),
+                duration: Some(215_250),
+                ..entry(
+                    "a",
+                    Some("Simon, Garfunkel"),
+                    Some("The Sound - Of Silence"),
+                )
+            },
+            // no artist, but a title that looks like it has one
+            entry("b", None, Some("AC/DC - Live")),
+            entry("c", Some("Band - The"), None),
+            PlaylistEntry {
+                duration: Some(180_000),
+                ..entry("d", Some("Ünïcödé 'quoted'"), Some("日本語"))
+            },
+            PlaylistEntry::new("spotify:episode:512ojhOuo1ktJprKbVcKyQ".parse().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        for format in &[Format::M3u, Format::Xspf, Format::Csv] {
+            let written = format.write(&awkward());
+            assert_eq!(format.parse(&written).unwrap(), awkward(), "{:?}", format);
+        }
+
+        let dir = TempDir::new();
+        for name in &["list.m3u8", "list.xspf", "list.csv"] {
+            save(dir.join(name), &awkward()).unwrap();
+            assert_eq!(load(dir.join(name)).unwrap(), awkward(), "{}", name);
+        }
+        assert!(save(dir.join("list.txt"), &awkward()).is_err());
+    }
+
+    #[test]
+    fn reads_m3u_from_elsewhere() {
+        let data = "\u{feff}#EXTM3U\r\n\
+                    #EXTINF:215 tvg-id=\"x\",Artist - Title\r\n\
+                    https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6?si=abc\r\n\
+                    \r\n\
+                    # a comment\r\n\
+                    spotify:track:b\r\n";
+        assert_eq!(
+            parse_m3u(data).unwrap(),
+            vec![
+                PlaylistEntry {
+                    duration: Some(215_000),
+                    ..entry("6rqhFgbbKwnb9MLmUQDhG6", Some("Artist"), Some("Title"))
+                },
+                entry("b", None, None),
+            ]
+        );
+
+        let err = parse_m3u("#EXTM3U\nspotify:track:a\nnot a uri\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 3:"), "{}", err);
+    }
+
+    #[test]
+    fn reads_xspf_from_elsewhere() {
+        let data = "<?xml version=\"1.0\"?>\n\
+                    <playlist><trackList>\n\
+                    <track id=\"1\">\n  <title><![CDATA[A & B]]></title>\n\
+                    \x20 <identifier>spotify:track:a</identifier>\n</track>\n\
+                    <track><location>spotify:track:b</location><titles>x</titles></track>\n\
+                    </trackList></playlist>\n";
+        assert_eq!(
+            parse_xspf(data).unwrap(),
+            vec![entry("a", None, Some("A & B")), entry("b", None, None)]
+        );
+
+        let err = parse_xspf("<trackList>\n\n<track>\n<title>x</title></track>").unwrap_err();
+        assert!(err.to_string().starts_with("line 3:"), "{}", err);
+        assert!(parse_xspf("<track><location>spotify:track:a</location>").is_err());
+
+        // a long list parses in one pass
+        let long = Format::Xspf.write(&vec![entry("a", Some("A"), Some("T")); 20_000]);
+        assert_eq!(parse_xspf(&long).unwrap().len(), 20_000);
+    }
+
+    #[test]
+    fn reads_csv_with_any_header() {
+        let data = "Track Name,Artist Name(s),Spotify URI,Duration\n\
+                    \"Hello, World\",Band,spotify:track:a,61.5\n\
+                    ,,spotify:track:b,\n";
+        assert_eq!(
+            parse_csv(data).unwrap(),
+            vec![
+                PlaylistEntry {
+                    duration: Some(61_500),
+                    ..entry("a", Some("Band"), Some("Hello, World"))
+                },
+                entry("b", None, None),
+            ]
+        );
+        assert!(parse_csv("name,artist\nx,y\n").is_err());
+        assert!(parse_csv("spotify:track:a,\"open\n").is_err());
+    }
+
+    #[test]
+    fn collects_the_tracks_of_a_session() {
+        let fake = FakePlayer::new();
+        let mut collector = Collector::new();
+
+        assert!(collector.poll(&fake).unwrap());
+        assert!(!collector.poll(&fake).unwrap());
+        fake.next().unwrap();
+        assert!(collector.poll(&fake).unwrap());
+        fake.play_track(TRACK.to_string(), None).unwrap();
+        assert!(!collector.poll(&fake).unwrap());
+        // local files have no Spotify URI
+        let mut local = track("spotify:local:x", 1000);
+        local.spotify_url = None;
+        local.id = None;
+        assert!(!collector.observe(&local));
+
+        let uris: Vec<String> = collector
+            .entries()
+            .iter()
+            .map(|e| e.uri.to_string())
+            .collect();
+        assert_eq!(uris, vec![TRACK, "spotify:track:0000000000000000000next"]);
+        assert_eq!(
+            collector.entries()[0].title.as_deref(),
+            Some("Song 6rqhFgbbKwnb9MLmUQDhG6")
+        );
+
+        let mut repeats = Collector::new().with_repeats(true);
+        for uri in &[TRACK, TRACK, "spotify:track:b", TRACK] {
+            repeats.observe(&track(uri, 1000));
+        }
+        assert_eq!(repeats.entries().len(), 3);
+
+        let dir = TempDir::new();
+        collector.save(dir.join("session.m3u")).unwrap();
+        assert_eq!(load(dir.join("session.m3u")).unwrap(), collector.entries());
+    }
+}
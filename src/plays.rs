@@ -0,0 +1,153 @@
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::state::State;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+// seconds of slack between wall clock and player position before we call it a seek
+const DRIFT: f64 = 1.5;
+// a track that got this close to its end finished instead of being skipped
+const END_MARGIN: f64 = 5.0;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Play {
+    pub track: TrackInfo,
+    pub started_at: SystemTime,
+    pub listened: Duration,
+    pub skipped: bool,
+}
+
+impl Play {
+    pub fn duration(&self) -> Option<Duration> {
+        self.track
+            .duration
+            .filter(|ms| *ms > 0)
+            .map(|ms| Duration::from_millis(ms as u64))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayEvent {
+    Started(Play),
+    Finished(Play),
+}
+
+struct Current {
+    play: Play,
+    key: String,
+    position: Option<f64>,
+    playing: bool,
+    at: SystemTime,
+}
+
+impl Current {
+    fn finish(mut self) -> Play {
+        let near_end = match (self.position, self.play.duration()) {
+            (Some(pos), Some(duration)) => pos + END_MARGIN >= duration.as_secs_f64(),
+            _ => false,
+        };
+        self.play.skipped = !near_end;
+        self.play
+    }
+}
+
+fn track_key(track: &TrackInfo) -> Option<String> {
+    track
+        .spotify_url
+        .clone()
+        .or_else(|| track.id.clone())
+        .or_else(|| match (&track.artist, &track.name) {
+            (Some(artist), Some(name)) => Some(format!("{}\u{0}{}", artist, name)),
+            _ => None,
+        })
+}
+
+#[derive(Default)]
+pub struct PlayTracker {
+    current: Option<Current>,
+}
+
+impl PlayTracker {
+    pub fn new() -> PlayTracker {
+        Default::default()
+    }
+
+    pub fn current(&self) -> Option<&Play> {
+        self.current.as_ref().map(|c| &c.play)
+    }
+
+    pub fn update(&mut self, snapshot: &Snapshot, now: SystemTime) -> Vec<PlayEvent> {
+        let mut events = Vec::new();
+        let playing = snapshot.state == Some(State::PLAYING);
+        let track = match (&snapshot.track, snapshot.state) {
+            (Some(track), Some(State::PLAYING)) | (Some(track), Some(State::PAUSED)) => {
+                track_key(track).map(|key| (track, key))
+            }
+            _ => None,
+        };
+
+        if let Some(current) = &mut self.current {
+            let same_track = track.as_ref().is_some_and(|(_, key)| *key == current.key);
+            let elapsed = now
+                .duration_since(current.at)
+                .unwrap_or_default()
+                .as_secs_f64();
+
+            if same_track && current.playing {
+                current.play.listened +=
+                    Duration::from_secs_f64(match (current.position, snapshot.position) {
+                        (Some(old), Some(new)) if new >= old && new - old <= elapsed + DRIFT => {
+                            new - old
+                        }
+                        _ => elapsed,
+                    });
+            }
+
+            let restarted = same_track
+                && match (current.position, snapshot.position, current.play.duration()) {
+                    (Some(old), Some(new), Some(duration)) => {
+                        old + END_MARGIN >= duration.as_secs_f64() && new < END_MARGIN
+                    }
+                    _ => false,
+                };
+
+            if !same_track || restarted {
+                if let Some(current) = self.current.take() {
+                    events.push(PlayEvent::Finished(current.finish()));
+                }
+            }
+        }
+
+        match (&mut self.current, track) {
+            (Some(current), Some(_)) => {
+                current.position = snapshot.position;
+                current.playing = playing;
+                current.at = now;
+            }
+            (None, Some((track, key))) if playing => {
+                let play = Play {
+                    track: track.clone(),
+                    started_at: now,
+                    listened: Duration::from_secs(0),
+                    skipped: false,
+                };
+                events.push(PlayEvent::Started(play.clone()));
+                self.current = Some(Current {
+                    play,
+                    key,
+                    position: snapshot.position,
+                    playing,
+                    at: now,
+                });
+            }
+            _ => {}
+        }
+
+        events
+    }
+
+    pub fn finish(&mut self) -> Option<Play> {
+        self.current.take().map(Current::finish)
+    }
+}
@@ -0,0 +1,532 @@
+use crate::clock::Clock;
+use crate::player::Player;
+use crate::spool::write_atomic;
+use crate::state::State;
+use crate::uri::SpotifyUri;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// close enough to the end of a track to start the next one, before Spotify
+// moves on to something of its own
+const END_SLACK: f64 = 0.5;
+// how often the position is written out while playing
+const SAVE_EVERY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueState {
+    pub entries: Vec<SpotifyUri>,
+    // the entry playing, or last played
+    pub current: Option<usize>,
+    // seconds into the current entry, to resume from
+    #[serde(default)]
+    pub position: f64,
+}
+
+impl QueueState {
+    fn upcoming(&self) -> usize {
+        self.current.map_or(0, |i| i + 1)
+    }
+}
+
+enum Progress {
+    Over,
+    // paused, or playing something of unknown length
+    Waiting,
+    Left(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Finished,
+    Stopped,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: QueueState,
+    // the current entry was removed or skipped
+    skip: bool,
+    stop: bool,
+    dirty: bool,
+}
+
+fn playable(uri: &SpotifyUri) -> Result<()> {
+    if uri.is_playable() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Only tracks and episodes can be queued, not a {}", uri.kind),
+        ))
+    }
+}
+
+#[derive(Clone)]
+pub struct QueueHandle(Arc<Mutex<Inner>>);
+
+impl QueueHandle {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn state(&self) -> QueueState {
+        self.lock().state.clone()
+    }
+
+    pub fn push(&self, uri: SpotifyUri) -> Result<()> {
+        playable(&uri)?;
+        let mut inner = self.lock();
+        inner.state.entries.push(uri);
+        inner.dirty = true;
+        Ok(())
+    }
+
+    pub fn insert(&self, index: usize, uri: SpotifyUri) -> Result<()> {
+        playable(&uri)?;
+        let mut inner = self.lock();
+        let index = index.min(inner.state.entries.len());
+
+        inner.state.entries.insert(index, uri);
+        if let Some(current) = inner.state.current.as_mut() {
+            if index <= *current {
+                *current += 1;
+            }
+        }
+        inner.dirty = true;
+        Ok(())
+    }
+
+    // Plays `uri` straight after the current entry.
+    pub fn play_next(&self, uri: SpotifyUri) -> Result<()> {
+        let index = self.lock().state.upcoming();
+        self.insert(index, uri)
+    }
+
+    // Removing the entry that is playing skips to the next one.
+    pub fn remove(&self, index: usize) -> Option<SpotifyUri> {
+        let mut inner = self.lock();
+        if index >= inner.state.entries.len() {
+            return None;
+        }
+
+        let uri = inner.state.entries.remove(index);
+        match inner.state.current {
+            Some(current) if index < current => inner.state.current = Some(current - 1),
+            Some(current) if index == current => {
+                inner.state.current = current.checked_sub(1);
+                inner.skip = true;
+            }
+            _ => {}
+        }
+        inner.dirty = true;
+
+        Some(uri)
+    }
+
+    pub fn move_entry(&self, from: usize, to: usize) -> bool {
+        let mut inner = self.lock();
+        let len = inner.state.entries.len();
+        if from >= len || to >= len {
+            return false;
+        }
+
+        let uri = inner.state.entries.remove(from);
+        inner.state.entries.insert(to, uri);
+        if let Some(current) = inner.state.current.as_mut() {
+            *current = match *current {
+                c if c == from => to,
+                c if from < c && c <= to => c - 1,
+                c if to <= c && c < from => c + 1,
+                c => c,
+            };
+        }
+        inner.dirty = true;
+
+        true
+    }
+
+    // Shuffles the entries that have not been played yet.
+    pub fn shuffle(&self) {
+        let mut inner = self.lock();
+        let start = inner.state.upcoming();
+        let upcoming = &mut inner.state.entries[start..];
+
+        // xorshift is plenty to deal a few tracks
+        let mut seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0x2545_f491, |d| d.as_nanos() as u64)
+            | 1;
+        for i in (1..upcoming.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            upcoming.swap(i, (seed % (i as u64 + 1)) as usize);
+        }
+        inner.dirty = true;
+    }
+
+    pub fn clear_upcoming(&self) {
+        let mut inner = self.lock();
+        let start = inner.state.upcoming();
+        inner.state.entries.truncate(start);
+        inner.dirty = true;
+    }
+
+    pub fn skip(&self) {
+        self.lock().skip = true;
+    }
+
+    // Stops running, leaving the current track playing and the queue saved
+    // to resume from.
+    pub fn stop(&self) {
+        self.lock().stop = true;
+    }
+}
+
+pub struct QueueRunner<P, C> {
+    player: P,
+    clock: C,
+    path: Option<PathBuf>,
+    step: Duration,
+    start_timeout: Duration,
+    handle: QueueHandle,
+}
+
+impl<P: Player, C: Clock> QueueRunner<P, C> {
+    pub fn new(player: P, clock: C, entries: Vec<SpotifyUri>) -> QueueRunner<P, C> {
+        let inner = Inner {
+            state: QueueState {
+                entries,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        QueueRunner {
+            player,
+            clock,
+            path: None,
+            step: Duration::from_secs(1),
+            start_timeout: Duration::from_secs(10),
+            handle: QueueHandle(Arc::new(Mutex::new(inner))),
+        }
+    }
+
+    // Resumes the queue saved at `path`, if there is one, and keeps it saved
+    // there.
+    pub fn open<T: AsRef<Path>>(player: P, clock: C, path: T) -> Result<QueueRunner<P, C>> {
+        let path = path.as_ref().to_path_buf();
+        let state: QueueState = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err),
+        };
+
+        let runner = QueueRunner::new(player, clock, Vec::new());
+        runner.handle.lock().state = state;
+        Ok(QueueRunner {
+            path: Some(path),
+            ..runner
+        })
+    }
+
+    pub fn with_step(mut self, step: Duration) -> QueueRunner<P, C> {
+        self.step = step;
+        self
+    }
+
+    pub fn with_start_timeout(mut self, timeout: Duration) -> QueueRunner<P, C> {
+        self.start_timeout = timeout;
+        self
+    }
+
+    pub fn handle(&self) -> QueueHandle {
+        self.handle.clone()
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    // Plays the queue through, picking up where a saved one left off.
+    pub fn run(&self) -> Result<Outcome> {
+        let mut resume = {
+            let inner = self.handle.lock();
+            inner.state.current.map(|_| inner.state.position)
+        };
+        let mut playing: Option<SpotifyUri> = None;
+        let mut saved_at = self.clock.now();
+
+        loop {
+            // ask Spotify before taking the lock, so the handle never waits on it
+            if self.handle.lock().stop {
+                let position = match playing {
+                    Some(_) => Some(self.player.position()?.unwrap_or(0.0)),
+                    None => None,
+                };
+                let mut inner = self.handle.lock();
+                inner.stop = false;
+                if let Some(position) = position {
+                    inner.state.position = position;
+                }
+                self.save(&mut inner)?;
+                return Ok(Outcome::Stopped);
+            }
+
+            let mut inner = self.handle.lock();
+
+            if playing.is_none() || inner.skip {
+                inner.skip = false;
+
+                let next = match (resume.is_some(), inner.state.current) {
+                    (true, Some(current)) => current,
+                    _ => inner.state.upcoming(),
+                };
+                if next >= inner.state.entries.len() {
+                    inner.state.current = None;
+                    inner.state.position = 0.0;
+                    self.save(&mut inner)?;
+                    return Ok(Outcome::Finished);
+                }
+
+                let uri = inner.state.entries[next].clone();
+                inner.state.current = Some(next);
+                inner.state.position = resume.unwrap_or(0.0);
+                self.save(&mut inner)?;
+                drop(inner);
+
+                self.start(&uri, resume.take())?;
+                playing = Some(uri);
+                saved_at = self.clock.now();
+                continue;
+            }
+
+            if inner.dirty {
+                self.save(&mut inner)?;
+            }
+            drop(inner);
+
+            let expected = playing.as_ref().map(|uri| uri.to_string());
+            let progress = self.progress(expected.as_deref())?;
+            if let Progress::Over = progress {
+                playing = None;
+                continue;
+            }
+
+            if self.clock.now().saturating_duration_since(saved_at) >= SAVE_EVERY {
+                let position = self.player.position()?.unwrap_or(0.0);
+                let mut inner = self.handle.lock();
+                inner.state.position = position;
+                self.save(&mut inner)?;
+                saved_at = self.clock.now();
+            }
+
+            // aim inside the slack so the next track starts before Spotify
+            // picks one
+            let nap = match progress {
+                Progress::Left(remaining) => {
+                    Duration::from_secs_f64((remaining - END_SLACK / 2.0).max(0.0))
+                }
+                _ => self.step,
+            };
+            self.clock.sleep(nap.min(self.step));
+        }
+    }
+
+    // How far along `expected` is, over once something else plays.
+    fn progress(&self, expected: Option<&str>) -> Result<Progress> {
+        let track = self.player.track()?;
+        let state = self.player.state()?;
+
+        let same = track.as_ref().and_then(|t| t.spotify_url.as_deref()) == expected;
+        if !same || state == Some(State::STOPPED) {
+            return Ok(Progress::Over);
+        }
+        if state != Some(State::PLAYING) {
+            return Ok(Progress::Waiting);
+        }
+
+        let duration = match track.and_then(|t| t.duration).filter(|ms| *ms > 0) {
+            Some(ms) => ms as f64 / 1000.0,
+            None => return Ok(Progress::Waiting),
+        };
+        let remaining = duration - self.player.position()?.unwrap_or(0.0);
+
+        if remaining <= END_SLACK {
+            Ok(Progress::Over)
+        } else {
+            Ok(Progress::Left(remaining))
+        }
+    }
+
+    fn start(&self, uri: &SpotifyUri, position: Option<f64>) -> Result<()> {
+        let expected = uri.to_string();
+        self.player.play_track(expected.clone(), None)?;
+
+        let deadline: Instant = self.clock.now() + self.start_timeout;
+        loop {
+            let track = self.player.track()?;
+            if track.and_then(|t| t.spotify_url).as_ref() == Some(&expected)
+                && self.player.state()? == Some(State::PLAYING)
+            {
+                break;
+            }
+            if self.clock.now() >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Spotify did not start playing {}", expected),
+                ));
+            }
+            self.clock.sleep(self.step);
+        }
+
+        match position {
+            Some(position) if position > 0.0 => self.player.set_position(position),
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self, inner: &mut Inner) -> Result<()> {
+        inner.dirty = false;
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data = serde_json::to_vec(&inner.state)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+        write_atomic(path, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Cued, FakePlayer, TempDir};
+
+    fn uri(id: &str) -> SpotifyUri {
+        format!("spotify:track:{}", id).parse().unwrap()
+    }
+
+    fn started(fake: &FakePlayer) -> Vec<String> {
+        fake.calls()
+            .iter()
+            .filter_map(|call| call.strip_prefix("play_track spotify:track:"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn plays_the_queue_through() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        fake.follow(clock.virtual_clock());
+        let runner = QueueRunner::new(&fake, &clock, vec![uri("a"), uri("b"), uri("c")]);
+
+        assert_eq!(runner.run().unwrap(), Outcome::Finished);
+        assert_eq!(started(&fake), vec!["a", "b", "c"]);
+        // each 200s track played to within the slack of its end
+        let elapsed = clock.elapsed().as_secs_f64();
+        assert!(elapsed > 598.0 && elapsed <= 600.0, "{}", elapsed);
+        assert_eq!(runner.handle().state().current, None);
+    }
+
+    #[test]
+    fn saves_where_it_stopped_and_resumes() {
+        let dir = TempDir::new();
+        let path = dir.join("queue.json");
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        fake.follow(clock.virtual_clock());
+
+        let runner = QueueRunner::open(&fake, &clock, &path).unwrap();
+        let handle = runner.handle();
+        for id in &["a", "b"] {
+            handle.push(uri(id)).unwrap();
+        }
+        clock.at(Duration::from_secs(250), move || handle.stop());
+        assert_eq!(runner.run().unwrap(), Outcome::Stopped);
+
+        let saved: QueueState = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.current, Some(1));
+        // the first track ended just short of 200s
+        assert!(saved.position >= 50.0 && saved.position <= 51.0);
+
+        fake.clear();
+        let runner = QueueRunner::open(&fake, &clock, &path).unwrap();
+        assert_eq!(runner.run().unwrap(), Outcome::Finished);
+        assert_eq!(started(&fake), vec!["b"]);
+        assert_eq!(fake.count("set_position"), 1);
+        let resumed = fake.get().position.unwrap();
+        assert!(resumed >= 199.5, "{}", resumed);
+    }
+
+    #[test]
+    fn follows_edits_while_playing() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        fake.follow(clock.virtual_clock());
+        let runner = QueueRunner::new(&fake, &clock, vec![uri("a"), uri("b"), uri("c")]);
+        let handle = runner.handle();
+
+        let h = handle.clone();
+        clock.at(Duration::from_secs(10), move || {
+            h.insert(0, uri("z")).unwrap();
+            assert_eq!(h.state().current, Some(1));
+            h.play_next(uri("n")).unwrap();
+            assert!(h.move_entry(4, 3));
+        });
+        // removing what plays skips it
+        let h = handle.clone();
+        clock.at(Duration::from_secs(220), move || {
+            assert_eq!(h.remove(2), Some(uri("n")));
+        });
+        let h = handle.clone();
+        clock.at(Duration::from_secs(230), move || h.skip());
+
+        assert_eq!(runner.run().unwrap(), Outcome::Finished);
+        // "c" was moved before "b", then skipped
+        assert_eq!(started(&fake), vec!["a", "n", "c", "b"]);
+        assert_eq!(
+            handle.state().entries,
+            vec![uri("z"), uri("a"), uri("c"), uri("b")]
+        );
+    }
+
+    #[test]
+    fn moves_on_when_something_else_plays() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        fake.follow(clock.virtual_clock());
+        let runner = QueueRunner::new(&fake, &clock, vec![uri("a"), uri("b")]);
+        clock.at(Duration::from_secs(30), || fake.next().unwrap());
+
+        assert_eq!(runner.run().unwrap(), Outcome::Finished);
+        assert_eq!(started(&fake), vec!["a", "b"]);
+        assert!(clock.elapsed() < Duration::from_secs(240));
+    }
+
+    #[test]
+    fn only_queues_tracks_and_episodes() {
+        let fake = FakePlayer::new();
+        let clock = Cued::new();
+        let runner = QueueRunner::new(&fake, &clock, Vec::new());
+        let handle = runner.handle();
+
+        let album: SpotifyUri = "spotify:album:1DFixLWuPkv3KT3TnV35m3".parse().unwrap();
+        let err = handle.push(album.clone()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(handle.insert(0, album.clone()).is_err());
+        assert!(handle.play_next(album).is_err());
+        handle
+            .push("spotify:episode:512ojhOuo1ktJprKbVcKyQ".parse().unwrap())
+            .unwrap();
+        assert_eq!(handle.state().entries.len(), 1);
+    }
+}
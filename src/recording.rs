@@ -0,0 +1,319 @@
+#[cfg(target_os = "macos")]
+use crate::events::{parse_desc, print_params, AEDesc, ResType, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+// One event sent and what came back, a line of the recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub class: String,
+    pub id: String,
+    // the event's parameters as AEGizmos text
+    pub params: String,
+    #[serde(default)]
+    pub reply: Option<String>,
+    // the OSStatus the send failed with
+    #[serde(default)]
+    pub error: Option<i32>,
+    // why the event or its reply could not be printed, if they couldn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unprintable: Option<String>,
+}
+
+impl Exchange {
+    pub fn new(class: &str, id: &str, params: &str) -> Exchange {
+        Exchange {
+            class: class.to_string(),
+            id: id.to_string(),
+            params: params.to_string(),
+            reply: None,
+            error: None,
+            unprintable: None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("'{}'\\'{}' {}", self.class, self.id, self.params)
+    }
+}
+
+fn json_error(err: serde_json::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Passes events on to `inner` and appends each exchange to a JSON lines file.
+pub struct Recorder<T> {
+    inner: T,
+    file: Mutex<File>,
+    // the first line that could not be written, sends go on regardless
+    failed: Mutex<Option<Error>>,
+}
+
+impl<T> Recorder<T> {
+    pub fn create<A: AsRef<Path>>(path: A, inner: T) -> Result<Recorder<T>> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Recorder {
+            inner,
+            file: Mutex::new(file),
+            failed: Mutex::new(None),
+        })
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // Appends `exchange`, flushed so it survives the process dying right
+    // after.
+    pub fn record(&self, exchange: &Exchange) -> Result<()> {
+        let mut line = serde_json::to_string(exchange).map_err(json_error)?;
+        line.push('\n');
+
+        let mut file = lock(&self.file);
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+
+    // Fails if any exchange went unrecorded.
+    pub fn finish(&self) -> Result<()> {
+        match lock(&self.failed).take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl<T: Transport> Transport for Recorder<T> {
+    fn send(
+        &self,
+        class: ResType,
+        id: ResType,
+        event: &AEDesc,
+        mut reply: Option<&mut AEDesc>,
+        mode: i32,
+    ) -> Result<()> {
+        let mut exchange = Exchange::new(&class.to_string(), &id.to_string(), "");
+        match print_params(event) {
+            Ok(params) => exchange.params = params,
+            Err(err) => exchange.unprintable = Some(format!("event: {}", err)),
+        }
+
+        let res = self
+            .inner
+            .send(class, id, event, reply.as_deref_mut(), mode);
+
+        match (&res, reply) {
+            (Ok(()), Some(reply)) => match print_params(reply) {
+                Ok(text) => exchange.reply = Some(text),
+                Err(err) => exchange.unprintable = Some(format!("reply: {}", err)),
+            },
+            (Err(err), _) => exchange.error = Some(err.raw_os_error().unwrap_or(-1)),
+            _ => {}
+        }
+
+        if let Err(err) = self.record(&exchange) {
+            lock(&self.failed).get_or_insert(err);
+        }
+        res
+    }
+}
+
+// Answers events from a recording instead of Spotify, failing as soon as
+// one differs from what was recorded.
+pub struct Replay {
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Replay {
+    pub fn new(exchanges: Vec<Exchange>) -> Replay {
+        Replay {
+            exchanges: Mutex::new(exchanges.into()),
+        }
+    }
+
+    pub fn open<A: AsRef<Path>>(path: A) -> Result<Replay> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut exchanges = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            exchanges.push(serde_json::from_str(&line).map_err(json_error)?);
+        }
+
+        Ok(Replay::new(exchanges))
+    }
+
+    pub fn remaining(&self) -> usize {
+        lock(&self.exchanges).len()
+    }
+
+    // The recorded reply to the next event, which has to be this one.
+    pub fn exchange(&self, class: &str, id: &str, params: &str) -> Result<Option<String>> {
+        let sent = Exchange::new(class, id, params);
+
+        let expected = lock(&self.exchanges).pop_front().ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Sent {} after the end of the recording", sent.describe()),
+            )
+        })?;
+        if (&expected.class, &expected.id, &expected.params)
+            != (&sent.class, &sent.id, &sent.params)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expected {}, but sent {}",
+                    expected.describe(),
+                    sent.describe()
+                ),
+            ));
+        }
+
+        match expected.error {
+            Some(code) => Err(Error::from_raw_os_error(code)),
+            None => Ok(expected.reply),
+        }
+    }
+
+    // Fails unless every recorded event was sent.
+    pub fn finish(&self) -> Result<()> {
+        let exchanges = lock(&self.exchanges);
+        match exchanges.front() {
+            Some(next) => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "{} recorded events were never sent, the first {}",
+                    exchanges.len(),
+                    next.describe()
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Transport for Replay {
+    fn send(
+        &self,
+        class: ResType,
+        id: ResType,
+        event: &AEDesc,
+        reply: Option<&mut AEDesc>,
+        _mode: i32,
+    ) -> Result<()> {
+        let recorded = self.exchange(&class.to_string(), &id.to_string(), &print_params(event)?)?;
+        if let (Some(reply), Some(text)) = (reply, recorded) {
+            *reply = parse_desc(&text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn volume(params: &str, reply: Option<&str>) -> Exchange {
+        Exchange {
+            reply: reply.map(str::to_string),
+            ..Exchange::new("core", "getd", params)
+        }
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let dir = TempDir::new();
+        let path = dir.join("session/spotify.jsonl");
+        let recorder = Recorder::create(&path, ()).unwrap();
+
+        let get = "{----:'obj '{want:type(prop), seld:type(pVol)}}";
+        recorder.record(&volume(get, Some("{----:50}"))).unwrap();
+        recorder
+            .record(&Exchange {
+                error: Some(-600),
+                ..Exchange::new("core", "setd", "{data:30}")
+            })
+            .unwrap();
+        recorder
+            .record(&Exchange {
+                unprintable: Some("reply: bad descriptor".to_string()),
+                ..Exchange::new("hook", "PlPs", "")
+            })
+            .unwrap();
+        recorder.finish().unwrap();
+
+        let replay = Replay::open(&path).unwrap();
+        assert_eq!(replay.remaining(), 3);
+        assert_eq!(
+            replay.exchange("core", "getd", get).unwrap().as_deref(),
+            Some("{----:50}")
+        );
+        let err = replay.exchange("core", "setd", "{data:30}").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(-600));
+        assert!(replay.finish().is_err());
+        assert_eq!(replay.exchange("hook", "PlPs", "").unwrap(), None);
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn fails_on_anything_unexpected() {
+        let replay = Replay::new(vec![volume("{}", None), volume("{}", None)]);
+
+        let err = replay.exchange("core", "setd", "{}").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(
+            err.to_string().contains("Expected 'core'\\'getd'"),
+            "{}",
+            err
+        );
+
+        let err = replay.exchange("core", "getd", "{data:1}").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = replay.exchange("core", "getd", "{}").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reads_old_recordings() {
+        let dir = TempDir::new();
+        let path = dir.join("old.jsonl");
+        fs::write(
+            &path,
+            "{\"class\":\"core\",\"id\":\"getd\",\"params\":\"{}\"}\n\n",
+        )
+        .unwrap();
+
+        let replay = Replay::open(&path).unwrap();
+        assert_eq!(replay.exchange("core", "getd", "{}").unwrap(), None);
+        replay.finish().unwrap();
+
+        fs::write(&path, "{\"class\":\"core\"\n").unwrap();
+        let err = Replay::open(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,196 @@
+use std::fmt::Display;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REDACT_PAYLOADS: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redaction {
+    // codes, queries, types and sizes only
+    #[default]
+    Payloads,
+    // also the events and replies as AEGizmos text, which can hold track
+    // names and URIs
+    Nothing,
+}
+
+// What traces leave out, payloads unless told otherwise.
+pub fn set_redaction(redaction: Redaction) {
+    REDACT_PAYLOADS.store(redaction == Redaction::Payloads, Ordering::Relaxed);
+}
+
+fn redaction() -> Redaction {
+    if REDACT_PAYLOADS.load(Ordering::Relaxed) {
+        Redaction::Payloads
+    } else {
+        Redaction::Nothing
+    }
+}
+
+// Traces an event about to be sent. `params` prints it, and is only called
+// when payloads are traced.
+pub(crate) fn sending<F: FnOnce() -> Result<String>>(params: F) {
+    sending_as(redaction(), params)
+}
+
+fn sending_as<F: FnOnce() -> Result<String>>(redaction: Redaction, params: F) {
+    if redaction == Redaction::Nothing {
+        if let Ok(params) = params() {
+            tracing::trace!(event = %params, "sending");
+        }
+    }
+}
+
+// Traces a reply's direct object, printed by `params` only when payloads are
+// traced.
+pub(crate) fn reply<T: Display, F: FnOnce() -> Result<String>>(
+    reply_type: T,
+    reply_size: usize,
+    params: F,
+) {
+    reply_as(redaction(), reply_type, reply_size, params)
+}
+
+fn reply_as<T: Display, F: FnOnce() -> Result<String>>(
+    redaction: Redaction,
+    reply_type: T,
+    reply_size: usize,
+    params: F,
+) {
+    match redaction {
+        Redaction::Payloads => tracing::debug!(reply_type = %reply_type, reply_size, "reply"),
+        Redaction::Nothing => {
+            let params = params().unwrap_or_default();
+            tracing::debug!(reply_type = %reply_type, reply_size, reply = %params, "reply");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    const PAYLOAD: &str = "{----:\"spotify:track:6rqhFgbbKwnb9MLmUQDhG6\"}";
+
+    // every field of every event, as `name=value`
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Capture {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let line = format!("{}={:?}", field.name(), value);
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            event.record(&mut self.clone());
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    fn capture<F: FnOnce()>(f: F) -> Vec<String> {
+        let capture = Capture::default();
+        tracing::subscriber::with_default(capture.clone(), f);
+        let fields = capture.0.lock().unwrap().clone();
+        fields
+    }
+
+    fn mentions_payload(fields: &[String]) -> bool {
+        fields
+            .iter()
+            .any(|field| field.contains("6rqhFgbbKwnb9MLmUQDhG6"))
+    }
+
+    #[test]
+    fn leaves_payloads_out_by_default() {
+        assert_eq!(Redaction::default(), Redaction::Payloads);
+
+        let fields = capture(|| {
+            sending_as(Redaction::Payloads, || panic!("printed a redacted event"));
+            reply_as(Redaction::Payloads, "utxt", 44, || {
+                panic!("printed a redacted reply")
+            });
+        });
+
+        assert!(!mentions_payload(&fields), "{:?}", fields);
+        assert!(
+            fields.contains(&"reply_type=utxt".to_string()),
+            "{:?}",
+            fields
+        );
+        assert!(
+            fields.contains(&"reply_size=44".to_string()),
+            "{:?}",
+            fields
+        );
+    }
+
+    #[test]
+    fn traces_payloads_when_asked() {
+        let fields = capture(|| {
+            sending_as(Redaction::Nothing, || Ok(PAYLOAD.to_string()));
+            reply_as(Redaction::Nothing, "utxt", 44, || Ok(PAYLOAD.to_string()));
+        });
+
+        assert_eq!(
+            fields
+                .iter()
+                .filter(|field| field.contains(PAYLOAD))
+                .count(),
+            2,
+            "{:?}",
+            fields
+        );
+        assert!(
+            fields.contains(&format!("event={}", PAYLOAD)),
+            "{:?}",
+            fields
+        );
+        assert!(
+            fields.contains(&format!("reply={}", PAYLOAD)),
+            "{:?}",
+            fields
+        );
+    }
+
+    #[test]
+    fn follows_set_redaction() {
+        let trace = || {
+            capture(|| {
+                sending(|| Ok(PAYLOAD.to_string()));
+                reply("utxt", 44, || Ok(PAYLOAD.to_string()));
+            })
+        };
+
+        assert_eq!(redaction(), Redaction::Payloads);
+        assert!(!mentions_payload(&trace()));
+
+        set_redaction(Redaction::Nothing);
+        assert!(mentions_payload(&trace()));
+
+        set_redaction(Redaction::Payloads);
+        assert!(!mentions_payload(&trace()));
+    }
+}
@@ -0,0 +1,648 @@
+use crate::calendar::{date, from_unix, unix, utc_offset, weekday, DAY};
+use crate::history::{Entry, History};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::io::{Error, ErrorKind, Result};
+use std::time::SystemTime;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    // seconds east of UTC, decides which day and hour a play falls in, None
+    // for the system time zone as it was at each play
+    pub utc_offset: Option<i64>,
+    pub top: usize,
+}
+
+impl Default for ReportOptions {
+    fn default() -> ReportOptions {
+        ReportOptions {
+            utc_offset: Some(0),
+            top: 10,
+        }
+    }
+}
+
+impl ReportOptions {
+    pub fn local() -> ReportOptions {
+        ReportOptions {
+            utc_offset: None,
+            ..Default::default()
+        }
+    }
+}
+
+// `at` in local seconds since the epoch
+fn local(at: i64, utc_offset: Option<i64>) -> i64 {
+    at + utc_offset.unwrap_or_else(|| self::utc_offset(from_unix(at)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Days,
+    Weeks,
+    Tracks,
+    Artists,
+    Albums,
+    Heatmap,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Period {
+    // first day of the period, YYYY-MM-DD
+    pub date: String,
+    pub plays: u64,
+    pub skipped: u64,
+    // seconds
+    pub listened: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ranked {
+    pub name: String,
+    pub artist: Option<String>,
+    pub plays: u64,
+    // seconds
+    pub listened: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    // unix time
+    pub from: i64,
+    pub to: i64,
+    // None for the system time zone
+    pub utc_offset: Option<i64>,
+    pub plays: u64,
+    pub skipped: u64,
+    // seconds
+    pub listened: u64,
+    pub skip_ratio: Option<f64>,
+    // tracks never played before the report started
+    pub discoveries: u64,
+    pub longest_streak: u32,
+    pub current_streak: u32,
+    pub days: Vec<Period>,
+    pub weeks: Vec<Period>,
+    pub top_tracks: Vec<Ranked>,
+    pub top_artists: Vec<Ranked>,
+    pub top_albums: Vec<Ranked>,
+    // plays by weekday (Monday first) and hour
+    pub heatmap: Vec<[u64; 24]>,
+}
+
+fn track_key(entry: &Entry) -> Option<String> {
+    entry.track_id.clone().or_else(|| {
+        entry
+            .name
+            .as_ref()
+            .map(|name| format!("{}\u{0}{}", entry.artist.as_deref().unwrap_or(""), name))
+    })
+}
+
+#[derive(Default)]
+struct Tally {
+    name: String,
+    artist: Option<String>,
+    plays: u64,
+    listened: u64,
+}
+
+fn rank(tallies: HashMap<String, Tally>, top: usize) -> Vec<Ranked> {
+    let mut ranked: Vec<Ranked> = tallies
+        .into_values()
+        .map(|t| Ranked {
+            name: t.name,
+            artist: t.artist,
+            plays: t.plays,
+            listened: t.listened,
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        b.plays
+            .cmp(&a.plays)
+            .then(b.listened.cmp(&a.listened))
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.artist.cmp(&b.artist))
+    });
+    ranked.truncate(top);
+    ranked
+}
+
+fn tally(
+    tallies: &mut HashMap<String, Tally>,
+    key: String,
+    name: &str,
+    artist: Option<&String>,
+    listened: u64,
+) {
+    let tally = tallies.entry(key).or_insert_with(|| Tally {
+        name: name.to_string(),
+        artist: artist.cloned(),
+        ..Default::default()
+    });
+    tally.plays += 1;
+    tally.listened += listened;
+}
+
+fn count(period: &mut Period, entry: &Entry) {
+    period.plays += 1;
+    period.skipped += entry.skipped as u64;
+    period.listened += entry.listened.as_secs();
+}
+
+fn periods(map: BTreeMap<i64, Period>) -> Vec<Period> {
+    map.into_iter()
+        .map(|(day, mut period)| {
+            period.date = date(day);
+            period
+        })
+        .collect()
+}
+
+impl Report {
+    pub fn generate(
+        history: &History,
+        from: SystemTime,
+        to: SystemTime,
+        options: &ReportOptions,
+    ) -> Result<Report> {
+        let entries = history.between(from, to)?;
+        let known = history.known_tracks(from)?;
+        Ok(Report::build(&entries, &known, from, to, options))
+    }
+
+    // `known` holds the track ids played before `from`, see History::known_tracks.
+    pub fn build(
+        entries: &[Entry],
+        known: &HashSet<String>,
+        from: SystemTime,
+        to: SystemTime,
+        options: &ReportOptions,
+    ) -> Report {
+        let (from, to) = (unix(from), unix(to));
+        let mut entries: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| (from..to).contains(&unix(e.started_at)))
+            .collect();
+        entries.sort_by_key(|e| (e.started_at, e.id));
+
+        let mut report = Report {
+            from,
+            to,
+            utc_offset: options.utc_offset,
+            plays: 0,
+            skipped: 0,
+            listened: 0,
+            skip_ratio: None,
+            discoveries: 0,
+            longest_streak: 0,
+            current_streak: 0,
+            days: Vec::new(),
+            weeks: Vec::new(),
+            top_tracks: Vec::new(),
+            top_artists: Vec::new(),
+            top_albums: Vec::new(),
+            heatmap: vec![[0; 24]; 7],
+        };
+
+        let mut days = BTreeMap::new();
+        let mut weeks = BTreeMap::new();
+        let mut active = BTreeSet::new();
+        let mut seen = HashSet::new();
+        let mut tracks = HashMap::new();
+        let mut artists = HashMap::new();
+        let mut albums = HashMap::new();
+
+        for entry in entries {
+            let local = local(unix(entry.started_at), options.utc_offset);
+            let day = local.div_euclid(DAY);
+            let hour = (local.rem_euclid(DAY) / 3600) as usize;
+            let listened = entry.listened.as_secs();
+
+            report.plays += 1;
+            report.skipped += entry.skipped as u64;
+            report.listened += listened;
+            report.heatmap[weekday(day)][hour] += 1;
+            count(days.entry(day).or_default(), entry);
+            count(weeks.entry(day - weekday(day) as i64).or_default(), entry);
+
+            let key = track_key(entry);
+            if let Some(key) = &key {
+                if !known.contains(key) && seen.insert(key.clone()) {
+                    report.discoveries += 1;
+                }
+            }
+
+            // skips say little about taste, keep them out of streaks and charts
+            if entry.skipped {
+                continue;
+            }
+            active.insert(day);
+
+            if let (Some(key), Some(name)) = (key, &entry.name) {
+                tally(&mut tracks, key, name, entry.artist.as_ref(), listened);
+            }
+            if let Some(artist) = &entry.artist {
+                tally(&mut artists, artist.clone(), artist, None, listened);
+            }
+            if let Some(album) = &entry.album {
+                let key = format!("{}\u{0}{}", entry.artist.as_deref().unwrap_or(""), album);
+                tally(&mut albums, key, album, entry.artist.as_ref(), listened);
+            }
+        }
+
+        let mut streak = 0;
+        let mut last = None;
+        for &day in &active {
+            streak = if last == Some(day - 1) { streak + 1 } else { 1 };
+            report.longest_streak = report.longest_streak.max(streak);
+            last = Some(day);
+        }
+
+        let today = local(to - 1, options.utc_offset).div_euclid(DAY);
+        if last == Some(today) || last == Some(today - 1) {
+            report.current_streak = streak;
+        }
+
+        if report.plays > 0 {
+            report.skip_ratio = Some(report.skipped as f64 / report.plays as f64);
+        }
+        report.days = periods(days);
+        report.weeks = periods(weeks);
+        report.top_tracks = rank(tracks, options.top);
+        report.top_artists = rank(artists, options.top);
+        report.top_albums = rank(albums, options.top);
+
+        report
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    pub fn to_csv(&self, table: Table) -> String {
+        let mut out = String::new();
+
+        match table {
+            Table::Days | Table::Weeks => {
+                out.push_str("date,plays,skipped,listened\n");
+                let periods = if table == Table::Days {
+                    &self.days
+                } else {
+                    &self.weeks
+                };
+                for p in periods {
+                    let _ = writeln!(out, "{},{},{},{}", p.date, p.plays, p.skipped, p.listened);
+                }
+            }
+            Table::Tracks | Table::Artists | Table::Albums => {
+                out.push_str("rank,name,artist,plays,listened\n");
+                for (i, r) in self.ranked(table).iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "{},{},{},{},{}",
+                        i + 1,
+                        csv_field(&r.name),
+                        csv_field(r.artist.as_deref().unwrap_or("")),
+                        r.plays,
+                        r.listened
+                    );
+                }
+            }
+            Table::Heatmap => {
+                out.push_str("weekday");
+                for hour in 0..24 {
+                    let _ = write!(out, ",{}", hour);
+                }
+                out.push('\n');
+                for (day, hours) in WEEKDAYS.iter().zip(&self.heatmap) {
+                    out.push_str(day);
+                    for plays in hours {
+                        let _ = write!(out, ",{}", plays);
+                    }
+                    out.push('\n');
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = String::new();
+        let from = date(local(self.from, self.utc_offset).div_euclid(DAY));
+        let to = date(local(self.to - 1, self.utc_offset).div_euclid(DAY));
+
+        out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        let _ = writeln!(out, "<title>Listening report {} – {}</title>", from, to);
+        out.push_str(concat!(
+            "<style>\n",
+            "body{font-family:-apple-system,Helvetica,sans-serif;margin:2em auto;max-width:60em;color:#222}\n",
+            "table{border-collapse:collapse;margin-bottom:2em}\n",
+            "th,td{padding:.25em .6em;text-align:left;border-bottom:1px solid #ddd}\n",
+            "td.n{text-align:right}\n",
+            ".heat td{width:1.6em;height:1.6em;padding:0;border:1px solid #fff;font-size:.7em;text-align:center}\n",
+            ".bar{background:#1db954;height:.8em}\n",
+            "</style>\n</head>\n<body>\n"
+        ));
+
+        let _ = writeln!(out, "<h1>Listening report</h1>\n<p>{} – {}</p>", from, to);
+        out.push_str("<table>\n");
+        let _ = writeln!(
+            out,
+            "<tr><th>Plays</th><td class=\"n\">{}</td></tr>",
+            self.plays
+        );
+        let _ = writeln!(
+            out,
+            "<tr><th>Listening time</th><td class=\"n\">{}</td></tr>",
+            hours(self.listened)
+        );
+        let _ = writeln!(
+            out,
+            "<tr><th>Skip ratio</th><td class=\"n\">{}</td></tr>",
+            self.skip_ratio
+                .map_or("–".to_string(), |r| format!("{:.1}%", r * 100.0))
+        );
+        let _ = writeln!(
+            out,
+            "<tr><th>New tracks</th><td class=\"n\">{}</td></tr>",
+            self.discoveries
+        );
+        let _ = writeln!(
+            out,
+            "<tr><th>Streak</th><td class=\"n\">{} days (longest {})</td></tr>",
+            self.current_streak, self.longest_streak
+        );
+        out.push_str("</table>\n");
+
+        for (title, table) in &[
+            ("Top tracks", Table::Tracks),
+            ("Top artists", Table::Artists),
+            ("Top albums", Table::Albums),
+        ] {
+            let _ = writeln!(out, "<h2>{}</h2>\n<table>", title);
+            out.push_str(
+                "<tr><th>#</th><th>Name</th><th>Artist</th><th>Plays</th><th>Time</th></tr>\n",
+            );
+            for (i, r) in self.ranked(*table).iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "<tr><td class=\"n\">{}</td><td>{}</td><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td></tr>",
+                    i + 1,
+                    html(&r.name),
+                    html(r.artist.as_deref().unwrap_or("")),
+                    r.plays,
+                    hours(r.listened)
+                );
+            }
+            out.push_str("</table>\n");
+        }
+
+        let max = self
+            .days
+            .iter()
+            .map(|d| d.listened)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        out.push_str("<h2>Daily listening</h2>\n<table>\n");
+        for day in &self.days {
+            let _ = writeln!(
+                out,
+                "<tr><td>{}</td><td class=\"n\">{}</td><td style=\"width:20em\"><div class=\"bar\" style=\"width:{:.1}%\"></div></td></tr>",
+                day.date,
+                hours(day.listened),
+                day.listened as f64 * 100.0 / max as f64
+            );
+        }
+        out.push_str("</table>\n");
+
+        let max = self
+            .heatmap
+            .iter()
+            .flatten()
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        out.push_str("<h2>Time of day</h2>\n<table class=\"heat\">\n<tr><th></th>");
+        for hour in 0..24 {
+            let _ = write!(out, "<th>{}</th>", hour);
+        }
+        out.push_str("</tr>\n");
+        for (day, row) in WEEKDAYS.iter().zip(&self.heatmap) {
+            let _ = write!(out, "<tr><th>{}</th>", day);
+            for plays in row {
+                let _ = write!(
+                    out,
+                    "<td title=\"{}\" style=\"background:rgba(29,185,84,{:.2})\"></td>",
+                    plays,
+                    *plays as f64 / max as f64
+                );
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n</body>\n</html>\n");
+
+        out
+    }
+
+    fn ranked(&self, table: Table) -> &[Ranked] {
+        match table {
+            Table::Tracks => &self.top_tracks,
+            Table::Artists => &self.top_artists,
+            Table::Albums => &self.top_albums,
+            _ => &[],
+        }
+    }
+}
+
+fn hours(secs: u64) -> String {
+    format!("{}:{:02}", secs / 3600, secs / 60 % 60)
+}
+
+fn html(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // a Monday, midnight UTC
+    const MON: i64 = 1_699_833_600;
+    const HOUR: i64 = 3600;
+
+    fn entry(id: i64, track: &str, artist: &str, at: i64, listened: u64, skipped: bool) -> Entry {
+        Entry {
+            id,
+            track_id: Some(format!("spotify:track:{}", track)),
+            name: Some(match track {
+                "c" => "Hello, \"World\"".to_string(),
+                track => format!("Song {}", track),
+            }),
+            artist: Some(artist.to_string()),
+            album: Some("LP".to_string()),
+            started_at: from_unix(at),
+            listened: Duration::from_secs(listened),
+            duration: Some(Duration::from_secs(200)),
+            skipped,
+            context: None,
+        }
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            entry(1, "a", "One", MON + 10 * HOUR, 200, false),
+            entry(2, "b", "Two & <Co>", MON + 23 * HOUR + 1800, 30, true),
+            entry(3, "a", "One", MON + DAY + 12 * HOUR, 200, false),
+            entry(4, "c", "Two & <Co>", MON + 2 * DAY + 9 * HOUR, 100, false),
+            entry(5, "d", "One", MON + 7 * DAY + HOUR, 50, false),
+        ]
+    }
+
+    fn build(to: i64, utc_offset: i64) -> Report {
+        let known = ["spotify:track:c".to_string()].iter().cloned().collect();
+        let options = ReportOptions {
+            utc_offset: Some(utc_offset),
+            top: 10,
+        };
+        Report::build(&entries(), &known, from_unix(MON), from_unix(to), &options)
+    }
+
+    fn days(report: &Report) -> Vec<(&str, u64)> {
+        report
+            .days
+            .iter()
+            .map(|d| (d.date.as_str(), d.plays))
+            .collect()
+    }
+
+    #[test]
+    fn totals_and_charts() {
+        let report = build(MON + 7 * DAY, 0);
+
+        assert_eq!((report.plays, report.skipped, report.listened), (4, 1, 530));
+        assert_eq!(report.skip_ratio, Some(0.25));
+        assert_eq!(report.discoveries, 2);
+        assert_eq!(
+            days(&report),
+            vec![("2023-11-13", 2), ("2023-11-14", 1), ("2023-11-15", 1)]
+        );
+        assert_eq!(report.weeks.len(), 1);
+        assert_eq!(report.weeks[0].date, "2023-11-13");
+        assert_eq!(report.heatmap[0][10], 1);
+        assert_eq!(report.heatmap[0][23], 1);
+        assert_eq!(report.heatmap[2][9], 1);
+
+        let tracks: Vec<(&str, u64)> = report
+            .top_tracks
+            .iter()
+            .map(|r| (r.name.as_str(), r.plays))
+            .collect();
+        assert_eq!(tracks, vec![("Song a", 2), ("Hello, \"World\"", 1)]);
+        assert_eq!(report.top_artists[0].name, "One");
+        assert_eq!(report.top_albums.len(), 2);
+    }
+
+    #[test]
+    fn offsets_move_plays_between_days() {
+        let report = build(MON + 7 * DAY, HOUR);
+        assert_eq!(
+            days(&report),
+            vec![("2023-11-13", 1), ("2023-11-14", 2), ("2023-11-15", 1)]
+        );
+        assert_eq!(report.heatmap[1][0], 1);
+        assert_eq!(report.heatmap[0][11], 1);
+    }
+
+    #[test]
+    fn streaks() {
+        let report = build(MON + 7 * DAY, 0);
+        assert_eq!((report.longest_streak, report.current_streak), (3, 0));
+
+        let report = build(MON + 3 * DAY, 0);
+        assert_eq!((report.longest_streak, report.current_streak), (3, 3));
+    }
+
+    #[test]
+    fn outputs() {
+        let report = build(MON + 7 * DAY, 0);
+
+        let csv = report.to_csv(Table::Tracks);
+        assert_eq!(
+            csv,
+            "rank,name,artist,plays,listened\n\
+             1,Song a,One,2,400\n\
+             2,\"Hello, \"\"World\"\"\",Two & <Co>,1,100\n"
+        );
+        let heatmap = report.to_csv(Table::Heatmap);
+        assert_eq!(heatmap.lines().count(), 8);
+        assert!(heatmap.lines().nth(1).unwrap().starts_with("Mon,0,"));
+
+        let html = report.to_html();
+        assert!(html.contains("<p>2023-11-13 – 2023-11-19</p>"), "{}", html);
+        assert!(html.contains("Hello, &quot;World&quot;"));
+        assert!(html.contains("Two &amp; &lt;Co&gt;"));
+        assert!(!html.contains("<Co>"));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["plays"], 4);
+        assert_eq!(json["utc_offset"], 0);
+        assert_eq!(json["days"][0]["date"], "2023-11-13");
+    }
+
+    #[test]
+    fn from_history() {
+        let history = History::open_in_memory().unwrap();
+        for entry in entries() {
+            let mut track = crate::testing::track(entry.track_id.as_deref().unwrap(), 200_000);
+            track.name = entry.name.clone();
+            track.artist = entry.artist.clone();
+            track.album = entry.album.clone();
+            let play = crate::plays::Play {
+                track,
+                started_at: entry.started_at,
+                listened: entry.listened,
+                skipped: entry.skipped,
+            };
+            history.record(&play, None).unwrap();
+        }
+
+        let report = Report::generate(
+            &history,
+            from_unix(MON + 11 * HOUR),
+            from_unix(MON + 7 * DAY),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(report.plays, 3);
+        // track a was played before the report started
+        assert_eq!(report.discoveries, 2);
+    }
+}
@@ -0,0 +1,516 @@
+use crate::clock::Clock;
+use crate::player::Player;
+use crate::snapshot::TrackInfo;
+use crate::state::State;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// OSStatus codes Spotify answers with while launching or waking up
+const PROC_NOT_FOUND: i32 = -600;
+const CONNECTION_INVALID: i32 = -609;
+const AE_TIMEOUT: i32 = -1712;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    // -1712, Spotify took too long to answer
+    Timeout,
+    // -609, the connection went away, e.g. over sleep
+    ConnectionInvalid,
+    // -600, Spotify is not running
+    NotRunning,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn of(err: &Error) -> ErrorClass {
+        match err.raw_os_error() {
+            Some(AE_TIMEOUT) => ErrorClass::Timeout,
+            Some(CONNECTION_INVALID) => ErrorClass::ConnectionInvalid,
+            Some(PROC_NOT_FOUND) => ErrorClass::NotRunning,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::ConnectionInvalid => "connection invalid",
+            ErrorClass::NotRunning => "not running",
+            ErrorClass::Other => "other",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // including the first try
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    // how far each wait may stray either way, as a fraction of it
+    pub jitter: f64,
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retry_on: vec![ErrorClass::Timeout, ErrorClass::ConnectionInvalid],
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Tries once and never again.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_retryable(&self, err: &Error) -> bool {
+        self.retry_on.contains(&ErrorClass::of(err))
+    }
+
+    // How long to wait after `attempt` (from 1) failed, `unit` being a
+    // random number in [0, 1) to spread the wait by.
+    pub fn backoff(&self, attempt: u32, unit: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        let base = (self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_backoff.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+
+        Duration::from_secs_f64((base * (1.0 - jitter + 2.0 * jitter * unit)).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    // failing fast until then
+    Open(Instant),
+    // the cooldown is over and the next call, the only one let through,
+    // decides
+    HalfOpen,
+}
+
+// Stops calling Spotify for a while once it keeps saying it isn't running.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    open_until: Option<Instant>,
+    // a trial call is on its way while half open
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            failures: 0,
+            open_until: None,
+            trial: false,
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BreakerState {
+        match self.open_until {
+            Some(until) if now < until => BreakerState::Open(until),
+            Some(_) => BreakerState::HalfOpen,
+            None => BreakerState::Closed,
+        }
+    }
+
+    // Errs without calling while open, and lets one trial call through once
+    // half open, which has to be recorded before the next.
+    pub fn check(&mut self, now: Instant) -> Result<()> {
+        match self.state(now) {
+            BreakerState::Open(until) => Err(Error::new(
+                ErrorKind::NotConnected,
+                format!(
+                    "Spotify is not running, not trying again for {:.0}s",
+                    until.saturating_duration_since(now).as_secs_f64().ceil()
+                ),
+            )),
+            BreakerState::HalfOpen if self.trial => Err(Error::new(
+                ErrorKind::NotConnected,
+                "Spotify is not running, waiting on a trial call",
+            )),
+            BreakerState::HalfOpen => {
+                self.trial = true;
+                Ok(())
+            }
+            BreakerState::Closed => Ok(()),
+        }
+    }
+
+    pub fn record<T>(&mut self, res: &Result<T>, now: Instant) {
+        self.trial = false;
+        match res.as_ref().err().map(ErrorClass::of) {
+            Some(ErrorClass::NotRunning) => {
+                self.failures += 1;
+                // a failed trial call opens it straight back up
+                if self.failures >= self.threshold || self.open_until.is_some() {
+                    self.open_until = Some(now + self.cooldown);
+                }
+            }
+            // says nothing either way, Spotify may be hanging while it
+            // launches, so a half open breaker waits for the next call
+            Some(ErrorClass::Timeout) => {}
+            // anything else means Spotify answered
+            _ => {
+                self.failures = 0;
+                self.open_until = None;
+            }
+        }
+    }
+}
+
+// Retries getters and setters that are safe to repeat, and fails every call
+// fast while the breaker is open. Toggles and skips are tried once.
+//
+// Setters and commands go out with kAENoReply, so they never time out and
+// only fail before Spotify got them, when it isn't running or the connection
+// is gone. Retrying them can't apply one twice.
+pub struct Retrying<P, C> {
+    inner: P,
+    clock: C,
+    policy: RetryPolicy,
+    breaker: Option<Mutex<CircuitBreaker>>,
+    seed: AtomicU64,
+}
+
+impl<P: Player, C: Clock> Retrying<P, C> {
+    pub fn new(inner: P, clock: C) -> Retrying<P, C> {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0x2545_f491, |d| d.as_nanos() as u64)
+            | 1;
+
+        Retrying {
+            inner,
+            clock,
+            policy: Default::default(),
+            breaker: None,
+            seed: AtomicU64::new(seed),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Retrying<P, C> {
+        self.policy = policy;
+        self
+    }
+
+    // Fails fast for `cooldown` after `threshold` not-running errors in a row.
+    pub fn with_breaker(mut self, threshold: u32, cooldown: Duration) -> Retrying<P, C> {
+        self.breaker = Some(Mutex::new(CircuitBreaker::new(threshold, cooldown)));
+        self
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        match &self.breaker {
+            Some(breaker) => breaker.lock().unwrap().state(self.clock.now()),
+            None => BreakerState::Closed,
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn unit(&self) -> f64 {
+        // xorshift, only spreading out waits
+        let mut x = self.seed.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed.store(x, Ordering::Relaxed);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn call<T, F: Fn(&P) -> Result<T>>(&self, retry: bool, f: F) -> Result<T> {
+        let mut attempt = 1;
+
+        loop {
+            if let Some(breaker) = &self.breaker {
+                breaker.lock().unwrap().check(self.clock.now())?;
+            }
+
+            let res = f(&self.inner);
+            if let Some(breaker) = &self.breaker {
+                breaker.lock().unwrap().record(&res, self.clock.now());
+            }
+
+            match res {
+                Err(err)
+                    if retry
+                        && attempt < self.policy.max_attempts
+                        && self.policy.is_retryable(&err) =>
+                {
+                    self.clock.sleep(self.policy.backoff(attempt, self.unit()));
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<P: Player, C: Clock> Player for Retrying<P, C> {
+    fn state(&self) -> Result<Option<State>> {
+        self.call(true, |p| p.state())
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        self.call(true, |p| p.is_shuffling())
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        self.call(true, |p| p.set_shuffling(is_it))
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        self.call(true, |p| p.is_repeating())
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        self.call(true, |p| p.set_repeating(is_it))
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        self.call(true, |p| p.position())
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        self.call(true, |p| p.set_position(pos))
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        self.call(true, |p| p.volume())
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        self.call(true, |p| p.set_volume(vol))
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        self.call(true, |p| p.track())
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        self.call(false, |p| p.play_pause())
+    }
+
+    fn play(&self) -> Result<()> {
+        self.call(true, |p| p.play())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.call(true, |p| p.pause())
+    }
+
+    fn next(&self) -> Result<()> {
+        self.call(false, |p| p.next())
+    }
+
+    fn previous(&self) -> Result<()> {
+        self.call(false, |p| p.previous())
+    }
+
+    // a retry would restart the track if the first try got through after all
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        self.call(false, |p| p.play_track(track.clone(), context.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::testing::FakePlayer;
+
+    fn retrying<'a>(
+        player: &'a FakePlayer,
+        clock: &VirtualClock,
+    ) -> Retrying<&'a FakePlayer, VirtualClock> {
+        Retrying::new(player, clock.clone()).with_breaker(2, Duration::from_secs(30))
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1, 0.5), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2, 0.5), Duration::from_millis(200));
+        assert_eq!(policy.backoff(10, 0.5), Duration::from_secs(2));
+        assert_eq!(policy.backoff(u32::MAX, 0.5), Duration::from_secs(2));
+
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1, 0.0), Duration::from_millis(80));
+        assert!(policy.backoff(1, 0.999) < Duration::from_millis(120));
+    }
+
+    #[test]
+    fn retries_getters_and_setters_that_failed_to_reach_spotify() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let retrying = retrying(&player, &clock);
+
+        player.fail("volume", &[AE_TIMEOUT, CONNECTION_INVALID]);
+        assert_eq!(retrying.volume().unwrap(), Some(50));
+        assert_eq!(player.count("volume"), 3);
+        // 100ms then 200ms, give or take a fifth
+        assert!(
+            clock.elapsed() >= Duration::from_millis(240),
+            "{:?}",
+            clock.elapsed()
+        );
+        assert!(
+            clock.elapsed() <= Duration::from_millis(360),
+            "{:?}",
+            clock.elapsed()
+        );
+
+        player.fail("set_volume", &[CONNECTION_INVALID]);
+        retrying.set_volume(30).unwrap();
+        assert_eq!(player.count("set_volume"), 2);
+        assert_eq!(player.get().volume, Some(30));
+        assert_eq!(retrying.breaker_state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn gives_up_on_the_last_attempt_and_other_errors() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let retrying = Retrying::new(&player, clock.clone());
+
+        player.fail("state", &[AE_TIMEOUT, AE_TIMEOUT, AE_TIMEOUT, AE_TIMEOUT]);
+        let err = retrying.state().unwrap_err();
+        assert_eq!(ErrorClass::of(&err), ErrorClass::Timeout);
+        assert_eq!(player.count("state"), 3);
+        // a fresh call gets its own attempts
+        assert_eq!(retrying.state().unwrap(), Some(State::PLAYING));
+        assert_eq!(player.count("state"), 5);
+
+        player.fail("position", &[-1728]);
+        assert_eq!(
+            ErrorClass::of(&retrying.position().unwrap_err()),
+            ErrorClass::Other
+        );
+        assert_eq!(player.count("position"), 1);
+
+        let once = Retrying::new(&player, clock).with_policy(RetryPolicy::none());
+        player.fail("track", &[AE_TIMEOUT]);
+        assert!(once.track().is_err());
+        assert_eq!(player.count("track"), 1);
+    }
+
+    #[test]
+    fn tries_toggles_and_skips_once() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let retrying = Retrying::new(&player, clock.clone());
+
+        player.fail("play_pause", &[CONNECTION_INVALID]);
+        player.fail("next", &[CONNECTION_INVALID]);
+        assert!(retrying.play_pause().is_err());
+        assert!(retrying.next().is_err());
+        assert_eq!(player.count("play_pause"), 1);
+        assert_eq!(player.count("next"), 1);
+        assert_eq!(clock.elapsed(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn fails_fast_while_spotify_is_not_running() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let retrying = retrying(&player, &clock);
+
+        player.fail("state", &[PROC_NOT_FOUND, PROC_NOT_FOUND]);
+        assert!(retrying.state().is_err());
+        assert_eq!(retrying.breaker_state(), BreakerState::Closed);
+        assert!(retrying.state().is_err());
+        assert!(matches!(retrying.breaker_state(), BreakerState::Open(_)));
+
+        let err = retrying.volume().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert_eq!(player.count("volume"), 0);
+
+        // a failed trial call opens it for another cooldown
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(retrying.breaker_state(), BreakerState::HalfOpen);
+        player.fail("volume", &[PROC_NOT_FOUND]);
+        assert!(retrying.volume().is_err());
+        assert_eq!(player.count("volume"), 1);
+        assert!(matches!(retrying.breaker_state(), BreakerState::Open(_)));
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(retrying.volume().unwrap(), Some(50));
+        assert_eq!(retrying.breaker_state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn lets_a_single_trial_call_through() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+        let not_running: Result<()> = Err(Error::from_raw_os_error(PROC_NOT_FOUND));
+
+        breaker.record(&not_running, start);
+        assert!(breaker.check(start).is_err());
+
+        let later = start + Duration::from_secs(10);
+        breaker.check(later).unwrap();
+        let err = breaker.check(later).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+        breaker.record(&Ok(()), later);
+        assert_eq!(breaker.state(later), BreakerState::Closed);
+        breaker.check(later).unwrap();
+        breaker.check(later).unwrap();
+    }
+
+    #[test]
+    fn timeouts_leave_the_breaker_alone() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        let not_running: Result<()> = Err(Error::from_raw_os_error(PROC_NOT_FOUND));
+        let timeout: Result<()> = Err(Error::from_raw_os_error(AE_TIMEOUT));
+
+        breaker.record(&not_running, start);
+        breaker.record(&timeout, start);
+        breaker.record(&not_running, start);
+        assert!(matches!(breaker.state(start), BreakerState::Open(_)));
+
+        // a trial that times out neither closes nor reopens it
+        let later = start + Duration::from_secs(10);
+        breaker.check(later).unwrap();
+        breaker.record(&timeout, later);
+        assert_eq!(breaker.state(later), BreakerState::HalfOpen);
+        breaker.check(later).unwrap();
+    }
+}
@@ -0,0 +1,800 @@
+use crate::player::Player;
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::state::State;
+use crate::watch::{self, PlayerEvent};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const PLAYER_ERROR: i64 = -32000;
+
+const NOTIFICATION: &str = "player.changed";
+// lines a client may fall behind by before it's dropped
+const CLIENT_BUFFER: usize = 64;
+
+pub fn default_socket_path() -> PathBuf {
+    std::env::temp_dir().join("macos-spotify.sock")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn data(&self) -> Option<&Value> {
+        self.data.as_ref()
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> RpcError {
+        RpcError {
+            code: PLAYER_ERROR,
+            message: err.to_string(),
+            data: err.raw_os_error().map(|code| json!({ "os_error": code })),
+        }
+    }
+}
+
+impl From<RpcError> for Error {
+    fn from(err: RpcError) -> Error {
+        let os_error = err
+            .data
+            .as_ref()
+            .and_then(|data| data.get("os_error"))
+            .and_then(Value::as_i64);
+
+        match os_error {
+            Some(code) => Error::from_raw_os_error(code as i32),
+            None => Error::other(err),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JSON-RPC error {}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+fn response(id: Value, res: std::result::Result<Value, RpcError>) -> Value {
+    match res {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+fn param<T: DeserializeOwned>(
+    params: &Value,
+    name: &str,
+    idx: usize,
+) -> std::result::Result<T, RpcError> {
+    let value = match params {
+        Value::Object(map) => map.get(name).cloned().unwrap_or(Value::Null),
+        Value::Array(list) => list.get(idx).cloned().unwrap_or(Value::Null),
+        _ => Value::Null,
+    };
+
+    serde_json::from_value(value)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, &format!("`{}`: {}", name, err)))
+}
+
+fn to_value<T: Serialize>(res: Result<T>) -> std::result::Result<Value, RpcError> {
+    Ok(json!(res?))
+}
+
+fn dispatch<P: Player>(
+    player: &P,
+    method: &str,
+    params: &Value,
+    subscribed: &AtomicBool,
+) -> std::result::Result<Value, RpcError> {
+    match method {
+        "player.snapshot" => to_value(player.snapshot()),
+        "player.state" => to_value(player.state()),
+        "player.position" => to_value(player.position()),
+        "player.set_position" => match param(params, "position", 0)? {
+            position if position >= 0.0 => to_value(player.set_position(position)),
+            _ => Err(RpcError::new(
+                INVALID_PARAMS,
+                "`position` must not be negative",
+            )),
+        },
+        "player.volume" => to_value(player.volume()),
+        "player.set_volume" => match param(params, "volume", 0)? {
+            volume if (0..=100).contains(&volume) => to_value(player.set_volume(volume)),
+            _ => Err(RpcError::new(
+                INVALID_PARAMS,
+                "`volume` must be between 0 and 100",
+            )),
+        },
+        "player.is_shuffling" => to_value(player.is_shuffling()),
+        "player.set_shuffling" => to_value(player.set_shuffling(param(params, "shuffling", 0)?)),
+        "player.is_repeating" => to_value(player.is_repeating()),
+        "player.set_repeating" => to_value(player.set_repeating(param(params, "repeating", 0)?)),
+        "player.play" => to_value(player.play()),
+        "player.pause" => to_value(player.pause()),
+        "player.play_pause" => to_value(player.play_pause()),
+        "player.next" => to_value(player.next()),
+        "player.previous" => to_value(player.previous()),
+        "player.play_track" => {
+            to_value(player.play_track(param(params, "track", 0)?, param(params, "context", 1)?))
+        }
+        "track.get" => to_value(player.track()),
+        "events.subscribe" => {
+            subscribed.store(true, Ordering::SeqCst);
+            Ok(Value::Null)
+        }
+        "events.unsubscribe" => {
+            subscribed.store(false, Ordering::SeqCst);
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+fn handle<P: Player>(player: &P, request: Value, subscribed: &AtomicBool) -> Option<Value> {
+    // `"id": null` is still a request, only leaving it out makes a notification
+    let id = request.get("id").cloned();
+    let request: Request = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(err) => {
+            return Some(response(
+                Value::Null,
+                Err(RpcError::new(INVALID_REQUEST, &err.to_string())),
+            ))
+        }
+    };
+
+    let res = if request.jsonrpc != "2.0" {
+        Err(RpcError::new(
+            INVALID_REQUEST,
+            "Only JSON-RPC 2.0 is supported",
+        ))
+    } else {
+        dispatch(player, &request.method, &request.params, subscribed)
+    };
+
+    // requests without an id are notifications and get no response
+    id.map(|id| response(id, res))
+}
+
+fn handle_line<P: Player>(player: &P, line: &str, subscribed: &AtomicBool) -> Option<Value> {
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::Array(batch)) if !batch.is_empty() => {
+            let responses: Vec<Value> = batch
+                .into_iter()
+                .filter_map(|request| handle(player, request, subscribed))
+                .collect();
+
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        Ok(Value::Array(_)) => Some(response(
+            Value::Null,
+            Err(RpcError::new(INVALID_REQUEST, "Empty batch")),
+        )),
+        Ok(request) => handle(player, request, subscribed),
+        Err(err) => Some(response(
+            Value::Null,
+            Err(RpcError::new(PARSE_ERROR, &err.to_string())),
+        )),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn write_line(stream: &Mutex<UnixStream>, value: &Value) -> Result<()> {
+    let mut line = value.to_string();
+    line.push('\n');
+
+    let mut stream = lock(stream);
+    stream.write_all(line.as_bytes())?;
+    stream.flush()
+}
+
+fn notification(event: &PlayerEvent) -> String {
+    json!({ "jsonrpc": "2.0", "method": NOTIFICATION, "params": event }).to_string()
+}
+
+struct Connection {
+    tx: SyncSender<String>,
+    stream: UnixStream,
+    subscribed: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+}
+
+fn notify(connections: &Mutex<Vec<Connection>>, event: &PlayerEvent) {
+    let line = notification(event);
+
+    lock(connections).retain(|conn| {
+        if conn.closed.load(Ordering::SeqCst) {
+            return false;
+        }
+        if !conn.subscribed.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        match conn.tx.try_send(line.clone()) {
+            Ok(()) => true,
+            // a client that stopped reading is hung up on instead of waited for
+            Err(_) => {
+                let _ = conn.stream.shutdown(std::net::Shutdown::Both);
+                false
+            }
+        }
+    });
+}
+
+pub struct RpcServer<P> {
+    listener: UnixListener,
+    path: PathBuf,
+    player: Arc<Mutex<P>>,
+    poll_interval: Duration,
+    connections: Arc<Mutex<Vec<Connection>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl<P: Player + Send + 'static> RpcServer<P> {
+    pub fn bind<T: AsRef<Path>>(
+        path: T,
+        player: P,
+        poll_interval: Duration,
+    ) -> Result<RpcServer<P>> {
+        let path = path.as_ref();
+
+        // a socket file nobody is listening on is a leftover of a dead daemon
+        if path.exists() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(RpcServer {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+            player: Arc::new(Mutex::new(player)),
+            poll_interval,
+            connections: Arc::new(Mutex::new(Vec::new())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let connections = self.connections.clone();
+        let active = self.connections.clone();
+        let watcher = watch::spawn(
+            self.player.clone(),
+            self.poll_interval,
+            self.stopped.clone(),
+            move || {
+                lock(&active)
+                    .iter()
+                    .any(|c| c.subscribed.load(Ordering::SeqCst))
+            },
+            move |event| notify(&connections, &event),
+        );
+
+        for stream in self.listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if let Ok(stream) = stream {
+                let _ = self.accept(stream);
+            }
+        }
+
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = watcher.join();
+        Ok(())
+    }
+
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop
+        let _ = UnixStream::connect(&self.path);
+    }
+
+    fn accept(&self, stream: UnixStream) -> Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream.try_clone()?;
+        let (tx, rx) = sync_channel::<String>(CLIENT_BUFFER);
+        let subscribed = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let player = self.player.clone();
+
+        {
+            let mut connections = lock(&self.connections);
+            connections.retain(|conn| !conn.closed.load(Ordering::SeqCst));
+            connections.push(Connection {
+                tx: tx.clone(),
+                stream: stream.try_clone()?,
+                subscribed: subscribed.clone(),
+                closed: closed.clone(),
+            });
+        }
+
+        // replies and notifications go out in order from here, so one slow
+        // client never holds up the others
+        thread::spawn(move || {
+            for mut line in rx {
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown(std::net::Shutdown::Both);
+        });
+
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let was_subscribed = subscribed.load(Ordering::SeqCst);
+                if let Some(res) = handle_line(&*player, &line, &subscribed) {
+                    if tx.send(res.to_string()).is_err() {
+                        break;
+                    }
+                }
+
+                // the watcher only reports changes, so new subscribers are
+                // told where things stand first
+                if !was_subscribed && subscribed.load(Ordering::SeqCst) {
+                    let snapshot = player.snapshot().unwrap_or_default();
+                    let sent = watch::diff(&Default::default(), &snapshot, Duration::from_secs(0))
+                        .iter()
+                        .try_for_each(|event| tx.send(notification(event)));
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            closed.store(true, Ordering::SeqCst);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        });
+
+        Ok(())
+    }
+}
+
+impl<P> Drop for RpcServer<P> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u64, Sender<std::result::Result<Value, RpcError>>>>>;
+
+pub struct RpcClient {
+    writer: Mutex<UnixStream>,
+    pending: Pending,
+    events: Arc<Mutex<Option<Sender<PlayerEvent>>>>,
+    closed: Arc<AtomicBool>,
+    next_id: AtomicU64,
+}
+
+impl RpcClient {
+    pub fn connect<T: AsRef<Path>>(path: T) -> Result<RpcClient> {
+        let stream = UnixStream::connect(path)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let events: Arc<Mutex<Option<Sender<PlayerEvent>>>> = Arc::new(Mutex::new(None));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        {
+            let pending = pending.clone();
+            let events = events.clone();
+            let closed = closed.clone();
+
+            thread::spawn(move || {
+                for line in reader.lines() {
+                    match line {
+                        Ok(line) => dispatch_reply(&line, &pending, &events),
+                        Err(_) => break,
+                    }
+                }
+
+                // wakes up every caller still waiting for a reply
+                closed.store(true, Ordering::SeqCst);
+                if let Ok(mut pending) = pending.lock() {
+                    pending.clear();
+                }
+            });
+        }
+
+        Ok(RpcClient {
+            writer: Mutex::new(stream),
+            pending,
+            events,
+            closed,
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    pub fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = channel();
+
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(id, tx);
+        }
+
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id });
+        let sent = if self.closed.load(Ordering::SeqCst) {
+            Err(Error::new(
+                ErrorKind::NotConnected,
+                "Connection to the daemon was closed",
+            ))
+        } else {
+            write_line(&self.writer, &request)
+        };
+
+        if let Err(err) = sent {
+            if let Ok(mut pending) = self.pending.lock() {
+                pending.remove(&id);
+            }
+            return Err(err);
+        }
+
+        match rx.recv() {
+            Ok(Ok(value)) => {
+                serde_json::from_value(value).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+            }
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "Connection to the daemon was closed",
+            )),
+        }
+    }
+
+    pub fn subscribe(&self) -> Result<Receiver<PlayerEvent>> {
+        let (tx, rx) = channel();
+
+        if let Ok(mut events) = self.events.lock() {
+            *events = Some(tx);
+        }
+
+        self.call::<Value>("events.subscribe", Value::Null)?;
+        Ok(rx)
+    }
+
+    pub fn unsubscribe(&self) -> Result<()> {
+        self.call::<Value>("events.unsubscribe", Value::Null)?;
+
+        if let Ok(mut events) = self.events.lock() {
+            *events = None;
+        }
+
+        Ok(())
+    }
+}
+
+fn dispatch_reply(
+    line: &str,
+    pending: &Mutex<HashMap<u64, Sender<std::result::Result<Value, RpcError>>>>,
+    events: &Mutex<Option<Sender<PlayerEvent>>>,
+) {
+    let mut message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+
+    if message.get("method").and_then(Value::as_str) == Some(NOTIFICATION) {
+        let event = serde_json::from_value(message["params"].take());
+
+        if let (Ok(event), Ok(events)) = (event, events.lock()) {
+            if let Some(tx) = events.as_ref() {
+                let _ = tx.send(event);
+            }
+        }
+
+        return;
+    }
+
+    let id = match message.get("id").and_then(Value::as_u64) {
+        Some(id) => id,
+        None => return,
+    };
+
+    let res = match message.get_mut("error") {
+        Some(error) => Err(serde_json::from_value(error.take())
+            .unwrap_or_else(|_| RpcError::new(PLAYER_ERROR, "Malformed error"))),
+        None => Ok(message["result"].take()),
+    };
+
+    if let Some(tx) = pending.lock().ok().and_then(|mut p| p.remove(&id)) {
+        let _ = tx.send(res);
+    }
+}
+
+impl Player for RpcClient {
+    fn state(&self) -> Result<Option<State>> {
+        self.call("player.state", Value::Null)
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        self.call("player.is_shuffling", Value::Null)
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        self.call("player.set_shuffling", json!({ "shuffling": is_it }))
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        self.call("player.is_repeating", Value::Null)
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        self.call("player.set_repeating", json!({ "repeating": is_it }))
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        self.call("player.position", Value::Null)
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        self.call("player.set_position", json!({ "position": pos }))
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        self.call("player.volume", Value::Null)
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        self.call("player.set_volume", json!({ "volume": vol }))
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        self.call("track.get", Value::Null)
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        self.call("player.play_pause", Value::Null)
+    }
+
+    fn play(&self) -> Result<()> {
+        self.call("player.play", Value::Null)
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.call("player.pause", Value::Null)
+    }
+
+    fn next(&self) -> Result<()> {
+        self.call("player.next", Value::Null)
+    }
+
+    fn previous(&self) -> Result<()> {
+        self.call("player.previous", Value::Null)
+    }
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        self.call(
+            "player.play_track",
+            json!({ "track": track, "context": context }),
+        )
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        self.call("player.snapshot", Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakePlayer;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    fn call(fake: &FakePlayer, line: &str) -> Option<Value> {
+        handle_line(fake, line, &AtomicBool::new(false))
+    }
+
+    fn start(name: &str) -> (Arc<RpcServer<Arc<FakePlayer>>>, Arc<FakePlayer>) {
+        let path = std::env::temp_dir().join(format!(
+            "macos-spotify-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        let fake = Arc::new(FakePlayer::new());
+        let server =
+            Arc::new(RpcServer::bind(&path, fake.clone(), Duration::from_millis(5)).unwrap());
+        let running = server.clone();
+        thread::spawn(move || running.run());
+        (server, fake)
+    }
+
+    fn next_event(rx: &Receiver<PlayerEvent>) -> PlayerEvent {
+        rx.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn requests_and_notifications() {
+        let fake = FakePlayer::new();
+
+        let res = call(
+            &fake,
+            r#"{"jsonrpc":"2.0","method":"player.volume","id":null}"#,
+        );
+        assert_eq!(
+            res,
+            Some(json!({ "jsonrpc": "2.0", "result": 50, "id": null }))
+        );
+
+        let res = call(&fake, r#"{"jsonrpc":"2.0","method":"player.next"}"#);
+        assert_eq!(res, None);
+        assert_eq!(fake.count("next"), 1);
+
+        let res = call(
+            &fake,
+            r#"[{"jsonrpc":"2.0","method":"player.pause"},
+                {"jsonrpc":"2.0","method":"player.state","id":"a"}]"#,
+        );
+        assert_eq!(
+            res,
+            Some(json!([{ "jsonrpc": "2.0", "result": "paused", "id": "a" }]))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let fake = FakePlayer::new();
+        let code = |line: &str| call(&fake, line).unwrap()["error"]["code"].as_i64();
+
+        assert_eq!(code("{"), Some(PARSE_ERROR));
+        assert_eq!(code("[]"), Some(INVALID_REQUEST));
+        assert_eq!(
+            code(r#"{"jsonrpc":"1.0","method":"player.play","id":1}"#),
+            Some(INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"player.nope","id":1}"#),
+            Some(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"player.set_volume","params":[101],"id":1}"#),
+            Some(INVALID_PARAMS)
+        );
+        assert_eq!(
+            code(
+                r#"{"jsonrpc":"2.0","method":"player.set_position","params":{"position":-1},"id":1}"#
+            ),
+            Some(INVALID_PARAMS)
+        );
+        assert_eq!(fake.count("set_volume") + fake.count("set_position"), 0);
+
+        fake.fail("play", &[libc::EBUSY]);
+        let res = call(&fake, r#"{"jsonrpc":"2.0","method":"player.play","id":1}"#).unwrap();
+        assert_eq!(res["error"]["code"], PLAYER_ERROR);
+        assert_eq!(res["error"]["data"]["os_error"], libc::EBUSY);
+    }
+
+    #[test]
+    fn client_round_trip() {
+        let (server, fake) = start("round-trip");
+        let client = RpcClient::connect(server.path()).unwrap();
+
+        assert_eq!(client.snapshot().unwrap(), fake.get());
+        client.set_volume(30).unwrap();
+        assert_eq!(fake.get().volume, Some(30));
+
+        fake.fail("next", &[libc::EBUSY]);
+        let err = client.next().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        let err = client.set_volume(200).unwrap_err();
+        assert!(err.to_string().contains("between 0 and 100"), "{}", err);
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn every_subscriber_starts_from_the_current_state() {
+        let (server, fake) = start("subscribers");
+
+        let first = RpcClient::connect(server.path()).unwrap();
+        let first_rx = first.subscribe().unwrap();
+        assert_eq!(
+            next_event(&first_rx),
+            PlayerEvent::TrackChanged(fake.get().track)
+        );
+        assert_eq!(
+            next_event(&first_rx),
+            PlayerEvent::StateChanged(Some(State::PLAYING))
+        );
+
+        // the watcher is already running for the first one
+        while fake.count("state") < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let second = RpcClient::connect(server.path()).unwrap();
+        let second_rx = second.subscribe().unwrap();
+        assert_eq!(
+            next_event(&second_rx),
+            PlayerEvent::TrackChanged(fake.get().track)
+        );
+
+        while first_rx.try_recv().is_ok() {}
+        while second_rx.try_recv().is_ok() {}
+        fake.set(|s| s.volume = Some(10));
+        for rx in &[&first_rx, &second_rx] {
+            loop {
+                if next_event(rx) == PlayerEvent::VolumeChanged(Some(10)) {
+                    break;
+                }
+            }
+        }
+
+        second.unsubscribe().unwrap();
+        fake.set(|s| s.volume = Some(20));
+        while next_event(&first_rx) != PlayerEvent::VolumeChanged(Some(20)) {}
+        assert_eq!(
+            second_rx.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+
+        server.shutdown();
+    }
+}
@@ -0,0 +1,509 @@
+use crate::alarm::Weekdays;
+use crate::calendar::{unix, utc_offset, weekday, DAY};
+use crate::player::Player;
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::state::State;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Skip,
+    Previous,
+    Play,
+    Pause,
+    Volume(i32),
+    // lowers the volume to at most this for as long as the rule matches
+    CapVolume(i32),
+    Shuffle(bool),
+    Repeat(bool),
+    PlayTrack(String),
+}
+
+impl Action {
+    // Whether the action would change anything, as far as the snapshot shows.
+    fn needed(&self, snapshot: &Snapshot) -> bool {
+        match self {
+            Action::Play => snapshot.state != Some(State::PLAYING),
+            Action::Pause => snapshot.state == Some(State::PLAYING),
+            Action::Volume(v) => snapshot.volume != Some(*v),
+            Action::CapVolume(cap) => snapshot.volume.is_some_and(|v| v > *cap),
+            Action::Shuffle(on) => snapshot.shuffling != Some(*on),
+            Action::Repeat(on) => snapshot.repeating != Some(*on),
+            Action::Skip | Action::Previous | Action::PlayTrack(_) => true,
+        }
+    }
+
+    fn run<P: Player>(&self, player: &P) -> Result<()> {
+        match self {
+            Action::Skip => player.next(),
+            Action::Previous => player.previous(),
+            Action::Play => player.play(),
+            Action::Pause => player.pause(),
+            Action::Volume(v) | Action::CapVolume(v) => player.set_volume(*v),
+            Action::Shuffle(on) => player.set_shuffling(*on),
+            Action::Repeat(on) => player.set_repeating(*on),
+            Action::PlayTrack(uri) => player.play_track(uri.clone(), None),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Skip => write!(f, "skip"),
+            Action::Previous => write!(f, "previous"),
+            Action::Play => write!(f, "play"),
+            Action::Pause => write!(f, "pause"),
+            Action::Volume(v) => write!(f, "volume {}", v),
+            Action::CapVolume(v) => write!(f, "cap volume at {}", v),
+            Action::Shuffle(on) => write!(f, "shuffle {}", if *on { "on" } else { "off" }),
+            Action::Repeat(on) => write!(f, "repeat {}", if *on { "on" } else { "off" }),
+            Action::PlayTrack(uri) => write!(f, "play {}", uri),
+        }
+    }
+}
+
+// Every condition given has to hold; text is compared ignoring case.
+// Spotify's scripting interface does not say whether a track is explicit, so
+// there is no condition for it; match such tracks by uri, track or album.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Conditions {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<String>,
+    pub uri: Option<String>,
+    pub podcast: Option<bool>,
+    pub state: Option<String>,
+    // local "HH:MM", a window may wrap past midnight
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub days: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    #[serde(default)]
+    pub when: Conditions,
+    pub then: Vec<Action>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RulesConfig {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<RuleConfig>,
+}
+
+fn invalid(rule: &str, message: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("rule {:?}: {}", rule, message),
+    )
+}
+
+fn minute_of_day(rule: &str, time: &str) -> Result<u32> {
+    let mut hm = time.splitn(2, ':').map(|n| n.parse::<u32>().ok());
+    match (hm.next().flatten(), hm.next().flatten()) {
+        (Some(h), Some(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        _ => Err(invalid(rule, format!("expected HH:MM, got {:?}", time))),
+    }
+}
+
+fn same(expected: &Option<String>, actual: &Option<String>) -> bool {
+    match (expected, actual) {
+        (None, _) => true,
+        (Some(expected), Some(actual)) => expected.to_lowercase() == actual.to_lowercase(),
+        (Some(_), None) => false,
+    }
+}
+
+fn is_episode(track: &TrackInfo) -> bool {
+    track
+        .spotify_url
+        .as_ref()
+        .is_some_and(|url| url.starts_with("spotify:episode:"))
+}
+
+struct Rule {
+    config: RuleConfig,
+    state: Option<State>,
+    after: Option<u32>,
+    before: Option<u32>,
+    days: Weekdays,
+    // the track the rule last fired for, while it keeps matching
+    fired: Option<Option<String>>,
+}
+
+impl Rule {
+    fn compile(config: RuleConfig) -> Result<Rule> {
+        let name = config.name.as_str();
+        let when = &config.when;
+
+        if config.then.is_empty() {
+            return Err(invalid(name, "no actions".to_string()));
+        }
+        for action in &config.then {
+            if let Action::Volume(v) | Action::CapVolume(v) = action {
+                if !(0..=100).contains(v) {
+                    return Err(invalid(name, format!("volume {} is not in 0-100", v)));
+                }
+            }
+        }
+
+        let state = match when.state.as_deref() {
+            None => None,
+            Some("playing") => Some(State::PLAYING),
+            Some("paused") => Some(State::PAUSED),
+            Some("stopped") => Some(State::STOPPED),
+            Some(other) => return Err(invalid(name, format!("unknown state {:?}", other))),
+        };
+        let days = match &when.days {
+            Some(days) => days
+                .parse()
+                .map_err(|err: Error| invalid(name, err.to_string()))?,
+            None => Weekdays::ALL,
+        };
+
+        Ok(Rule {
+            state,
+            after: when
+                .after
+                .as_deref()
+                .map(|t| minute_of_day(name, t))
+                .transpose()?,
+            before: when
+                .before
+                .as_deref()
+                .map(|t| minute_of_day(name, t))
+                .transpose()?,
+            days,
+            fired: None,
+            config,
+        })
+    }
+
+    fn matches(&self, snapshot: &Snapshot, local: i64) -> bool {
+        let when = &self.config.when;
+        let day = local.div_euclid(DAY);
+        let minute = (local.rem_euclid(DAY) / 60) as u32;
+
+        if self.state.is_some() && self.state != snapshot.state {
+            return false;
+        }
+
+        let in_window = match (self.after, self.before) {
+            (Some(after), Some(before)) if after > before => minute >= after || minute < before,
+            (after, before) => {
+                !matches!(after, Some(a) if minute < a) && !matches!(before, Some(b) if minute >= b)
+            }
+        };
+        if !in_window || !self.days.contains(weekday(day)) {
+            return false;
+        }
+
+        let has_track_conditions = when.artist.is_some()
+            || when.album.is_some()
+            || when.album_artist.is_some()
+            || when.track.is_some()
+            || when.uri.is_some()
+            || when.podcast.is_some();
+
+        match &snapshot.track {
+            Some(track) => {
+                same(&when.artist, &track.artist)
+                    && same(&when.album, &track.album)
+                    && same(&when.album_artist, &track.album_artist)
+                    && same(&when.track, &track.name)
+                    && same(&when.uri, &track.spotify_url)
+                    && !matches!(when.podcast, Some(p) if p != is_episode(track))
+            }
+            None => !has_track_conditions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fired {
+    pub rule: String,
+    pub action: Action,
+    // false in a dry run
+    pub applied: bool,
+}
+
+pub struct Rules<P> {
+    player: P,
+    rules: Vec<Rule>,
+    dry_run: bool,
+    utc_offset: Option<i64>,
+}
+
+impl<P: Player> Rules<P> {
+    pub fn new(player: P, config: RulesConfig) -> Result<Rules<P>> {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(Rule::compile)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Rules {
+            player,
+            rules,
+            dry_run: false,
+            utc_offset: None,
+        })
+    }
+
+    pub fn from_toml(player: P, toml: &str) -> Result<Rules<P>> {
+        let config = toml::from_str(toml).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        Rules::new(player, config)
+    }
+
+    pub fn load<T: AsRef<Path>>(player: P, path: T) -> Result<Rules<P>> {
+        Rules::from_toml(player, &fs::read_to_string(path)?)
+    }
+
+    // Reports what would be done without touching the player.
+    pub fn dry_run(mut self, dry_run: bool) -> Rules<P> {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // Seconds east of UTC for time windows, instead of the system time zone.
+    pub fn with_utc_offset(mut self, utc_offset: i64) -> Rules<P> {
+        self.utc_offset = Some(utc_offset);
+        self
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn poll(&mut self) -> Result<Vec<Fired>> {
+        let snapshot = self.player.snapshot()?;
+        self.update(&snapshot, SystemTime::now())
+    }
+
+    // Runs the actions of every rule that started matching, or kept matching
+    // into a new track. Volume caps are held for as long as a rule matches.
+    pub fn update(&mut self, snapshot: &Snapshot, at: SystemTime) -> Result<Vec<Fired>> {
+        let offset = self.utc_offset.unwrap_or_else(|| utc_offset(at));
+        let local = unix(at) + offset;
+        let track = snapshot.track.as_ref().and_then(|t| t.spotify_url.clone());
+        let mut fired = Vec::new();
+
+        for rule in &mut self.rules {
+            if !rule.matches(snapshot, local) {
+                rule.fired = None;
+                continue;
+            }
+
+            let fresh = rule.fired.as_ref() != Some(&track);
+            rule.fired = Some(track.clone());
+
+            for action in &rule.config.then {
+                let holds = matches!(action, Action::CapVolume(_));
+                if !(fresh || holds) || !action.needed(snapshot) {
+                    continue;
+                }
+
+                if !self.dry_run {
+                    action.run(&self.player)?;
+                }
+                fired.push(Fired {
+                    rule: rule.config.name.clone(),
+                    action: action.clone(),
+                    applied: !self.dry_run,
+                });
+            }
+        }
+
+        Ok(fired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{track, FakePlayer};
+    use std::time::Duration;
+
+    const HOUR: u64 = 3600;
+
+    // the epoch was a Thursday
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn playing(uri: &str, artist: &str) -> Snapshot {
+        let mut snapshot = FakePlayer::new().get();
+        let mut info = track(uri, 200_000);
+        info.artist = Some(artist.to_string());
+        snapshot.track = Some(info);
+        snapshot
+    }
+
+    fn rules<'a>(fake: &'a FakePlayer, toml: &str) -> Rules<&'a FakePlayer> {
+        Rules::from_toml(fake, toml).unwrap().with_utc_offset(0)
+    }
+
+    fn actions(fired: Vec<Fired>) -> Vec<Action> {
+        fired.into_iter().map(|f| f.action).collect()
+    }
+
+    #[test]
+    fn skips_an_artist_once_per_track() {
+        let fake = FakePlayer::new();
+        let mut rules = rules(
+            &fake,
+            "[[rule]]\nname = \"no band\"\nwhen = { artist = \"band\" }\nthen = [\"skip\"]\n",
+        );
+
+        let stream = [
+            (playing("spotify:track:a", "Band"), vec![Action::Skip]),
+            (playing("spotify:track:a", "Band"), vec![]),
+            (playing("spotify:track:b", "BAND"), vec![Action::Skip]),
+            (playing("spotify:track:c", "Other"), vec![]),
+            (playing("spotify:track:a", "Band"), vec![Action::Skip]),
+        ];
+        for (i, (snapshot, expected)) in stream.iter().enumerate() {
+            let fired = rules.update(snapshot, at(i as u64 * 60)).unwrap();
+            assert_eq!(&actions(fired), expected, "event {}", i);
+        }
+        assert_eq!(fake.count("next"), 3);
+    }
+
+    #[test]
+    fn holds_a_volume_cap_through_the_night() {
+        let fake = FakePlayer::new();
+        let mut rules = rules(
+            &fake,
+            "[[rule]]\nname = \"night\"\n\
+             when = { after = \"22:00\", before = \"06:00\" }\n\
+             then = [{ cap_volume = 30 }]\n",
+        );
+        let with_volume = |volume| {
+            let mut snapshot = playing("spotify:track:a", "Band");
+            snapshot.volume = Some(volume);
+            snapshot
+        };
+
+        assert!(rules
+            .update(&with_volume(50), at(21 * HOUR + 3540))
+            .unwrap()
+            .is_empty());
+        let fired = rules.update(&with_volume(50), at(22 * HOUR)).unwrap();
+        assert_eq!(actions(fired), vec![Action::CapVolume(30)]);
+        assert!(rules
+            .update(&with_volume(30), at(23 * HOUR))
+            .unwrap()
+            .is_empty());
+        // turned up again past midnight
+        let fired = rules.update(&with_volume(60), at(25 * HOUR)).unwrap();
+        assert_eq!(actions(fired), vec![Action::CapVolume(30)]);
+        assert!(rules
+            .update(&with_volume(60), at(30 * HOUR))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(fake.count("set_volume"), 2);
+        assert_eq!(fake.get().volume, Some(30));
+    }
+
+    #[test]
+    fn turns_shuffle_off_for_podcasts_on_weekends() {
+        let fake = FakePlayer::new();
+        let mut rules = rules(
+            &fake,
+            "[[rule]]\nname = \"talk\"\n\
+             when = { podcast = true, state = \"playing\", days = \"weekend\" }\n\
+             then = [{ shuffle = false }, { volume = 80 }]\n",
+        );
+        let mut episode = playing("spotify:episode:e", "Host");
+        episode.shuffling = Some(true);
+
+        // Thursday
+        assert!(rules.update(&episode, at(HOUR)).unwrap().is_empty());
+        // Saturday
+        let saturday = 2 * 24 * HOUR;
+        assert!(rules
+            .update(&playing("spotify:track:a", "Band"), at(saturday))
+            .unwrap()
+            .is_empty());
+        let fired = rules.update(&episode, at(saturday + 60)).unwrap();
+        assert_eq!(
+            actions(fired),
+            vec![Action::Shuffle(false), Action::Volume(80)]
+        );
+        episode.state = Some(State::PAUSED);
+        assert!(rules
+            .update(&episode, at(saturday + 120))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            fake.calls(),
+            vec![
+                "set_shuffling false".to_string(),
+                "set_volume 80".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_runs_leave_the_player_alone() {
+        let fake = FakePlayer::new();
+        let mut rules = rules(
+            &fake,
+            "[[rule]]\nname = \"pause\"\nwhen = { uri = \"spotify:track:a\" }\nthen = [\"pause\"]\n",
+        )
+        .dry_run(true);
+
+        let fired = rules
+            .update(&playing("spotify:track:a", "Band"), at(0))
+            .unwrap();
+        assert_eq!(
+            fired,
+            vec![Fired {
+                rule: "pause".to_string(),
+                action: Action::Pause,
+                applied: false,
+            }]
+        );
+        assert!(fake.calls().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let fake = FakePlayer::new();
+        for bad in &[
+            "then = [{ volume = 150 }]",
+            "then = [{ cap_volume = -1 }]",
+            "then = []",
+            "when = { state = \"loud\" }\nthen = [\"play\"]",
+            "when = { after = \"24:00\" }\nthen = [\"play\"]",
+            "when = { days = \"someday\" }\nthen = [\"play\"]",
+            "when = { explicit = true }\nthen = [\"skip\"]",
+        ] {
+            let toml = format!("[[rule]]\nname = \"bad\"\n{}\n", bad);
+            assert!(Rules::from_toml(&fake, &toml).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use crate::clock::Clock;
+use crate::player::Player;
+use crate::snapshot::TrackInfo;
+use crate::state::State;
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{Error, Result};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    State,
+    Shuffling,
+    Repeating,
+    Position,
+    Volume,
+    Track,
+    SetVolume,
+}
+
+type Shared = Arc<dyn Any + Send + Sync>;
+
+#[derive(Default)]
+struct FlightState {
+    // the event has gone out, too late to change what it says
+    started: bool,
+    result: Option<Result<Shared>>,
+}
+
+#[derive(Default)]
+struct Flight {
+    state: Mutex<FlightState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct Pending {
+    flights: HashMap<Key, Arc<Flight>>,
+    // the last volume asked for and not sent yet
+    volume: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    // calls passed on to Spotify
+    pub sent: u64,
+    // calls answered by a call someone else made
+    pub shared: u64,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn copy_error(err: &Error) -> Error {
+    match err.raw_os_error() {
+        Some(code) => Error::from_raw_os_error(code),
+        None => Error::new(err.kind(), err.to_string()),
+    }
+}
+
+// Passes on the calls of every thread sharing it one at a time and no faster
+// than the rate limit. Callers asking for something already being asked for
+// wait for that answer, and volume changes queued behind each other collapse
+// into the last one.
+pub struct Scheduler<P, C> {
+    inner: P,
+    clock: C,
+    interval: Duration,
+    // when the last call went out, held while sending
+    gate: Mutex<Option<Instant>>,
+    pending: Mutex<Pending>,
+    stats: Mutex<SchedulerStats>,
+}
+
+impl<P: Player, C: Clock> Scheduler<P, C> {
+    pub fn new(inner: P, clock: C) -> Scheduler<P, C> {
+        Scheduler {
+            inner,
+            clock,
+            interval: Duration::from_millis(40),
+            gate: Mutex::new(None),
+            pending: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    // At most `per_second` player calls, evenly spaced. The limit counts
+    // calls, not Apple Events, so one sending a few, like `play_track`, counts
+    // once. Zero lifts the limit.
+    pub fn with_max_rate(mut self, per_second: u32) -> Scheduler<P, C> {
+        self.interval = match per_second {
+            0 => Duration::ZERO,
+            n => Duration::from_secs(1) / n,
+        };
+        self
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        *lock(&self.stats)
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn send<T>(&self, f: impl FnOnce(&P) -> Result<T>) -> Result<T> {
+        let mut last = lock(&self.gate);
+        if let Some(last) = *last {
+            let wait = (last + self.interval).saturating_duration_since(self.clock.now());
+            if !wait.is_zero() {
+                self.clock.sleep(wait);
+            }
+        }
+
+        *last = Some(self.clock.now());
+        lock(&self.stats).sent += 1;
+        f(&self.inner)
+    }
+
+    // Returns the flight to wait on, and whether it's ours to send.
+    fn join(&self, key: Key, enter: impl FnOnce(&mut Pending)) -> (Arc<Flight>, bool) {
+        let mut pending = lock(&self.pending);
+        enter(&mut pending);
+
+        if let Some(flight) = pending.flights.get(&key) {
+            // a setter that went out already doesn't carry our value
+            if key != Key::SetVolume || !lock(&flight.state).started {
+                lock(&self.stats).shared += 1;
+                return (flight.clone(), false);
+            }
+        }
+
+        let flight = Arc::new(Flight::default());
+        pending.flights.insert(key, flight.clone());
+        (flight, true)
+    }
+
+    fn start<A>(&self, flight: &Flight, take: impl FnOnce(&mut Pending) -> A) -> A {
+        let mut pending = lock(&self.pending);
+        lock(&flight.state).started = true;
+        take(&mut pending)
+    }
+
+    fn finish(&self, key: Key, flight: &Arc<Flight>, result: Result<Shared>) {
+        let mut pending = lock(&self.pending);
+        if pending
+            .flights
+            .get(&key)
+            .is_some_and(|f| Arc::ptr_eq(f, flight))
+        {
+            pending.flights.remove(&key);
+        }
+        drop(pending);
+
+        lock(&flight.state).result = Some(result);
+        flight.done.notify_all();
+    }
+
+    fn wait<T: Clone + 'static>(&self, flight: &Flight) -> Result<T> {
+        let mut state = lock(&flight.state);
+        loop {
+            match &state.result {
+                Some(Ok(value)) => {
+                    return Ok(value
+                        .downcast_ref::<T>()
+                        .expect("flights are keyed by type")
+                        .clone())
+                }
+                Some(Err(err)) => return Err(copy_error(err)),
+                None => {
+                    state = flight
+                        .done
+                        .wait(state)
+                        .unwrap_or_else(|err| err.into_inner())
+                }
+            }
+        }
+    }
+
+    fn deduped<T: Clone + Send + Sync + 'static>(
+        &self,
+        key: Key,
+        f: impl FnOnce(&P) -> Result<T>,
+    ) -> Result<T> {
+        let (flight, ours) = self.join(key, |_| {});
+        if !ours {
+            return self.wait(&flight);
+        }
+
+        let res = self.send(|p| {
+            self.start(&flight, |_| ());
+            f(p)
+        });
+        let shared = match &res {
+            Ok(value) => Ok(Arc::new(value.clone()) as Shared),
+            Err(err) => Err(copy_error(err)),
+        };
+        self.finish(key, &flight, shared);
+        res
+    }
+}
+
+impl<P: Player, C: Clock> Player for Scheduler<P, C> {
+    fn state(&self) -> Result<Option<State>> {
+        self.deduped(Key::State, |p| p.state())
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        self.deduped(Key::Shuffling, |p| p.is_shuffling())
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        self.send(|p| p.set_shuffling(is_it))
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        self.deduped(Key::Repeating, |p| p.is_repeating())
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        self.send(|p| p.set_repeating(is_it))
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        self.deduped(Key::Position, |p| p.position())
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        self.send(|p| p.set_position(pos))
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        self.deduped(Key::Volume, |p| p.volume())
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        let (flight, ours) = self.join(Key::SetVolume, |pending| pending.volume = Some(vol));
+        if !ours {
+            return self.wait(&flight);
+        }
+
+        let res = self.send(|p| {
+            let vol = self
+                .start(&flight, |pending| pending.volume.take())
+                .unwrap_or(vol);
+            p.set_volume(vol)
+        });
+        let shared = match &res {
+            Ok(()) => Ok(Arc::new(()) as Shared),
+            Err(err) => Err(copy_error(err)),
+        };
+        self.finish(Key::SetVolume, &flight, shared);
+        res
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        self.deduped(Key::Track, |p| p.track())
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        self.send(|p| p.play_pause())
+    }
+
+    fn play(&self) -> Result<()> {
+        self.send(|p| p.play())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.send(|p| p.pause())
+    }
+
+    fn next(&self) -> Result<()> {
+        self.send(|p| p.next())
+    }
+
+    fn previous(&self) -> Result<()> {
+        self.send(|p| p.previous())
+    }
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        self.send(|p| p.play_track(track, context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::testing::{FakePlayer, TRACK};
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    fn until(f: impl Fn() -> bool) {
+        while !f() {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn spaces_calls_out_to_the_rate() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let scheduler = Scheduler::new(&player, clock.clone()).with_max_rate(10);
+
+        scheduler.volume().unwrap();
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        scheduler.set_volume(30).unwrap();
+        scheduler.play_track(TRACK.to_string(), None).unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_millis(200));
+
+        // time already gone by counts towards the wait
+        clock.advance(Duration::from_millis(60));
+        scheduler.pause().unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_millis(300));
+        assert_eq!(scheduler.stats().sent, 4);
+
+        let unlimited = Scheduler::new(&player, clock.clone()).with_max_rate(0);
+        unlimited.volume().unwrap();
+        unlimited.volume().unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn shares_an_answer_already_asked_for() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let scheduler = Scheduler::new(&player, clock).with_max_rate(0);
+
+        thread::scope(|scope| {
+            // holds the first call up inside the player
+            let snapshot = player.snapshot.lock().unwrap();
+            let first = scope.spawn(|| scheduler.volume());
+            until(|| player.count("volume") == 1);
+            let second = scope.spawn(|| scheduler.volume());
+            until(|| scheduler.stats().shared == 1);
+            drop(snapshot);
+
+            assert_eq!(first.join().unwrap().unwrap(), Some(50));
+            assert_eq!(second.join().unwrap().unwrap(), Some(50));
+        });
+
+        assert_eq!(player.count("volume"), 1);
+        assert_eq!(scheduler.stats(), SchedulerStats { sent: 1, shared: 1 });
+    }
+
+    #[test]
+    fn collapses_queued_volume_changes() {
+        let clock = VirtualClock::new(UNIX_EPOCH);
+        let player = FakePlayer::new();
+        let scheduler = Scheduler::new(&player, clock).with_max_rate(0);
+
+        thread::scope(|scope| {
+            let snapshot = player.snapshot.lock().unwrap();
+            // already gone out, so it keeps its value
+            let sent = scope.spawn(|| scheduler.set_volume(10));
+            until(|| player.count("set_volume") == 1);
+            let queued = scope.spawn(|| scheduler.set_volume(20));
+            until(|| lock(&scheduler.pending).volume == Some(20));
+            let last = scope.spawn(|| scheduler.set_volume(30));
+            until(|| scheduler.stats().shared == 1);
+            drop(snapshot);
+
+            sent.join().unwrap().unwrap();
+            queued.join().unwrap().unwrap();
+            last.join().unwrap().unwrap();
+        });
+
+        let calls = player.calls();
+        assert_eq!(calls, ["set_volume 10", "set_volume 30"]);
+        assert_eq!(player.get().volume, Some(30));
+        assert_eq!(scheduler.stats(), SchedulerStats { sent: 2, shared: 1 });
+    }
+}
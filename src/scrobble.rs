@@ -0,0 +1,602 @@
+use crate::player::Player;
+use crate::plays::{Play, PlayEvent, PlayTracker};
+use crate::snapshot::Snapshot;
+use crate::spool::{is_permanent, Spool};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MIN_DURATION: Duration = Duration::from_secs(30);
+pub const MAX_THRESHOLD: Duration = Duration::from_secs(4 * 60);
+// Last.fm ignores scrobbles older than this
+pub const MAX_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+// tracks per request accepted by track.scrobble
+const BATCH: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scrobble {
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    // seconds
+    pub duration: Option<u64>,
+    pub track_number: Option<i32>,
+    #[serde(default)]
+    pub origin_url: Option<String>,
+    // unix time the track started playing
+    pub timestamp: u64,
+}
+
+impl Scrobble {
+    pub fn from_play(play: &Play) -> Option<Scrobble> {
+        let track = &play.track;
+
+        Some(Scrobble {
+            artist: track.artist.clone().filter(|a| !a.is_empty())?,
+            track: track.name.clone().filter(|n| !n.is_empty())?,
+            album: track.album.clone().filter(|a| !a.is_empty()),
+            album_artist: track.album_artist.clone().filter(|a| !a.is_empty()),
+            duration: play.duration().map(|d| d.as_secs()),
+            track_number: track.track_number.filter(|n| *n > 0),
+            origin_url: track.url(),
+            timestamp: play
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        })
+    }
+}
+
+// A play counts once the track is longer than 30 seconds and has been listened
+// to for half its length or four minutes, whichever comes first.
+pub fn is_scrobblable(play: &Play) -> bool {
+    match play.duration() {
+        Some(duration) if duration <= MIN_DURATION => false,
+        Some(duration) => play.listened >= (duration / 2).min(MAX_THRESHOLD),
+        None => play.listened >= MAX_THRESHOLD,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Submission {
+    NowPlaying(Scrobble),
+    Scrobble(Scrobble),
+}
+
+pub trait ScrobbleEndpoint {
+    fn now_playing(&self, scrobble: &Scrobble) -> Result<()>;
+
+    fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()>;
+}
+
+impl<E: ScrobbleEndpoint + ?Sized> ScrobbleEndpoint for &E {
+    fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        (**self).now_playing(scrobble)
+    }
+
+    fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()> {
+        (**self).scrobble(scrobbles)
+    }
+}
+
+impl<E: ScrobbleEndpoint + ?Sized> ScrobbleEndpoint for Arc<E> {
+    fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        (**self).now_playing(scrobble)
+    }
+
+    fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()> {
+        (**self).scrobble(scrobbles)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+enum Job {
+    NowPlaying(Scrobble),
+    Flush,
+}
+
+struct Shared<E> {
+    endpoint: E,
+    spool: Mutex<Spool<Scrobble>>,
+    // one flush at a time, the spool itself is only locked between requests
+    flushing: Mutex<()>,
+}
+
+impl<E: ScrobbleEndpoint> Shared<E> {
+    fn flush(&self, now: SystemTime) -> Result<usize> {
+        let _flushing = lock(&self.flushing);
+        let cutoff = now
+            .checked_sub(MAX_AGE)
+            .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        lock(&self.spool).retain(|scrobble| scrobble.timestamp >= cutoff)?;
+
+        let mut sent = 0;
+        loop {
+            let batch: Vec<Scrobble> = lock(&self.spool)
+                .items()
+                .iter()
+                .take(BATCH)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                return Ok(sent);
+            }
+
+            match self.endpoint.scrobble(&batch) {
+                Ok(()) => {
+                    lock(&self.spool).remove_front(batch.len())?;
+                    sent += batch.len();
+                }
+                Err(err) if is_permanent(&err) => lock(&self.spool).reject_front(batch.len())?,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+// Queues finished plays on disk and hands them and now playing updates to
+// `endpoint` from a thread of its own, so polling never waits on the network.
+pub struct Scrobbler<E> {
+    shared: Arc<Shared<E>>,
+    tracker: PlayTracker,
+    jobs: SyncSender<Job>,
+}
+
+impl<E: ScrobbleEndpoint + Send + Sync + 'static> Scrobbler<E> {
+    pub fn new<P: AsRef<Path>>(endpoint: E, spool: P) -> Result<Scrobbler<E>> {
+        let shared = Arc::new(Shared {
+            endpoint,
+            spool: Mutex::new(Spool::open(spool)?),
+            flushing: Mutex::new(()),
+        });
+        let (jobs, rx) = sync_channel(16);
+
+        let worker = shared.clone();
+        thread::spawn(move || {
+            for job in rx {
+                match job {
+                    // best effort, it is stale by the time we are back online
+                    Job::NowPlaying(scrobble) => {
+                        let _ = worker.endpoint.now_playing(&scrobble);
+                    }
+                    // whatever fails stays queued for the next one
+                    Job::Flush => {
+                        let _ = worker.flush(SystemTime::now());
+                    }
+                }
+            }
+        });
+
+        Ok(Scrobbler {
+            shared,
+            tracker: PlayTracker::new(),
+            jobs,
+        })
+    }
+
+    pub fn endpoint(&self) -> &E {
+        &self.shared.endpoint
+    }
+
+    pub fn pending(&self) -> Vec<Scrobble> {
+        lock(&self.shared.spool).items().to_vec()
+    }
+
+    pub fn poll<P: Player>(&mut self, player: &P) -> Result<Vec<Submission>> {
+        let snapshot = player.snapshot()?;
+        self.update(&snapshot, SystemTime::now())
+    }
+
+    pub fn update(&mut self, snapshot: &Snapshot, now: SystemTime) -> Result<Vec<Submission>> {
+        let mut submissions = Vec::new();
+        let mut queued = false;
+
+        for event in self.tracker.update(snapshot, now) {
+            match event {
+                PlayEvent::Started(play) => {
+                    if let Some(scrobble) = Scrobble::from_play(&play) {
+                        // skipped rather than waited for if the worker is behind
+                        let _ = self.jobs.try_send(Job::NowPlaying(scrobble.clone()));
+                        submissions.push(Submission::NowPlaying(scrobble));
+                    }
+                }
+                PlayEvent::Finished(play) => {
+                    if let Some(scrobble) = Some(&play)
+                        .filter(|play| is_scrobblable(play))
+                        .and_then(Scrobble::from_play)
+                    {
+                        lock(&self.shared.spool).push(scrobble.clone())?;
+                        submissions.push(Submission::Scrobble(scrobble));
+                        queued = true;
+                    }
+                }
+            }
+        }
+
+        if queued {
+            let _ = self.jobs.try_send(Job::Flush);
+        }
+
+        Ok(submissions)
+    }
+
+    // Sends what is queued right away, on this thread. Scrobbles older than
+    // `MAX_AGE` are dropped and the ones the endpoint turns down for good are
+    // set aside next to the spool.
+    pub fn flush(&self) -> Result<usize> {
+        self.shared.flush(SystemTime::now())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LastFmError {
+    code: i64,
+    message: String,
+}
+
+impl LastFmError {
+    pub fn code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::error::Error for LastFmError {}
+
+impl fmt::Display for LastFmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Last.fm error {}: {}", self.code, self.message)
+    }
+}
+
+pub struct LastFm {
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+    url: String,
+    agent: ureq::Agent,
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(15))
+        .build()
+}
+
+fn sign(params: &[(String, String)], secret: &str) -> String {
+    let mut sorted: Vec<&(String, String)> = params.iter().collect();
+    sorted.sort();
+
+    let mut payload = String::new();
+    for (key, value) in sorted {
+        payload.push_str(key);
+        payload.push_str(value);
+    }
+    payload.push_str(secret);
+
+    format!("{:x}", md5::compute(payload.as_bytes()))
+}
+
+fn post(
+    agent: &ureq::Agent,
+    url: &str,
+    mut params: Vec<(String, String)>,
+    secret: &str,
+) -> Result<Value> {
+    let signature = sign(&params, secret);
+    params.push(("api_sig".to_string(), signature));
+    params.push(("format".to_string(), "json".to_string()));
+
+    let form: Vec<(&str, &str)> = params
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+
+    let body = match agent.post(url).send_form(&form) {
+        Ok(response) => response.into_string()?,
+        Err(ureq::Error::Status(_, response)) => response.into_string()?,
+        Err(err) => return Err(Error::other(err)),
+    };
+
+    let value: Value =
+        serde_json::from_str(&body).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+    let code = match value.get("error").and_then(Value::as_i64) {
+        Some(code) => code,
+        None => return Ok(value),
+    };
+
+    // see https://www.last.fm/api/errorcodes, the rest are worth retrying
+    let kind = match code {
+        6 => ErrorKind::InvalidInput,
+        4 | 9 | 10 | 13 | 26 => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    let message = value
+        .get("message")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+
+    Err(Error::new(kind, LastFmError { code, message }))
+}
+
+fn push_opt<T: ToString>(params: &mut Vec<(String, String)>, key: String, value: &Option<T>) {
+    if let Some(value) = value {
+        params.push((key, value.to_string()));
+    }
+}
+
+impl LastFm {
+    pub const API_URL: &'static str = "https://ws.audioscrobbler.com/2.0/";
+
+    pub fn new(api_key: &str, api_secret: &str, session_key: &str) -> LastFm {
+        LastFm {
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            session_key: session_key.to_string(),
+            url: LastFm::API_URL.to_string(),
+            agent: agent(),
+        }
+    }
+
+    pub fn with_url(mut self, url: &str) -> LastFm {
+        self.url = url.to_string();
+        self
+    }
+
+    pub fn mobile_session(
+        url: &str,
+        api_key: &str,
+        api_secret: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<String> {
+        let params = vec![
+            ("method".to_string(), "auth.getMobileSession".to_string()),
+            ("api_key".to_string(), api_key.to_string()),
+            ("username".to_string(), username.to_string()),
+            ("password".to_string(), password.to_string()),
+        ];
+
+        let res = post(&agent(), url, params, api_secret)?;
+        res.pointer("/session/key")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing session key"))
+    }
+
+    fn params(&self, method: &str) -> Vec<(String, String)> {
+        vec![
+            ("method".to_string(), method.to_string()),
+            ("api_key".to_string(), self.api_key.clone()),
+            ("sk".to_string(), self.session_key.clone()),
+        ]
+    }
+}
+
+impl ScrobbleEndpoint for LastFm {
+    fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        let mut params = self.params("track.updateNowPlaying");
+        params.push(("artist".to_string(), scrobble.artist.clone()));
+        params.push(("track".to_string(), scrobble.track.clone()));
+        push_opt(&mut params, "album".to_string(), &scrobble.album);
+        push_opt(
+            &mut params,
+            "albumArtist".to_string(),
+            &scrobble.album_artist,
+        );
+        push_opt(&mut params, "duration".to_string(), &scrobble.duration);
+        push_opt(
+            &mut params,
+            "trackNumber".to_string(),
+            &scrobble.track_number,
+        );
+
+        post(&self.agent, &self.url, params, &self.api_secret).map(|_| ())
+    }
+
+    fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()> {
+        let mut params = self.params("track.scrobble");
+
+        for (i, scrobble) in scrobbles.iter().enumerate() {
+            params.push((format!("artist[{}]", i), scrobble.artist.clone()));
+            params.push((format!("track[{}]", i), scrobble.track.clone()));
+            params.push((format!("timestamp[{}]", i), scrobble.timestamp.to_string()));
+            push_opt(&mut params, format!("album[{}]", i), &scrobble.album);
+            push_opt(
+                &mut params,
+                format!("albumArtist[{}]", i),
+                &scrobble.album_artist,
+            );
+            push_opt(&mut params, format!("duration[{}]", i), &scrobble.duration);
+            push_opt(
+                &mut params,
+                format!("trackNumber[{}]", i),
+                &scrobble.track_number,
+            );
+        }
+
+        post(&self.agent, &self.url, params, &self.api_secret).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::testing::{http_stub, track, TempDir, TRACK};
+    use std::collections::VecDeque;
+    use std::time::Instant;
+
+    #[derive(Default)]
+    struct Endpoint {
+        now_playing: Mutex<Vec<String>>,
+        batches: Mutex<Vec<Vec<u64>>>,
+        failures: Mutex<VecDeque<ErrorKind>>,
+    }
+
+    impl ScrobbleEndpoint for Endpoint {
+        fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+            lock(&self.now_playing).push(scrobble.track.clone());
+            Ok(())
+        }
+
+        fn scrobble(&self, scrobbles: &[Scrobble]) -> Result<()> {
+            lock(&self.batches).push(scrobbles.iter().map(|s| s.timestamp).collect());
+            match lock(&self.failures).pop_front() {
+                Some(kind) => Err(Error::new(kind, "no")),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn eventually(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn scrobble(timestamp: u64) -> Scrobble {
+        Scrobble {
+            artist: "Band".to_string(),
+            track: "Song".to_string(),
+            album: None,
+            album_artist: None,
+            duration: Some(200),
+            track_number: None,
+            origin_url: None,
+            timestamp,
+        }
+    }
+
+    fn unix(at: SystemTime) -> u64 {
+        at.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn plays_are_sent_from_the_worker() {
+        let dir = TempDir::new();
+        let endpoint = Arc::new(Endpoint::default());
+        let mut scrobbler = Scrobbler::new(endpoint.clone(), dir.join("spool.jsonl")).unwrap();
+        let start = SystemTime::now() - Duration::from_secs(3600);
+        let playing = |position: f64| Snapshot {
+            state: Some(State::PLAYING),
+            position: Some(position),
+            track: Some(track(TRACK, 200_000)),
+            ..Default::default()
+        };
+
+        let res = scrobbler.update(&playing(0.0), start).unwrap();
+        assert!(matches!(res.as_slice(), [Submission::NowPlaying(_)]));
+        let at = start + Duration::from_secs(150);
+        assert!(scrobbler.update(&playing(150.0), at).unwrap().is_empty());
+        let res = scrobbler
+            .update(&Snapshot::default(), at + Duration::from_secs(1))
+            .unwrap();
+        assert!(matches!(res.as_slice(), [Submission::Scrobble(_)]));
+
+        eventually(|| lock(&endpoint.batches).len() == 1);
+        assert_eq!(
+            *lock(&endpoint.now_playing),
+            vec!["Song 6rqhFgbbKwnb9MLmUQDhG6"]
+        );
+        assert_eq!(*lock(&endpoint.batches), vec![vec![unix(start)]]);
+        eventually(|| scrobbler.pending().is_empty());
+    }
+
+    #[test]
+    fn flush_prunes_and_sets_aside() {
+        let dir = TempDir::new();
+        let path = dir.join("spool.jsonl");
+        let now = unix(SystemTime::now());
+        let old = now - MAX_AGE.as_secs() - 60;
+        {
+            let mut spool = Spool::open(&path).unwrap();
+            spool.push(scrobble(old)).unwrap();
+            for n in 0..BATCH as u64 + 1 {
+                spool.push(scrobble(now - 100 + n)).unwrap();
+            }
+        }
+
+        let endpoint = Arc::new(Endpoint::default());
+        lock(&endpoint.failures).extend(&[ErrorKind::PermissionDenied, ErrorKind::TimedOut]);
+        let scrobbler = Scrobbler::new(endpoint.clone(), &path).unwrap();
+
+        // the first batch is turned down, the second one can wait
+        assert_eq!(scrobbler.flush().unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            scrobbler.pending(),
+            vec![scrobble(now - 100 + BATCH as u64)]
+        );
+        let rejected: Spool<Scrobble> = Spool::open(path.with_extension("jsonl.rejected")).unwrap();
+        assert_eq!(rejected.len(), BATCH);
+        assert!(rejected.items().iter().all(|s| s.timestamp != old));
+
+        assert_eq!(scrobbler.flush().unwrap(), 1);
+        assert!(Spool::<Scrobble>::open(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn last_fm_requests_and_errors() {
+        let (url, requests) = http_stub(vec![
+            (200, r#"{"scrobbles":{"@attr":{"accepted":2}}}"#),
+            (200, r#"{"error":9,"message":"Invalid session key"}"#),
+            (400, r#"{"error":6,"message":"Invalid parameters"}"#),
+            (200, r#"{"error":16,"message":"Try again"}"#),
+            (503, "<html>"),
+        ]);
+        let lastfm = LastFm::new("key", "secret", "session").with_url(&url);
+        let batch = [scrobble(1), scrobble(2)];
+
+        lastfm.scrobble(&batch).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST / "), "{}", request);
+        for param in &[
+            "method=track.scrobble",
+            "sk=session",
+            "timestamp%5B1%5D=2",
+            "api_sig=",
+        ] {
+            assert!(request.contains(param), "{} in {}", param, request);
+        }
+
+        let kinds: Vec<ErrorKind> = (0..4)
+            .map(|_| lastfm.scrobble(&batch).unwrap_err().kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ErrorKind::PermissionDenied,
+                ErrorKind::InvalidInput,
+                ErrorKind::Other,
+                ErrorKind::InvalidData
+            ]
+        );
+    }
+
+    #[test]
+    fn signature() {
+        let params = vec![
+            ("b".to_string(), "2".to_string()),
+            ("a".to_string(), "1".to_string()),
+        ];
+        assert_eq!(sign(&params, "s"), format!("{:x}", md5::compute("a1b2s")));
+    }
+}
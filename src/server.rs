@@ -0,0 +1,588 @@
+use crate::player::Player;
+use crate::watch;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{Error, Result, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+const KEEPALIVE: &str = ": keepalive\n\n";
+const STREAM_BUFFER: usize = 64;
+const ROUTES: &[&str] = &[
+    "/status",
+    "/track",
+    "/play",
+    "/pause",
+    "/playpause",
+    "/next",
+    "/previous",
+    "/volume",
+    "/position",
+    "/shuffle",
+    "/repeat",
+    "/open",
+    "/events",
+];
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub token: Option<String>,
+    pub cors_origins: Vec<String>,
+    pub poll_interval: Duration,
+    // how often idle event streams are written to, which is also how soon
+    // a client that went away is noticed
+    pub keepalive: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            token: None,
+            cors_origins: Vec::new(),
+            poll_interval: Duration::from_millis(500),
+            keepalive: Duration::from_secs(15),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VolumeBody {
+    volume: i32,
+}
+
+#[derive(Deserialize)]
+struct PositionBody {
+    position: f64,
+}
+
+#[derive(Deserialize)]
+struct ShuffleBody {
+    shuffle: bool,
+}
+
+#[derive(Deserialize)]
+struct RepeatBody {
+    repeat: bool,
+}
+
+#[derive(Deserialize)]
+struct OpenBody {
+    uri: String,
+    context: Option<String>,
+}
+
+pub(crate) enum Reply {
+    Json(StatusCode, Value),
+    Empty(StatusCode),
+}
+
+pub(crate) fn error_reply(status: u16, message: &str) -> Reply {
+    Reply::Json(StatusCode(status), json!({ "error": message }))
+}
+
+fn player_reply(res: Result<()>) -> Reply {
+    match res {
+        Ok(()) => Reply::Empty(StatusCode(204)),
+        Err(err) => error_reply(502, &err.to_string()),
+    }
+}
+
+pub(crate) fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+pub(crate) fn header_value<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key == name => Some(value),
+            _ => None,
+        }
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn read_body<T: for<'de> Deserialize<'de>>(
+    request: &mut Request,
+) -> std::result::Result<T, Reply> {
+    let mut body = Vec::new();
+
+    if let Err(err) = request.as_reader().read_to_end(&mut body) {
+        return Err(error_reply(400, &err.to_string()));
+    }
+
+    serde_json::from_slice(&body).map_err(|err| error_reply(400, &err.to_string()))
+}
+
+pub(crate) fn respond(request: Request, reply: Reply, mut headers: Vec<Header>) -> Result<()> {
+    let response = match reply {
+        Reply::Json(status, body) => {
+            headers.push(header("Content-Type", "application/json"));
+            Response::from_string(body.to_string()).with_status_code(status)
+        }
+        Reply::Empty(status) => Response::from_string("").with_status_code(status),
+    };
+
+    request.respond(headers.into_iter().fold(response, |r, h| r.with_header(h)))
+}
+
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn sse_message(name: &str, data: &Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+pub struct Server<P> {
+    http: Arc<tiny_http::Server>,
+    player: Arc<Mutex<P>>,
+    config: ServerConfig,
+    subscribers: Arc<Mutex<Vec<SyncSender<String>>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl<P: Player + Send + 'static> Server<P> {
+    pub fn bind<A: ToSocketAddrs>(addr: A, player: P, config: ServerConfig) -> Result<Server<P>> {
+        let http = tiny_http::Server::http(addr).map_err(|err| Error::other(err.to_string()))?;
+
+        Ok(Server {
+            http: Arc::new(http),
+            player: Arc::new(Mutex::new(player)),
+            config,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.http.unblock();
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let watcher = self.spawn_watcher();
+
+        for request in self.http.incoming_requests() {
+            // a client hanging up mid-response is not a server failure
+            let _ = self.handle(request);
+        }
+
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = watcher.join();
+        Ok(())
+    }
+
+    fn spawn_watcher(&self) -> thread::JoinHandle<()> {
+        let subscribers = self.subscribers.clone();
+        let active = self.subscribers.clone();
+        let keepalive = self.config.keepalive;
+        let mut last_keepalive = Instant::now();
+
+        watch::spawn(
+            self.player.clone(),
+            self.config.poll_interval,
+            self.stopped.clone(),
+            move || {
+                let mut active = lock(&active);
+                // streams only find out their client left when written to,
+                // so an idle server would otherwise keep polling for nobody
+                if last_keepalive.elapsed() >= keepalive {
+                    last_keepalive = Instant::now();
+                    send_all(&mut active, KEEPALIVE);
+                }
+                !active.is_empty()
+            },
+            move |event| {
+                send_all(
+                    &mut lock(&subscribers),
+                    &sse_message(event.name(), &json!(event)),
+                )
+            },
+        )
+    }
+
+    fn cors_headers(&self, request: &Request) -> Vec<Header> {
+        let origin = match header_value(request, "Origin") {
+            Some(origin) => origin,
+            None => return Vec::new(),
+        };
+
+        let allowed = self
+            .config
+            .cors_origins
+            .iter()
+            .any(|o| o == "*" || o.eq_ignore_ascii_case(origin));
+
+        if allowed {
+            vec![
+                header("Access-Control-Allow-Origin", origin),
+                header("Vary", "Origin"),
+            ]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn is_authorized(&self, request: &Request, query: &str) -> bool {
+        let token = match &self.config.token {
+            Some(token) => token,
+            None => return true,
+        };
+
+        let given = header_value(request, "Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| query_param(query, "access_token"));
+
+        given.is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+    }
+
+    fn handle(&self, mut request: Request) -> Result<()> {
+        let mut headers = self.cors_headers(&request);
+        let url = request.url().to_string();
+        let (path, query) = match url.find('?') {
+            Some(idx) => (&url[..idx], &url[idx + 1..]),
+            None => (url.as_str(), ""),
+        };
+
+        let reply = if *request.method() == Method::Options {
+            if !headers.is_empty() {
+                headers.push(header(
+                    "Access-Control-Allow-Methods",
+                    "GET, POST, PUT, OPTIONS",
+                ));
+                headers.push(header(
+                    "Access-Control-Allow-Headers",
+                    "Authorization, Content-Type",
+                ));
+            }
+            Reply::Empty(StatusCode(204))
+        } else if !self.is_authorized(&request, query) {
+            headers.push(header("WWW-Authenticate", "Bearer"));
+            error_reply(401, "Unauthorized")
+        } else if *request.method() == Method::Get && path == "/events" {
+            return self.stream(request, headers);
+        } else {
+            self.route(&mut request, path)
+        };
+
+        respond(request, reply, headers)
+    }
+
+    fn route(&self, request: &mut Request, path: &str) -> Reply {
+        let player = &*self.player;
+
+        match (request.method(), path) {
+            (Method::Get, "/status") => match player.snapshot() {
+                Ok(snapshot) => Reply::Json(StatusCode(200), json!(snapshot)),
+                Err(err) => error_reply(502, &err.to_string()),
+            },
+            (Method::Get, "/track") => match player.track() {
+                Ok(Some(track)) => Reply::Json(StatusCode(200), json!(track)),
+                Ok(None) => error_reply(404, "No track"),
+                Err(err) => error_reply(502, &err.to_string()),
+            },
+            (Method::Post, "/play") => player_reply(player.play()),
+            (Method::Post, "/pause") => player_reply(player.pause()),
+            (Method::Post, "/playpause") => player_reply(player.play_pause()),
+            (Method::Post, "/next") => player_reply(player.next()),
+            (Method::Post, "/previous") => player_reply(player.previous()),
+            (Method::Put, "/volume") => match read_body::<VolumeBody>(request) {
+                Ok(body) if (0..=100).contains(&body.volume) => {
+                    player_reply(player.set_volume(body.volume))
+                }
+                Ok(_) => error_reply(400, "Volume must be between 0 and 100"),
+                Err(reply) => reply,
+            },
+            (Method::Put, "/position") => match read_body::<PositionBody>(request) {
+                Ok(body) if body.position >= 0.0 => {
+                    player_reply(player.set_position(body.position))
+                }
+                Ok(_) => error_reply(400, "Position must not be negative"),
+                Err(reply) => reply,
+            },
+            (Method::Put, "/shuffle") => match read_body::<ShuffleBody>(request) {
+                Ok(body) => player_reply(player.set_shuffling(body.shuffle)),
+                Err(reply) => reply,
+            },
+            (Method::Put, "/repeat") => match read_body::<RepeatBody>(request) {
+                Ok(body) => player_reply(player.set_repeating(body.repeat)),
+                Err(reply) => reply,
+            },
+            (Method::Post, "/open") => match read_body::<OpenBody>(request) {
+                Ok(body) => player_reply(player.play_track(body.uri, body.context)),
+                Err(reply) => reply,
+            },
+            (_, path) if ROUTES.contains(&path) => error_reply(405, "Method not allowed"),
+            _ => error_reply(404, "Not found"),
+        }
+    }
+
+    fn stream(&self, request: Request, headers: Vec<Header>) -> Result<()> {
+        let snapshot = match self.player.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                let response =
+                    Response::from_string(json!({ "error": err.to_string() }).to_string())
+                        .with_status_code(502);
+                return request
+                    .respond(headers.into_iter().fold(response, |r, h| r.with_header(h)));
+            }
+        };
+
+        let (tx, rx) = sync_channel(STREAM_BUFFER);
+        let _ = tx.send(sse_message("snapshot", &json!(snapshot)));
+
+        lock(&self.subscribers).push(tx);
+
+        thread::spawn(move || {
+            let _ = write_stream(request.into_writer(), headers, rx);
+        });
+
+        Ok(())
+    }
+}
+
+// Drops the streams whose client went away.
+fn send_all(subscribers: &mut Vec<SyncSender<String>>, message: &str) {
+    // slow clients lose events instead of stalling everyone else
+    subscribers.retain(|tx| {
+        !matches!(
+            tx.try_send(message.to_string()),
+            Err(TrySendError::Disconnected(_))
+        )
+    });
+}
+
+fn write_stream(
+    mut writer: Box<dyn Write + Send>,
+    headers: Vec<Header>,
+    rx: Receiver<String>,
+) -> Result<()> {
+    let mut head = String::from(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n",
+    );
+
+    for header in headers {
+        head.push_str(&format!("{}: {}\r\n", header.field, header.value));
+    }
+
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())?;
+    writer.flush()?;
+
+    // keepalives come through `rx` too, failing the write once the client
+    // is gone
+    for message in rx {
+        writer.write_all(message.as_bytes())?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakePlayer;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+
+    fn start(config: ServerConfig) -> (Arc<Server<Arc<FakePlayer>>>, Arc<FakePlayer>) {
+        let fake = Arc::new(FakePlayer::new());
+        let server = Arc::new(Server::bind("127.0.0.1:0", fake.clone(), config).unwrap());
+        let running = server.clone();
+        thread::spawn(move || running.run());
+        (server, fake)
+    }
+
+    fn request(
+        server: &Server<Arc<FakePlayer>>,
+        method: &str,
+        path: &str,
+        headers: &[&str],
+        body: &str,
+    ) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        for header in headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        write!(stream, "{}\r\n{}", head, body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+        let status = head[9..12].parse().unwrap();
+        (status, head.to_string(), body[4..].to_string())
+    }
+
+    #[test]
+    fn routes_reach_the_player() {
+        let (server, fake) = start(Default::default());
+
+        let (status, _, body) = request(&server, "GET", "/status", &[], "");
+        assert_eq!(status, 200);
+        let status: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["volume"], 50);
+        assert_eq!(status["state"], "playing");
+
+        assert_eq!(request(&server, "POST", "/pause", &[], "").0, 204);
+        assert_eq!(fake.count("pause"), 1);
+        assert_eq!(
+            request(&server, "PUT", "/volume", &[], r#"{"volume":30}"#).0,
+            204
+        );
+        assert_eq!(fake.get().volume, Some(30));
+        assert_eq!(
+            request(&server, "PUT", "/volume", &[], r#"{"volume":130}"#).0,
+            400
+        );
+        assert_eq!(request(&server, "PUT", "/volume", &[], "{").0, 400);
+        assert_eq!(request(&server, "GET", "/play", &[], "").0, 405);
+        assert_eq!(request(&server, "GET", "/nope", &[], "").0, 404);
+        assert_eq!(fake.get().volume, Some(30));
+
+        fake.fail("next", &[-1712]);
+        assert_eq!(request(&server, "POST", "/next", &[], "").0, 502);
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn tokens_and_cors() {
+        let (server, fake) = start(ServerConfig {
+            token: Some("secret".to_string()),
+            cors_origins: vec!["https://example.com".to_string()],
+            ..Default::default()
+        });
+
+        let (status, head, _) = request(&server, "POST", "/next", &[], "");
+        assert_eq!(status, 401);
+        assert!(head.contains("WWW-Authenticate: Bearer"));
+        assert_eq!(
+            request(
+                &server,
+                "POST",
+                "/next",
+                &["Authorization: Bearer nope"],
+                ""
+            )
+            .0,
+            401
+        );
+        assert_eq!(fake.count("next"), 0);
+
+        assert_eq!(
+            request(
+                &server,
+                "POST",
+                "/next",
+                &["Authorization: Bearer secret"],
+                ""
+            )
+            .0,
+            204
+        );
+        assert_eq!(
+            request(&server, "POST", "/next?access_token=secret", &[], "").0,
+            204
+        );
+        assert_eq!(fake.count("next"), 2);
+
+        let (status, head, _) = request(
+            &server,
+            "OPTIONS",
+            "/volume",
+            &["Origin: https://example.com"],
+            "",
+        );
+        assert_eq!(status, 204);
+        assert!(head.contains("Access-Control-Allow-Origin: https://example.com"));
+        let (_, head, _) = request(
+            &server,
+            "OPTIONS",
+            "/volume",
+            &["Origin: https://evil.com"],
+            "",
+        );
+        assert!(!head.contains("Access-Control-Allow-Origin"));
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn streams_stop_polling_once_clients_leave() {
+        let (server, fake) = start(ServerConfig {
+            poll_interval: Duration::from_millis(5),
+            keepalive: Duration::from_millis(20),
+            ..Default::default()
+        });
+
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(stream, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "event: snapshot\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+
+        // the stream's snapshot and the watcher's first look
+        while fake.count("state") < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        fake.set(|s| s.volume = Some(20));
+        while line != "event: volume_changed\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("data: {\"type\":\"volume_changed\",\"value\":20}\n"));
+        drop(reader);
+
+        // nothing changes, only keepalives can notice the client is gone
+        for _ in 0..200 {
+            if lock(&server.subscribers).is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(lock(&server.subscribers).is_empty());
+
+        thread::sleep(Duration::from_millis(20));
+        let polls = fake.count("state");
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(fake.count("state"), polls);
+
+        server.shutdown();
+    }
+}
@@ -0,0 +1,345 @@
+use crate::clock::Clock;
+use crate::fade::FadeCurve;
+use crate::player::Player;
+use crate::state::State;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// close enough to the end of a track to call it over
+const END_SLACK: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Until {
+    Elapsed(Duration),
+    EndOfTrack,
+}
+
+#[derive(Debug, Clone)]
+pub struct SleepConfig {
+    pub until: Until,
+    // once the time is up, let the current track play out before stopping
+    pub finish_track: bool,
+    pub fade: Duration,
+    pub curve: FadeCurve,
+    pub step: Duration,
+    pub restore_volume: bool,
+}
+
+impl Default for SleepConfig {
+    fn default() -> SleepConfig {
+        SleepConfig {
+            until: Until::Elapsed(Duration::from_secs(30 * 60)),
+            finish_track: false,
+            fade: Duration::from_secs(60),
+            curve: FadeCurve::default(),
+            step: Duration::from_secs(1),
+            restore_volume: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Paused,
+    Cancelled,
+    // playback was stopped or paused by someone else
+    Interrupted,
+}
+
+#[derive(Default)]
+struct Control {
+    cancelled: AtomicBool,
+    suspended: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct SleepHandle(Arc<Control>);
+
+impl SleepHandle {
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Stops the countdown and undoes any fade until resumed.
+    pub fn suspend(&self) {
+        self.0.suspended.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.0.suspended.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.0.suspended.load(Ordering::SeqCst)
+    }
+}
+
+enum Phase {
+    Timer(Duration),
+    // the track we are letting play out, once known
+    Track(Option<String>),
+}
+
+pub struct SleepTimer<P, C> {
+    player: P,
+    clock: C,
+    config: SleepConfig,
+    control: Arc<Control>,
+}
+
+impl<P: Player, C: Clock> SleepTimer<P, C> {
+    pub fn new(player: P, clock: C, config: SleepConfig) -> SleepTimer<P, C> {
+        SleepTimer {
+            player,
+            clock,
+            config,
+            control: Default::default(),
+        }
+    }
+
+    pub fn handle(&self) -> SleepHandle {
+        SleepHandle(self.control.clone())
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn run(&self) -> Result<Outcome> {
+        // without a volume to come back to, there is no fading either
+        let original = self.player.volume()?;
+        let mut volume = original;
+        let mut fade_window: Option<f64> = None;
+        let mut phase = match self.config.until {
+            Until::Elapsed(duration) => Phase::Timer(duration),
+            Until::EndOfTrack => Phase::Track(None),
+        };
+        let mut last = self.clock.now();
+
+        loop {
+            let now = self.clock.now();
+            let elapsed = now.saturating_duration_since(last);
+            last = now;
+
+            if self.control.cancelled.load(Ordering::SeqCst) {
+                self.restore(&mut volume, original)?;
+                return Ok(Outcome::Cancelled);
+            }
+
+            if self.control.suspended.load(Ordering::SeqCst) {
+                if fade_window.take().is_some() {
+                    self.restore(&mut volume, original)?;
+                }
+                self.clock.sleep(self.config.step);
+                continue;
+            }
+
+            if self.player.state()? != Some(State::PLAYING) {
+                self.restore(&mut volume, original)?;
+                return Ok(Outcome::Interrupted);
+            }
+
+            if let Phase::Timer(left) = &mut phase {
+                *left = left.saturating_sub(elapsed);
+                if *left == Duration::from_secs(0) && self.config.finish_track {
+                    phase = Phase::Track(None);
+                }
+            }
+
+            let remaining = match &mut phase {
+                // nothing to fade towards until we know where the track ends
+                Phase::Timer(_) if self.config.finish_track => None,
+                Phase::Timer(left) => Some(left.as_secs_f64()),
+                Phase::Track(key) => self.track_remaining(key)?,
+            };
+
+            match remaining {
+                Some(remaining) if remaining <= self.slack(&phase) => {
+                    self.player.pause()?;
+                    if self.config.restore_volume {
+                        self.restore(&mut volume, original)?;
+                    }
+                    return Ok(Outcome::Paused);
+                }
+                Some(remaining) if remaining <= self.config.fade.as_secs_f64() => {
+                    if let Some(original) = original {
+                        let window = *fade_window.get_or_insert(remaining);
+                        let progress = 1.0 - remaining / window;
+                        let faded = self.config.curve.volume(original, 0, progress);
+                        self.set_volume(&mut volume, faded)?;
+                    }
+                }
+                _ => {}
+            }
+
+            // when finishing a track, aim inside the slack so we stop before
+            // the next one starts
+            let nap = match (remaining, &phase) {
+                (Some(remaining), Phase::Track(_)) => {
+                    Duration::from_secs_f64(remaining - END_SLACK / 2.0)
+                }
+                (Some(remaining), _) => Duration::from_secs_f64(remaining),
+                (None, _) => self.config.step,
+            };
+            self.clock.sleep(nap.min(self.config.step));
+        }
+    }
+
+    fn slack(&self, phase: &Phase) -> f64 {
+        match phase {
+            Phase::Track(_) => END_SLACK,
+            Phase::Timer(_) => 0.0,
+        }
+    }
+
+    // Seconds left in the track being finished, zero once another one started.
+    fn track_remaining(&self, key: &mut Option<String>) -> Result<Option<f64>> {
+        let track = match self.player.track()? {
+            Some(track) => track,
+            None => return Ok(Some(0.0)),
+        };
+
+        let current = track.spotify_url.clone().or_else(|| track.id.clone());
+        match key {
+            Some(key) if current.as_ref() != Some(key) => return Ok(Some(0.0)),
+            Some(_) => {}
+            None => *key = current,
+        }
+
+        let duration = match track.duration.filter(|ms| *ms > 0) {
+            Some(ms) => ms as f64 / 1000.0,
+            // no idea when it ends, stopping now beats never stopping
+            None => return Ok(Some(0.0)),
+        };
+        let position = self.player.position()?.unwrap_or(0.0);
+
+        Ok(Some((duration - position).max(0.0)))
+    }
+
+    fn set_volume(&self, current: &mut Option<i32>, volume: i32) -> Result<()> {
+        if *current != Some(volume) {
+            self.player.set_volume(volume)?;
+            *current = Some(volume);
+        }
+        Ok(())
+    }
+
+    fn restore(&self, current: &mut Option<i32>, original: Option<i32>) -> Result<()> {
+        match original {
+            Some(original) => self.set_volume(current, original),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::testing::FakePlayer;
+    use std::time::SystemTime;
+
+    fn start(
+        fake: &FakePlayer,
+        config: SleepConfig,
+    ) -> (SleepTimer<&FakePlayer, VirtualClock>, VirtualClock) {
+        let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+        fake.follow(&clock);
+        (SleepTimer::new(fake, clock.clone(), config), clock)
+    }
+
+    fn volumes(fake: &FakePlayer) -> Vec<i32> {
+        fake.calls()
+            .iter()
+            .filter_map(|call| call.strip_prefix("set_volume "))
+            .map(|v| v.parse().unwrap())
+            .collect()
+    }
+
+    fn settings(until: Until) -> SleepConfig {
+        SleepConfig {
+            until,
+            fade: Duration::from_secs(10),
+            curve: FadeCurve::Linear,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fades_out_pauses_and_restores() {
+        let fake = FakePlayer::new();
+        let (timer, clock) = start(&fake, settings(Until::Elapsed(Duration::from_secs(60))));
+
+        assert_eq!(timer.run().unwrap(), Outcome::Paused);
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+        assert_eq!(fake.get().state, Some(State::PAUSED));
+        assert_eq!(volumes(&fake), vec![45, 40, 35, 30, 25, 20, 15, 10, 5, 50]);
+    }
+
+    #[test]
+    fn keeps_the_faded_volume_when_told() {
+        let fake = FakePlayer::new();
+        let config = SleepConfig {
+            restore_volume: false,
+            ..settings(Until::Elapsed(Duration::from_secs(20)))
+        };
+        let (timer, _) = start(&fake, config);
+
+        assert_eq!(timer.run().unwrap(), Outcome::Paused);
+        assert_eq!(volumes(&fake).last(), Some(&5));
+    }
+
+    #[test]
+    fn plays_the_track_out() {
+        let fake = FakePlayer::new();
+        let config = SleepConfig {
+            finish_track: true,
+            ..settings(Until::Elapsed(Duration::from_secs(30)))
+        };
+        let (timer, clock) = start(&fake, config);
+
+        assert_eq!(timer.run().unwrap(), Outcome::Paused);
+        // 10s in to a 200s track, stopped just short of its end
+        let stopped = clock.elapsed().as_secs_f64();
+        assert!(stopped > 189.7 && stopped <= 190.0, "{}", stopped);
+        assert_eq!(volumes(&fake).last(), Some(&50));
+
+        let fake = FakePlayer::new();
+        let (timer, clock) = start(&fake, settings(Until::EndOfTrack));
+        fake.next().unwrap();
+        assert_eq!(timer.run().unwrap(), Outcome::Paused);
+        assert!(clock.elapsed() < Duration::from_secs(180));
+    }
+
+    #[test]
+    fn stops_when_cancelled_or_interrupted() {
+        let fake = FakePlayer::new();
+        let (timer, clock) = start(&fake, settings(Until::Elapsed(Duration::from_secs(60))));
+        timer.handle().cancel();
+        assert_eq!(timer.run().unwrap(), Outcome::Cancelled);
+        assert_eq!(clock.elapsed(), Duration::from_secs(0));
+
+        let fake = FakePlayer::new();
+        fake.pause().unwrap();
+        let (timer, _) = start(&fake, settings(Until::Elapsed(Duration::from_secs(60))));
+        assert_eq!(timer.run().unwrap(), Outcome::Interrupted);
+        assert!(volumes(&fake).is_empty());
+    }
+
+    #[test]
+    fn leaves_an_unknown_volume_alone() {
+        let fake = FakePlayer::new();
+        fake.set(|s| s.volume = None);
+        let (timer, _) = start(&fake, settings(Until::Elapsed(Duration::from_secs(60))));
+
+        assert_eq!(timer.run().unwrap(), Outcome::Paused);
+        assert!(volumes(&fake).is_empty());
+        assert_eq!(fake.get().state, Some(State::PAUSED));
+    }
+}
@@ -0,0 +1,146 @@
+#[cfg(target_os = "macos")]
+use crate::spotify::{Spotify, SpotifyTrack};
+use crate::state::State;
+use crate::uri::SpotifyUri;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(target_os = "macos")]
+use std::io::Result;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TrackInfo {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub artwork_url: Option<String>,
+    pub spotify_url: Option<String>,
+    pub disk_number: Option<i32>,
+    // milliseconds
+    pub duration: Option<i32>,
+    pub played_count: Option<i32>,
+    pub popularity: Option<i32>,
+    pub starred: Option<bool>,
+    pub track_number: Option<i32>,
+}
+
+impl TrackInfo {
+    pub fn url(&self) -> Option<String> {
+        self.uri().map(|uri| uri.url())
+    }
+
+    pub fn uri(&self) -> Option<SpotifyUri> {
+        self.spotify_url.as_ref()?.parse().ok()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    pub state: Option<State>,
+    // seconds
+    pub position: Option<f64>,
+    pub volume: Option<i32>,
+    pub shuffling: Option<bool>,
+    pub repeating: Option<bool>,
+    pub track: Option<TrackInfo>,
+}
+
+impl Snapshot {
+    pub fn is_playing(&self) -> bool {
+        self.state == Some(State::PLAYING)
+    }
+
+    pub fn duration(&self) -> Option<f64> {
+        self.track
+            .as_ref()
+            .and_then(|track| track.duration)
+            .map(|ms| f64::from(ms) / 1000.0)
+    }
+
+    pub fn remaining(&self) -> Option<f64> {
+        match (self.duration(), self.position) {
+            (Some(duration), Some(position)) => Some((duration - position).max(0.0)),
+            _ => None,
+        }
+    }
+
+    pub fn progress(&self) -> Option<f64> {
+        match (self.duration(), self.position) {
+            (Some(duration), Some(position)) if duration > 0.0 => {
+                Some((position / duration).clamp(0.0, 1.0))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl SpotifyTrack {
+    pub fn info(&self) -> Result<TrackInfo> {
+        Ok(TrackInfo {
+            id: self.id()?,
+            name: self.name()?,
+            artist: self.artist()?,
+            album: self.album()?,
+            album_artist: self.album_artist()?,
+            artwork_url: self.artwork_url()?,
+            spotify_url: self.spotify_url()?,
+            disk_number: self.disk_number()?,
+            duration: self.duration()?,
+            played_count: self.played_count()?,
+            popularity: self.popularity()?,
+            starred: self.starred()?,
+            track_number: self.track_number()?,
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Spotify {
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            state: self.state()?,
+            position: self.position()?,
+            volume: self.volume()?,
+            shuffling: self.is_shuffling()?,
+            repeating: self.is_repeating()?,
+            track: match self.track()? {
+                Some(track) => Some(track.info()?),
+                None => None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_url(url: &str) -> TrackInfo {
+        TrackInfo {
+            spotify_url: Some(url.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn url_follows_the_uri_kind() {
+        assert_eq!(
+            with_url("spotify:track:6rqhFgbbKwnb9MLmUQDhG6")
+                .url()
+                .as_deref(),
+            Some("https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6")
+        );
+        assert_eq!(
+            with_url("spotify:episode:4rOoJ6Egrf8K2IrywzwOMk")
+                .url()
+                .as_deref(),
+            Some("https://open.spotify.com/episode/4rOoJ6Egrf8K2IrywzwOMk")
+        );
+        assert_eq!(with_url("spotify:local:Band:LP:Song:200").url(), None);
+        assert_eq!(TrackInfo::default().url(), None);
+    }
+}
@@ -0,0 +1,340 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+// Whether retrying what failed with `err` can't help, because the other end
+// turned it down rather than couldn't be reached.
+pub fn is_permanent(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::InvalidInput | ErrorKind::PermissionDenied
+    )
+}
+
+pub struct Spool<T> {
+    path: PathBuf,
+    items: Vec<T>,
+    // the file ends in half a line, which has to go before appending
+    torn: bool,
+}
+
+impl<T: Serialize + DeserializeOwned> Spool<T> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Spool<T>> {
+        let path = path.as_ref().to_path_buf();
+        let mut items = Vec::new();
+        let mut torn = false;
+
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        let lines: Vec<&[u8]> = data
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .collect();
+
+        for (idx, line) in lines.iter().enumerate() {
+            match serde_json::from_slice(line) {
+                Ok(item) => items.push(item),
+                // a torn last line from a crash is dropped, not fatal
+                Err(_) if idx + 1 == lines.len() => torn = true,
+                Err(err) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{}: line {}: {}", path.display(), idx + 1, err),
+                    ))
+                }
+            }
+        }
+
+        torn |= !data.is_empty() && !data.ends_with(b"\n");
+
+        Ok(Spool { path, items, torn })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn push(&mut self, item: T) -> Result<()> {
+        if self.torn {
+            self.items.push(item);
+            if let Err(err) = self.save() {
+                self.items.pop();
+                return Err(err);
+            }
+            return Ok(());
+        }
+
+        append(&self.path, std::slice::from_ref(&item))?;
+        self.items.push(item);
+        Ok(())
+    }
+
+    // Where batches turned down for good end up, next to the spool itself.
+    pub fn rejected_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".rejected");
+        PathBuf::from(path)
+    }
+
+    // Hands the items to `submit` in batches of at most `batch`, forgetting
+    // every batch that went through and moving the ones that failed for good
+    // (see `is_permanent`) to `rejected_path`. Stops at the first failure
+    // worth retrying, returning how many were sent.
+    pub fn drain<F>(&mut self, batch: usize, mut submit: F) -> Result<usize>
+    where
+        F: FnMut(&[T]) -> Result<()>,
+    {
+        let rejected = self.rejected_path();
+        let mut sent = 0;
+        let mut done = 0;
+        let mut res = Ok(());
+
+        for chunk in self.items.chunks(batch.max(1)) {
+            match submit(chunk) {
+                Ok(()) => sent += chunk.len(),
+                Err(err) if is_permanent(&err) => {
+                    if let Err(err) = append(&rejected, chunk) {
+                        res = Err(err);
+                        break;
+                    }
+                }
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+            done += chunk.len();
+        }
+
+        if done > 0 {
+            self.items.drain(..done);
+            self.save()?;
+        }
+
+        res.map(|_| sent)
+    }
+
+    // Forgets the first `count` items once they were sent some other way
+    // than `drain`, without holding on to the spool while sending.
+    pub fn remove_front(&mut self, count: usize) -> Result<()> {
+        let count = count.min(self.items.len());
+        if count > 0 {
+            self.items.drain(..count);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Like `remove_front`, for items turned down for good.
+    pub fn reject_front(&mut self, count: usize) -> Result<()> {
+        let count = count.min(self.items.len());
+        append(&self.rejected_path(), &self.items[..count])?;
+        self.remove_front(count)
+    }
+
+    // Keeps only the items `keep` says yes to, returning how many went.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, keep: F) -> Result<usize> {
+        let before = self.items.len();
+        self.items.retain(keep);
+
+        let removed = before - self.items.len();
+        if removed > 0 {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    // Changes the items as `f` likes and writes them back in one go, so a
+    // crash leaves either all of the change or none of it.
+    pub fn edit<R, F: FnOnce(&mut Vec<T>) -> R>(&mut self, f: F) -> Result<R> {
+        let res = f(&mut self.items);
+        self.save()?;
+        Ok(res)
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let mut lines = Vec::new();
+        for item in &self.items {
+            serde_json::to_writer(&mut lines, item)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            lines.push(b'\n');
+        }
+
+        write_atomic(&self.path, &lines)?;
+        self.torn = false;
+        Ok(())
+    }
+}
+
+// Replaces the file at `path` with `data` through a temporary file next to
+// it, so a crash leaves either the old contents or the new.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+fn append<T: Serialize>(path: &Path, items: &[T]) -> Result<()> {
+    let mut lines = String::new();
+    for item in items {
+        lines.push_str(
+            &serde_json::to_string(item).map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+        );
+        lines.push('\n');
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn open(path: &Path) -> Result<Spool<u32>> {
+        Spool::open(path)
+    }
+
+    fn rejected(kind: ErrorKind) -> Result<()> {
+        Err(Error::new(kind, "no"))
+    }
+
+    #[test]
+    fn keeps_items_across_opens() {
+        let dir = TempDir::new();
+        let path = dir.join("nested/spool.jsonl");
+
+        let mut spool = open(&path).unwrap();
+        assert!(spool.is_empty());
+        spool.push(1).unwrap();
+        spool.push(2).unwrap();
+        assert_eq!(spool.retain(|n| *n != 1).unwrap(), 1);
+        spool.push(3).unwrap();
+
+        assert_eq!(open(&path).unwrap().items(), &[2, 3]);
+    }
+
+    #[test]
+    fn only_a_torn_last_line_is_forgiven() {
+        let dir = TempDir::new();
+        let path = dir.join("spool.jsonl");
+
+        fs::write(&path, "1\n2\n{\"a").unwrap();
+        let mut spool = open(&path).unwrap();
+        assert_eq!(spool.items(), &[1, 2]);
+
+        // the torn line goes instead of swallowing the next one
+        spool.push(3).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n2\n3\n");
+
+        fs::write(&path, "1\n2").unwrap();
+        open(&path).unwrap().push(3).unwrap();
+        assert_eq!(open(&path).unwrap().items(), &[1, 2, 3]);
+
+        fs::write(&path, "1\nnope\n3\n").unwrap();
+        let err = open(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn drain_sets_aside_what_is_turned_down() {
+        let dir = TempDir::new();
+        let path = dir.join("spool.jsonl");
+        let mut spool = open(&path).unwrap();
+        for n in 1..=7 {
+            spool.push(n).unwrap();
+        }
+
+        let mut batches = Vec::new();
+        let sent = spool
+            .drain(2, |batch| {
+                batches.push(batch.to_vec());
+                match batch[0] {
+                    3 => rejected(ErrorKind::InvalidInput),
+                    5 => rejected(ErrorKind::TimedOut),
+                    _ => Ok(()),
+                }
+            })
+            .unwrap_err();
+        assert_eq!(sent.kind(), ErrorKind::TimedOut);
+        assert_eq!(batches, vec![vec![1, 2], vec![3, 4], vec![5, 6]]);
+        assert_eq!(spool.items(), &[5, 6, 7]);
+        assert_eq!(open(&path).unwrap().items(), &[5, 6, 7]);
+        assert_eq!(open(&spool.rejected_path()).unwrap().items(), &[3, 4]);
+
+        assert_eq!(spool.drain(2, |_| Ok(())).unwrap(), 3);
+        assert!(open(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn front_removal() {
+        let dir = TempDir::new();
+        let path = dir.join("spool.jsonl");
+        let mut spool = open(&path).unwrap();
+        for n in 1..=4 {
+            spool.push(n).unwrap();
+        }
+
+        spool.remove_front(1).unwrap();
+        spool.reject_front(2).unwrap();
+        spool.remove_front(10).unwrap();
+        assert!(open(&path).unwrap().is_empty());
+        assert_eq!(open(&spool.rejected_path()).unwrap().items(), &[2, 3]);
+    }
+
+    #[test]
+    fn edits_in_one_write() {
+        let dir = TempDir::new();
+        let path = dir.join("spool.jsonl");
+        fs::write(&path, "1\n2\n{\"a").unwrap();
+        let mut spool = open(&path).unwrap();
+
+        let removed = spool
+            .edit(|items| {
+                let removed = items.remove(0);
+                items.push(3);
+                removed
+            })
+            .unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n3\n");
+
+        spool.push(4).unwrap();
+        assert_eq!(open(&path).unwrap().items(), &[2, 3, 4]);
+    }
+}
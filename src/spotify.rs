@@ -2,23 +2,11 @@ use crate::events::{
     AEDesc, AutoPropertyType, EventEnum, EventPropertyReader, EventedObject, EventedRootObject,
     EventedSubObject, ResType
 };
+pub use crate::state::State;
+use crate::uri::SpotifyUri;
 use libc::c_char;
 use std::io::Result;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum State {
-    STOPPED = 0x6b505353,
-    PLAYING = 0x6b505350,
-    PAUSED = 0x6b505370,
-}
-
-impl Into<u32> for State {
-    fn into(self) -> u32 {
-        self as u32
-    }
-}
-
 impl EventEnum for State {
     fn from_int(value: u32) -> Self {
         match value {
@@ -124,14 +112,10 @@ impl SpotifyTrack {
     }
 
     pub fn url(&self) -> Result<Option<String>> {
-        if let Some(url) = self.spotify_url()? {
-            Ok(Some(format!(
-                "https://open.spotify.com/track/{}",
-                &url[14..]
-            )))
-        } else {
-            Ok(None)
-        }
+        Ok(self
+            .spotify_url()?
+            .and_then(|url| url.parse::<SpotifyUri>().ok())
+            .map(|uri| uri.url()))
     }
 
     pub fn starred(&self) -> Result<Option<bool>> {
@@ -147,6 +131,9 @@ impl SpotifyTrack {
     // }
 }
 
+// The Apple Event Manager is thread safe, descriptors can be moved across threads.
+unsafe impl Send for SpotifyTrack {}
+
 impl EventedObject for SpotifyTrack {
     fn signature(&self) -> ResType {
         self.signature
@@ -185,6 +172,8 @@ pub struct Spotify {
     target_object: AEDesc,
 }
 
+unsafe impl Send for Spotify {}
+
 impl EventedRootObject for Spotify {}
 
 impl EventedObject for Spotify {
@@ -0,0 +1,29 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+#[repr(u32)]
+pub enum State {
+    STOPPED = 0x6b505353,
+    PLAYING = 0x6b505350,
+    PAUSED = 0x6b505370,
+}
+
+impl From<State> for u32 {
+    fn from(state: State) -> u32 {
+        state as u32
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::STOPPED => "stopped",
+            State::PLAYING => "playing",
+            State::PAUSED => "paused",
+        })
+    }
+}
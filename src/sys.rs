@@ -123,6 +123,7 @@ pub const typeLongInteger: ResType = typeSInt32;
 pub const typeSInt64: ResType = res_type!("comp");
 pub const typeComp: ResType = typeSInt64;
 pub const typeType: ResType = res_type!("type");
+pub const typeAERecord: ResType = res_type!("reco");
 
 pub const kAutoGenerateReturnID: i16 = -1;
 pub const kAnyTransactionID: i32 = 0;
@@ -187,6 +188,7 @@ extern "C" {
         desiredType: DescType,
         result: *mut AEDesc,
     ) -> OSErr;
+    pub fn AECoerceDesc(theAEDesc: *const AEDesc, toType: DescType, result: *mut AEDesc) -> OSErr;
     pub fn AEBuildDesc(dst: *mut AEDesc, error: *mut AEBuildError, src: *const u8, ...) -> OSErr;
     pub fn AEPrintDescToHandle(desc: *const AEDesc, result: *mut Handle) -> OSStatus;
     pub fn DisposeHandle(h: Handle);
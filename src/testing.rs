@@ -0,0 +1,348 @@
+// each feature set uses its own share of these
+#![allow(dead_code)]
+
+use crate::clock::{Clock, VirtualClock};
+use crate::player::Player;
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::state::State;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Error, Read, Result, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const TRACK: &str = "spotify:track:6rqhFgbbKwnb9MLmUQDhG6";
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub fn track(uri: &str, duration_ms: i32) -> TrackInfo {
+    TrackInfo {
+        id: Some(uri.to_string()),
+        name: Some(format!("Song {}", uri.rsplit(':').next().unwrap_or(uri))),
+        artist: Some("Band".to_string()),
+        album: Some("LP".to_string()),
+        spotify_url: Some(uri.to_string()),
+        duration: Some(duration_ms),
+        ..Default::default()
+    }
+}
+
+// A player kept in memory that logs every call and fails the ones told to.
+pub struct FakePlayer {
+    pub snapshot: Mutex<Snapshot>,
+    calls: Mutex<Vec<String>>,
+    failures: Mutex<HashMap<&'static str, VecDeque<i32>>>,
+    // the clock playback follows and how far it was at the last look
+    clock: Mutex<Option<(VirtualClock, Duration)>>,
+}
+
+impl FakePlayer {
+    // Playing TRACK, 200s long, 10s in at volume 50.
+    pub fn new() -> FakePlayer {
+        FakePlayer::with(Snapshot {
+            state: Some(State::PLAYING),
+            position: Some(10.0),
+            volume: Some(50),
+            shuffling: Some(false),
+            repeating: Some(false),
+            track: Some(track(TRACK, 200_000)),
+        })
+    }
+
+    pub fn with(snapshot: Snapshot) -> FakePlayer {
+        FakePlayer {
+            snapshot: Mutex::new(snapshot),
+            calls: Default::default(),
+            failures: Default::default(),
+            clock: Default::default(),
+        }
+    }
+
+    // From now on the position moves on with `clock` while playing, up to
+    // the end of the track.
+    pub fn follow(&self, clock: &VirtualClock) {
+        *lock(&self.clock) = Some((clock.clone(), clock.elapsed()));
+    }
+
+    fn snapshot(&self) -> MutexGuard<'_, Snapshot> {
+        let mut snapshot = lock(&self.snapshot);
+        if let Some((clock, seen)) = &mut *lock(&self.clock) {
+            let elapsed = clock.elapsed() - *seen;
+            *seen = clock.elapsed();
+            if snapshot.state == Some(State::PLAYING) {
+                let end = snapshot.duration().unwrap_or(f64::MAX);
+                if let Some(position) = &mut snapshot.position {
+                    *position = (*position + elapsed.as_secs_f64()).min(end);
+                }
+            }
+        }
+        snapshot
+    }
+
+    pub fn set(&self, f: impl FnOnce(&mut Snapshot)) {
+        f(&mut self.snapshot())
+    }
+
+    pub fn get(&self) -> Snapshot {
+        self.snapshot().clone()
+    }
+
+    // The next calls to `method` fail with these OSStatus codes, in order.
+    pub fn fail(&self, method: &'static str, codes: &[i32]) {
+        lock(&self.failures)
+            .entry(method)
+            .or_default()
+            .extend(codes);
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        lock(&self.calls).clone()
+    }
+
+    pub fn count(&self, method: &str) -> usize {
+        lock(&self.calls)
+            .iter()
+            .filter(|call| call.split(' ').next() == Some(method))
+            .count()
+    }
+
+    pub fn clear(&self) {
+        lock(&self.calls).clear();
+    }
+
+    fn call(&self, call: String) -> Result<MutexGuard<'_, Snapshot>> {
+        let method = call.split(' ').next().unwrap_or("");
+        lock(&self.calls).push(call.clone());
+        if let Some(code) = lock(&self.failures)
+            .get_mut(method)
+            .and_then(|codes| codes.pop_front())
+        {
+            return Err(Error::from_raw_os_error(code));
+        }
+        Ok(self.snapshot())
+    }
+}
+
+impl Player for FakePlayer {
+    fn state(&self) -> Result<Option<State>> {
+        Ok(self.call("state".into())?.state)
+    }
+
+    fn is_shuffling(&self) -> Result<Option<bool>> {
+        Ok(self.call("is_shuffling".into())?.shuffling)
+    }
+
+    fn set_shuffling(&self, is_it: bool) -> Result<()> {
+        self.call(format!("set_shuffling {}", is_it))?.shuffling = Some(is_it);
+        Ok(())
+    }
+
+    fn is_repeating(&self) -> Result<Option<bool>> {
+        Ok(self.call("is_repeating".into())?.repeating)
+    }
+
+    fn set_repeating(&self, is_it: bool) -> Result<()> {
+        self.call(format!("set_repeating {}", is_it))?.repeating = Some(is_it);
+        Ok(())
+    }
+
+    fn position(&self) -> Result<Option<f64>> {
+        Ok(self.call("position".into())?.position)
+    }
+
+    fn set_position(&self, pos: f64) -> Result<()> {
+        self.call(format!("set_position {}", pos))?.position = Some(pos);
+        Ok(())
+    }
+
+    fn volume(&self) -> Result<Option<i32>> {
+        Ok(self.call("volume".into())?.volume)
+    }
+
+    fn set_volume(&self, vol: i32) -> Result<()> {
+        self.call(format!("set_volume {}", vol))?.volume = Some(vol);
+        Ok(())
+    }
+
+    fn track(&self) -> Result<Option<TrackInfo>> {
+        Ok(self.call("track".into())?.track.clone())
+    }
+
+    fn play_pause(&self) -> Result<()> {
+        let mut s = self.call("play_pause".into())?;
+        s.state = match s.state {
+            Some(State::PLAYING) => Some(State::PAUSED),
+            _ => Some(State::PLAYING),
+        };
+        Ok(())
+    }
+
+    fn play(&self) -> Result<()> {
+        self.call("play".into())?.state = Some(State::PLAYING);
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.call("pause".into())?.state = Some(State::PAUSED);
+        Ok(())
+    }
+
+    fn next(&self) -> Result<()> {
+        let mut s = self.call("next".into())?;
+        s.track = Some(track("spotify:track:0000000000000000000next", 180_000));
+        s.position = Some(0.0);
+        Ok(())
+    }
+
+    fn previous(&self) -> Result<()> {
+        self.call("previous".into())?.position = Some(0.0);
+        Ok(())
+    }
+
+    fn play_track(&self, track: String, context: Option<String>) -> Result<()> {
+        let mut s = self.call(match &context {
+            Some(context) => format!("play_track {} {}", track, context),
+            None => format!("play_track {}", track),
+        })?;
+        s.track = Some(self::track(&track, 200_000));
+        s.position = Some(0.0);
+        s.state = Some(State::PLAYING);
+        Ok(())
+    }
+}
+
+type Cue<'a> = (Duration, Box<dyn Fn() + Send + 'a>);
+
+// A virtual clock starting at the epoch that runs each cue once it has been
+// slept past, to step in part way through a timer.
+pub struct Cued<'a> {
+    clock: VirtualClock,
+    cues: Mutex<Vec<Cue<'a>>>,
+}
+
+impl<'a> Cued<'a> {
+    pub fn new() -> Cued<'a> {
+        Cued {
+            clock: VirtualClock::new(SystemTime::UNIX_EPOCH),
+            cues: Default::default(),
+        }
+    }
+
+    pub fn at(&self, after: Duration, cue: impl Fn() + Send + 'a) {
+        lock(&self.cues).push((after, Box::new(cue)));
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    pub fn virtual_clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+}
+
+impl Clock for Cued<'_> {
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.clock.system_now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration);
+        let elapsed = self.clock.elapsed();
+        let due: Vec<_> = {
+            let mut cues = lock(&self.cues);
+            let (due, later) = cues.drain(..).partition(|(at, _)| *at <= elapsed);
+            *cues = later;
+            due
+        };
+        for (_, cue) in due {
+            cue();
+        }
+    }
+}
+
+// Answers one request per canned (status, body) in order, passing on each
+// request's head and body as text.
+pub fn http_stub(responses: Vec<(u16, &'static str)>) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = channel();
+
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (stream, _) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+                request.push_str(&line);
+            }
+            let mut content = vec![0; length];
+            let _ = reader.read_exact(&mut content);
+            request.push_str(&String::from_utf8_lossy(&content));
+            let _ = tx.send(request);
+
+            let _ = write!(
+                &stream,
+                "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    });
+
+    (url, rx)
+}
+
+// A directory of its own for each test, gone when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "macos-spotify-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
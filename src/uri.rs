@@ -0,0 +1,128 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UriKind {
+    Track,
+    Episode,
+    Album,
+    Artist,
+    Playlist,
+    Show,
+}
+
+impl UriKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UriKind::Track => "track",
+            UriKind::Episode => "episode",
+            UriKind::Album => "album",
+            UriKind::Artist => "artist",
+            UriKind::Playlist => "playlist",
+            UriKind::Show => "show",
+        }
+    }
+
+    fn parse(s: &str) -> Option<UriKind> {
+        Some(match s {
+            "track" => UriKind::Track,
+            "episode" => UriKind::Episode,
+            "album" => UriKind::Album,
+            "artist" => UriKind::Artist,
+            "playlist" => UriKind::Playlist,
+            "show" => UriKind::Show,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for UriKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// "spotify:track:6rqhFgbbKwnb9MLmUQDhG6", also parsed from open.spotify.com links.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpotifyUri {
+    pub kind: UriKind,
+    pub id: String,
+}
+
+impl SpotifyUri {
+    pub fn new(kind: UriKind, id: &str) -> SpotifyUri {
+        SpotifyUri {
+            kind,
+            id: id.to_string(),
+        }
+    }
+
+    pub fn is_playable(&self) -> bool {
+        matches!(self.kind, UriKind::Track | UriKind::Episode)
+    }
+
+    pub fn url(&self) -> String {
+        format!("https://open.spotify.com/{}/{}", self.kind, self.id)
+    }
+}
+
+impl fmt::Display for SpotifyUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spotify:{}:{}", self.kind, self.id)
+    }
+}
+
+impl FromStr for SpotifyUri {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SpotifyUri, Error> {
+        let s = s.trim();
+        let parts: Option<(&str, &str)> = if let Some(rest) = s.strip_prefix("spotify:") {
+            let mut parts = rest.splitn(2, ':');
+            parts.next().zip(parts.next())
+        } else {
+            s.strip_prefix("https://open.spotify.com/")
+                .or_else(|| s.strip_prefix("http://open.spotify.com/"))
+                .and_then(|rest| {
+                    let rest = rest.split(['?', '#']).next().unwrap_or("");
+                    let mut parts = rest.trim_end_matches('/').splitn(2, '/');
+                    parts.next().zip(parts.next())
+                })
+        };
+
+        match parts {
+            Some((kind, id)) if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                UriKind::parse(kind)
+                    .map(|kind| SpotifyUri::new(kind, id))
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Unknown Spotify URI kind {:?}", kind),
+                        )
+                    })
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not a Spotify URI", s),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for SpotifyUri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for SpotifyUri {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SpotifyUri, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
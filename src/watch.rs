@@ -0,0 +1,172 @@
+use crate::player::Player;
+use crate::snapshot::{Snapshot, TrackInfo};
+use crate::state::State;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// seconds of drift tolerated before a position change counts as a seek
+const SEEK_TOLERANCE: f64 = 1.5;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", content = "value", rename_all = "snake_case")
+)]
+pub enum PlayerEvent {
+    StateChanged(Option<State>),
+    TrackChanged(Option<TrackInfo>),
+    PositionChanged(Option<f64>),
+    Seeked(f64),
+    VolumeChanged(Option<i32>),
+    ShufflingChanged(Option<bool>),
+    RepeatingChanged(Option<bool>),
+}
+
+impl PlayerEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerEvent::StateChanged(_) => "state_changed",
+            PlayerEvent::TrackChanged(_) => "track_changed",
+            PlayerEvent::PositionChanged(_) => "position_changed",
+            PlayerEvent::Seeked(_) => "seeked",
+            PlayerEvent::VolumeChanged(_) => "volume_changed",
+            PlayerEvent::ShufflingChanged(_) => "shuffling_changed",
+            PlayerEvent::RepeatingChanged(_) => "repeating_changed",
+        }
+    }
+}
+
+fn track_key(track: &Option<TrackInfo>) -> Option<&str> {
+    track
+        .as_ref()
+        .and_then(|t| t.spotify_url.as_deref().or(t.id.as_deref()))
+}
+
+pub fn diff(old: &Snapshot, new: &Snapshot, elapsed: Duration) -> Vec<PlayerEvent> {
+    let mut events = Vec::new();
+    let track_changed = track_key(&old.track) != track_key(&new.track);
+
+    if track_changed {
+        events.push(PlayerEvent::TrackChanged(new.track.clone()));
+    }
+
+    if old.state != new.state {
+        events.push(PlayerEvent::StateChanged(new.state));
+    }
+
+    if old.position != new.position {
+        events.push(PlayerEvent::PositionChanged(new.position));
+
+        if let (false, Some(old_pos), Some(new_pos)) = (track_changed, old.position, new.position)
+        {
+            let expected = if old.is_playing() {
+                old_pos + elapsed.as_secs_f64()
+            } else {
+                old_pos
+            };
+
+            if (new_pos - expected).abs() > SEEK_TOLERANCE {
+                events.push(PlayerEvent::Seeked(new_pos));
+            }
+        }
+    }
+
+    if old.volume != new.volume {
+        events.push(PlayerEvent::VolumeChanged(new.volume));
+    }
+
+    if old.shuffling != new.shuffling {
+        events.push(PlayerEvent::ShufflingChanged(new.shuffling));
+    }
+
+    if old.repeating != new.repeating {
+        events.push(PlayerEvent::RepeatingChanged(new.repeating));
+    }
+
+    events
+}
+
+pub struct Watcher<P> {
+    player: P,
+    last: Snapshot,
+    last_at: Option<Instant>,
+}
+
+impl<P: Player> Watcher<P> {
+    pub fn new(player: P) -> Watcher<P> {
+        Watcher {
+            player,
+            last: Default::default(),
+            last_at: None,
+        }
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.last
+    }
+
+    pub fn poll(&mut self) -> Result<Vec<PlayerEvent>> {
+        let snapshot = self.player.snapshot()?;
+        let now = Instant::now();
+        let elapsed = self.last_at.map_or(Duration::from_secs(0), |at| now - at);
+        self.last_at = Some(now);
+
+        Ok(self.update(snapshot, elapsed))
+    }
+
+    pub fn update(&mut self, snapshot: Snapshot, elapsed: Duration) -> Vec<PlayerEvent> {
+        let events = diff(&self.last, &snapshot, elapsed);
+        self.last = snapshot;
+        events
+    }
+}
+
+// Polls `player` every `interval` while `is_active` says someone is listening.
+// Listeners are expected to fetch the state themselves when they join, so
+// events pick up from what the player is doing when polling resumes.
+pub fn spawn<P, A, F>(
+    player: P,
+    interval: Duration,
+    stopped: Arc<AtomicBool>,
+    mut is_active: A,
+    mut on_event: F,
+) -> thread::JoinHandle<()>
+where
+    P: Player + Clone + Send + 'static,
+    A: FnMut() -> bool + Send + 'static,
+    F: FnMut(PlayerEvent) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut watcher: Option<Watcher<P>> = None;
+
+        while !stopped.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+
+            if !is_active() {
+                watcher = None;
+                continue;
+            }
+
+            let watcher = watcher.get_or_insert_with(|| {
+                let mut watcher = Watcher::new(player.clone());
+                // otherwise everything is reported as changed from nothing
+                let _ = watcher.poll();
+                watcher
+            });
+
+            if let Ok(events) = watcher.poll() {
+                events.into_iter().for_each(&mut on_event);
+            }
+        }
+    })
+}
@@ -0,0 +1,362 @@
+use crate::player::Player;
+use crate::watch::{self, PlayerEvent};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    pub poll_interval: Duration,
+    pub client_buffer: usize,
+}
+
+impl Default for WsConfig {
+    fn default() -> WsConfig {
+        WsConfig {
+            poll_interval: Duration::from_millis(500),
+            client_buffer: 32,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    Volume {
+        value: i32,
+    },
+    Position {
+        value: f64,
+    },
+    Shuffle {
+        value: bool,
+    },
+    Repeat {
+        value: bool,
+    },
+    PlayTrack {
+        uri: String,
+        context: Option<String>,
+    },
+    Snapshot,
+}
+
+struct Client {
+    tx: SyncSender<String>,
+    // set when the client fell behind and has to start over from a snapshot
+    lagging: Arc<AtomicBool>,
+}
+
+fn message(kind: &str, value: Value) -> String {
+    json!({ "type": kind, "value": value }).to_string()
+}
+
+fn broadcast(clients: &Mutex<Vec<Client>>, event: &PlayerEvent) {
+    let text = json!(event).to_string();
+
+    if let Ok(mut clients) = clients.lock() {
+        clients.retain(|client| match client.tx.try_send(text.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                client.lagging.store(true, Ordering::SeqCst);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+pub struct WsServer<P> {
+    listener: TcpListener,
+    player: Arc<Mutex<P>>,
+    config: WsConfig,
+    clients: Arc<Mutex<Vec<Client>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl<P: Player + Send + 'static> WsServer<P> {
+    pub fn bind<A: ToSocketAddrs>(addr: A, player: P, config: WsConfig) -> Result<WsServer<P>> {
+        Ok(WsServer {
+            listener: TcpListener::bind(addr)?,
+            player: Arc::new(Mutex::new(player)),
+            config,
+            clients: Arc::new(Mutex::new(Vec::new())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let clients = self.clients.clone();
+        let active = self.clients.clone();
+        let watcher = watch::spawn(
+            self.player.clone(),
+            self.config.poll_interval,
+            self.stopped.clone(),
+            move || active.lock().is_ok_and(|c| !c.is_empty()),
+            move |event| broadcast(&clients, &event),
+        );
+
+        for stream in self.listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if let Ok(stream) = stream {
+                self.accept(stream);
+            }
+        }
+
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = watcher.join();
+        Ok(())
+    }
+
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop
+        if let Ok(addr) = self.listener.local_addr() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    fn accept(&self, stream: TcpStream) {
+        let (tx, rx) = sync_channel(self.config.client_buffer.max(1));
+        let lagging = Arc::new(AtomicBool::new(false));
+        let player = self.player.clone();
+        let clients = self.clients.clone();
+        let client = Client {
+            tx,
+            lagging: lagging.clone(),
+        };
+
+        thread::spawn(move || {
+            let mut socket = match tungstenite::accept(stream) {
+                Ok(socket) => socket,
+                Err(_) => return,
+            };
+
+            if socket
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .is_err()
+            {
+                return;
+            }
+
+            if let Ok(mut clients) = clients.lock() {
+                clients.push(client);
+            }
+
+            if send_snapshot(&mut socket, &*player).is_err() {
+                return;
+            }
+
+            let _ = serve(&mut socket, &*player, &rx, &lagging);
+        });
+    }
+}
+
+fn send_snapshot<P: Player>(
+    socket: &mut WebSocket<TcpStream>,
+    player: &P,
+) -> tungstenite::Result<()> {
+    let text = match player.snapshot() {
+        Ok(snapshot) => message("snapshot", json!(snapshot)),
+        Err(err) => message("error", json!(err.to_string())),
+    };
+
+    socket.send(Message::text(text))
+}
+
+fn serve<P: Player>(
+    socket: &mut WebSocket<TcpStream>,
+    player: &P,
+    rx: &Receiver<String>,
+    lagging: &AtomicBool,
+) -> tungstenite::Result<()> {
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                let reply = match serde_json::from_str::<Command>(text.as_str()) {
+                    Ok(Command::Snapshot) => {
+                        send_snapshot(socket, player)?;
+                        None
+                    }
+                    Ok(command) => execute(player, command)
+                        .err()
+                        .map(|err| message("error", json!(err.to_string()))),
+                    Err(err) => Some(message("error", json!(err.to_string()))),
+                };
+
+                if let Some(reply) = reply {
+                    socket.send(Message::text(reply))?;
+                }
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+            Err(err) => return Err(err),
+        }
+
+        if lagging.swap(false, Ordering::SeqCst) {
+            while rx.try_recv().is_ok() {}
+            send_snapshot(socket, player)?;
+            continue;
+        }
+
+        loop {
+            match rx.try_recv() {
+                Ok(text) => socket.write(Message::text(text))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        socket.flush()?;
+    }
+}
+
+fn execute<P: Player>(player: &P, command: Command) -> Result<()> {
+    match command {
+        Command::Play => player.play(),
+        Command::Pause => player.pause(),
+        Command::PlayPause => player.play_pause(),
+        Command::Next => player.next(),
+        Command::Previous => player.previous(),
+        Command::Volume { value } if (0..=100).contains(&value) => player.set_volume(value),
+        Command::Volume { .. } => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Volume must be between 0 and 100",
+        )),
+        Command::Position { value } if value >= 0.0 => player.set_position(value),
+        Command::Position { .. } => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Position must not be negative",
+        )),
+        Command::Shuffle { value } => player.set_shuffling(value),
+        Command::Repeat { value } => player.set_repeating(value),
+        Command::PlayTrack { uri, context } => player.play_track(uri, context),
+        Command::Snapshot => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::testing::FakePlayer;
+
+    type Client = WebSocket<TcpStream>;
+
+    fn start() -> (Arc<WsServer<Arc<FakePlayer>>>, Arc<FakePlayer>) {
+        let fake = Arc::new(FakePlayer::new());
+        let config = WsConfig {
+            poll_interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let server = Arc::new(WsServer::bind("127.0.0.1:0", fake.clone(), config).unwrap());
+        let running = server.clone();
+        thread::spawn(move || running.run());
+        (server, fake)
+    }
+
+    fn connect(server: &WsServer<Arc<FakePlayer>>) -> Client {
+        let addr = server.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        tungstenite::client(format!("ws://{}/", addr), stream)
+            .unwrap()
+            .0
+    }
+
+    fn receive(client: &mut Client) -> Value {
+        loop {
+            if let Message::Text(text) = client.read().unwrap() {
+                return serde_json::from_str(text.as_str()).unwrap();
+            }
+        }
+    }
+
+    fn send(client: &mut Client, command: Value) {
+        client.send(Message::text(command.to_string())).unwrap();
+    }
+
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn snapshot_then_only_what_changed() {
+        let (server, fake) = start();
+        let mut client = connect(&server);
+
+        let snapshot = receive(&mut client);
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(snapshot["value"]["volume"], 50);
+
+        // the one for the client's snapshot and the watcher's first look
+        wait_for(|| fake.count("state") >= 2);
+        fake.set(|s| s.state = Some(State::PAUSED));
+
+        let event = receive(&mut client);
+        assert_eq!(event["type"], "state_changed");
+        assert_eq!(event["value"], "paused");
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn commands_are_checked_and_run() {
+        let (server, fake) = start();
+        let mut client = connect(&server);
+        receive(&mut client);
+
+        send(&mut client, json!({ "cmd": "volume", "value": 30 }));
+        wait_for(|| fake.get().volume == Some(30));
+
+        send(&mut client, json!({ "cmd": "volume", "value": 130 }));
+        let error = receive(&mut client);
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["value"], "Volume must be between 0 and 100");
+
+        send(&mut client, json!({ "cmd": "position", "value": -1.0 }));
+        assert_eq!(receive(&mut client)["type"], "error");
+
+        send(&mut client, json!({ "cmd": "bogus" }));
+        assert_eq!(receive(&mut client)["type"], "error");
+        assert_eq!(fake.count("set_volume"), 1);
+        assert_eq!(fake.count("set_position"), 0);
+
+        send(&mut client, json!({ "cmd": "next" }));
+        let event = receive(&mut client);
+        assert_eq!(event["type"], "track_changed");
+        assert_eq!(fake.count("next"), 1);
+
+        server.shutdown();
+    }
+}